@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use globset::{Glob, GlobBuilder};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -15,8 +16,19 @@ pub enum Command {
         /// Path to the backing directory
         backing: PathBuf,
 
-        /// Path to the mount point
-        mountpoint: PathBuf,
+        /// Path to the mount point (optional with --agent or --in-place)
+        #[arg(required_unless_present_any = ["agents", "in_place"], conflicts_with = "in_place")]
+        mountpoint: Option<PathBuf>,
+
+        /// Mount over the backing directory itself, so its path leads
+        /// through dibs
+        #[arg(long)]
+        in_place: bool,
+
+        /// Give an agent its own mount point over the same backing directory;
+        /// everything done through it is that agent's (repeatable)
+        #[arg(long = "agent", value_name = "NAME=MOUNTPOINT", value_parser = parse_agent_mount)]
+        agents: Vec<AgentMount>,
 
         /// Session identifier for logging
         #[arg(long)]
@@ -27,13 +39,61 @@ pub enum Command {
         log_file: PathBuf,
 
         /// Minutes before evicting idle CAS entries
-        #[arg(long, default_value_t = 60)]
-        eviction_minutes: u64,
+        #[arg(long, default_value_t = 60.0)]
+        eviction_minutes: f64,
 
         /// Save rejected write contents to .dibs/conflicts/
         #[arg(long)]
         save_conflicts: bool,
 
+        /// Maximum number of saved conflicts to keep (0 = unlimited)
+        #[arg(long, default_value_t = 100)]
+        max_conflicts: usize,
+
+        /// Hours to keep saved conflicts (0 = forever)
+        #[arg(long, default_value_t = 168)]
+        conflict_max_age_hours: u64,
+
+        /// Megabytes of file content to keep for the versions sessions last read (0 = none)
+        #[arg(long, default_value_t = 64)]
+        object_store_mb: u64,
+
+        /// Let stale writers write into a shadow file and reject them at close,
+        /// so the complete rejected content is saved
+        #[arg(long, requires = "save_conflicts")]
+        defer_rejection: bool,
+
+        /// Merge stale writes to text files with the changes made since the
+        /// writer last read them, rejecting only overlapping edits
+        #[arg(long)]
+        auto_merge: bool,
+
+        /// How to tell agents apart
+        #[arg(long, value_enum, default_value_t = IdentityStrategy::Session)]
+        identity: IdentityStrategy,
+
+        /// With --identity ancestry: PID whose children are the agents
+        #[arg(long, default_value_t = 1)]
+        ancestry_root: u32,
+
+        /// Refuse symlinks whose target leads out of the mount
+        #[arg(long)]
+        contain_symlinks: bool,
+
+        /// What mode, owner, timestamp and flag changes have to pass
+        #[arg(long, value_enum, default_value_t = AttrPolicy::Allow)]
+        attr_policy: AttrPolicy,
+
+        /// Refuse to let an agent overwrite, truncate, delete or rename over
+        /// an existing non-empty file it never read
+        #[arg(long)]
+        strict: bool,
+
+        /// Apply a policy to the paths matching a glob (repeatable); a glob
+        /// without a slash matches file names at any depth
+        #[arg(long = "policy", value_name = "GLOB=POLICY", value_parser = parse_path_policy)]
+        policies: Vec<PathPolicy>,
+
         /// Fall back to read-only on CAS errors instead of EIO
         #[arg(long)]
         readonly_fallback: bool,
@@ -47,16 +107,169 @@ pub enum Command {
         /// Path to the mount point
         mountpoint: PathBuf,
     },
+    /// List pending saved conflicts, or settle one
+    Resolve {
+        /// Path to the mount point
+        mountpoint: PathBuf,
+
+        /// Conflict to settle (as listed); lists pending conflicts if omitted
+        id: Option<String>,
+
+        /// Which version the file should end up with
+        #[arg(long, value_enum, requires = "id")]
+        take: Option<Take>,
+
+        /// Open $EDITOR on the result before writing it, with conflict
+        /// markers where a merge overlaps
+        #[arg(long, requires = "take")]
+        edit: bool,
+    },
+    /// Run an agent in its own session, named in status and logs
+    Run {
+        /// Name to show for the agent
+        #[arg(long)]
+        name: String,
+
+        /// Mount point of the running daemon
+        #[arg(long)]
+        mount: PathBuf,
+
+        /// Run the agent in its own user and mount namespaces, where the
+        /// mount replaces the backing directory at its path (Linux)
+        #[arg(long)]
+        isolate: bool,
+
+        /// The agent command and its arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+/// A per-agent mount point, from `--agent NAME=MOUNTPOINT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentMount {
+    pub name: String,
+    pub mountpoint: PathBuf,
+}
+
+fn parse_agent_mount(arg: &str) -> Result<AgentMount, String> {
+    match arg.split_once('=') {
+        Some((name, mountpoint)) if !name.is_empty() && !mountpoint.is_empty() => Ok(AgentMount {
+            name: name.to_string(),
+            mountpoint: PathBuf::from(mountpoint),
+        }),
+        _ => Err(format!("expected NAME=MOUNTPOINT, got {:?}", arg)),
+    }
+}
+
+/// A per-path policy, from `--policy GLOB=POLICY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// Matched against paths relative to the backing directory.
+    pub glob: Glob,
+    pub kind: PolicyKind,
+}
+
+fn parse_path_policy(arg: &str) -> Result<PathPolicy, String> {
+    let (glob, kind) = match arg.rsplit_once('=') {
+        Some((glob, kind)) if !glob.is_empty() => (glob, kind),
+        _ => return Err(format!("expected GLOB=POLICY, got {:?}", arg)),
+    };
+    let kind = PolicyKind::from_str(kind, false)?;
+    // As in .gitignore, a glob without a slash matches names at any depth
+    let pattern = if glob.contains('/') {
+        glob.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{}", glob)
+    };
+    let glob = GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    Ok(PathPolicy { glob, kind })
+}
+
+/// What a `--policy` rule does to the paths it matches.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    /// As with --strict: no overwriting files the agent never read
+    Strict,
+    /// Appends skip the CAS check, one at a time, and leave other agents'
+    /// reads current
+    Append,
+    /// Writers coordinate through file locks, so the CAS check is skipped,
+    /// as for a file while it is write-locked
+    Locks,
+}
+
+/// How requests are attributed to agents.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityStrategy {
+    /// Unix session ID of the calling process
+    Session,
+    /// Unix user of the calling process
+    Uid,
+    /// cgroup of the calling process (Linux), e.g. one per container
+    Cgroup,
+    /// Ancestor of the calling process directly below --ancestry-root
+    Ancestry,
+    /// DIBS_AGENT environment variable of the calling process (Linux)
+    Env,
+}
+
+/// CAS checks on attribute changes other than size. Truncation is always
+/// checked like a write.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrPolicy {
+    /// Apply them unchecked
+    Allow,
+    /// Reject them from an agent whose last read of the file is stale
+    Check,
+}
+
+/// Version to keep when resolving a saved conflict.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Take {
+    /// The rejected write
+    Theirs,
+    /// The file as it is now
+    Current,
+    /// Both, merged against the version the rejected writer had read
+    Merged,
+}
+
+impl Take {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Take::Theirs => "theirs",
+            Take::Current => "current",
+            Take::Merged => "merged",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct DibsConfig {
     pub backing: PathBuf,
-    pub mountpoint: PathBuf,
+    /// Shared mount point, where the identity strategy tells agents apart.
+    pub mountpoint: Option<PathBuf>,
+    /// Per-agent mount points.
+    pub agents: Vec<AgentMount>,
     pub session_id: String,
     pub log_file: PathBuf,
-    pub eviction_minutes: u64,
+    pub eviction_minutes: f64,
     pub save_conflicts: bool,
+    pub max_conflicts: usize,
+    pub conflict_max_age_hours: u64,
+    pub object_store_mb: u64,
+    pub defer_rejection: bool,
+    pub auto_merge: bool,
+    pub identity: IdentityStrategy,
+    pub ancestry_root: u32,
+    pub contain_symlinks: bool,
+    pub attr_policy: AttrPolicy,
+    pub strict: bool,
+    pub policies: Vec<PathPolicy>,
     pub readonly_fallback: bool,
     pub foreground: bool,
 }
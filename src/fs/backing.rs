@@ -0,0 +1,520 @@
+/// Access to the backing directory through a directory descriptor.
+///
+/// Every path is resolved relative to the descriptor with the `*at` calls, so
+/// the directory stays reachable after something is mounted over its path.
+/// That is what lets `--in-place` mount dibs over the project itself.
+///
+/// Paths are walked one component at a time with `O_NOFOLLOW`, and the final
+/// component is never followed either: a symlink planted in the tree, or a
+/// directory swapped for one between a lookup and the next request, can't make
+/// dibs act on a file outside the backing directory.
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+use super::cas;
+
+/// How intermediate directories are opened: only to look up the next
+/// component, which on Linux doesn't need read permission.
+#[cfg(target_os = "linux")]
+const WALK_FLAGS: libc::c_int = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const WALK_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+
+/// `renameat` flags, with the values the kernel hands FUSE's `rename`: fail
+/// if the new path exists, or swap the two paths.
+#[cfg(target_os = "linux")]
+pub const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE;
+#[cfg(target_os = "linux")]
+pub const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+#[cfg(not(target_os = "linux"))]
+pub const RENAME_NOREPLACE: u32 = libc::RENAME_EXCL;
+#[cfg(not(target_os = "linux"))]
+pub const RENAME_EXCHANGE: u32 = libc::RENAME_SWAP;
+
+/// An open directory. Paths given to its methods are relative to it; the
+/// empty path is the directory itself.
+#[derive(Debug)]
+pub struct Dir {
+    fd: OwnedFd,
+}
+
+/// The last component of a path and the directory it's in.
+struct At<'a> {
+    root: &'a OwnedFd,
+    /// The parent, if it isn't the root itself.
+    parent: Option<OwnedFd>,
+    name: CString,
+}
+
+impl At<'_> {
+    fn fd(&self) -> RawFd {
+        self.parent.as_ref().unwrap_or(self.root).as_raw_fd()
+    }
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Read a variable-length value with `f`, which is called first without a
+/// buffer to learn the size, like the `*xattr` calls. Asks again if the
+/// value grew in between.
+fn read_sized(mut f: impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let n = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if n >= 0 {
+            buf.truncate(n as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+fn c_name(name: &std::ffi::OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains null byte"))
+}
+
+fn is_dir_mode(st: &libc::stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+/// Whether `target`, read as a symlink in directory `dir`, stays inside the
+/// tree `dir` is relative to. Decided on the names alone: `..` that climbs
+/// out of the tree escapes, even if a symlink along the way would have led
+/// back in.
+pub fn contained(dir: &Path, target: &Path) -> bool {
+    if target.is_absolute() {
+        return false;
+    }
+    let mut depth = dir.components().count();
+    for component in target.components() {
+        match component {
+            Component::ParentDir if depth == 0 => return false,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+    }
+    true
+}
+
+impl Dir {
+    /// Open the directory at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let c_path = super::passthrough::path_to_cstring(path)?;
+        let fd = cvt(unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) })?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Walk to the directory holding the last component of `rel` without
+    /// following symlinks. Absolute paths and `..` are refused.
+    fn at(&self, rel: &Path) -> io::Result<At<'_>> {
+        let mut names = Vec::new();
+        for component in rel.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => {}
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path leaves the backing directory")),
+            }
+        }
+        let Some(last) = names.pop() else {
+            return Ok(At { root: &self.fd, parent: None, name: c".".to_owned() });
+        };
+        let mut parent: Option<OwnedFd> = None;
+        for name in names {
+            let c_path = c_name(name)?;
+            let dirfd = parent.as_ref().unwrap_or(&self.fd).as_raw_fd();
+            let fd = cvt(unsafe { libc::openat(dirfd, c_path.as_ptr(), WALK_FLAGS) })?;
+            parent = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        Ok(At { root: &self.fd, parent, name: c_name(last)? })
+    }
+
+    /// Open a subdirectory.
+    pub fn open_dir(&self, rel: &Path) -> io::Result<Self> {
+        let fd = self.openat(rel, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC, 0)?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// `openat` with `O_NOFOLLOW`: a raw descriptor the caller must close.
+    pub fn openat(&self, rel: &Path, flags: libc::c_int, mode: libc::c_uint) -> io::Result<RawFd> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::openat(at.fd(), at.name.as_ptr(), flags | libc::O_NOFOLLOW, mode) })
+    }
+
+    /// Open a file for reading.
+    pub fn file(&self, rel: &Path) -> io::Result<File> {
+        let fd = self.openat(rel, libc::O_RDONLY | libc::O_CLOEXEC, 0)?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Create or truncate a file for writing.
+    pub fn create(&self, rel: &Path, mode: libc::c_uint) -> io::Result<File> {
+        let fd = self.openat(rel, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC, mode)?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Like `std::fs::read`.
+    pub fn read(&self, rel: &Path) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.file(rel)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Like `std::fs::write`.
+    pub fn write(&self, rel: &Path, data: &[u8]) -> io::Result<()> {
+        self.create(rel, 0o666)?.write_all(data)
+    }
+
+    /// Copy a file into `to`, creating it with `mode`.
+    pub fn copy(&self, rel: &Path, to: &Dir, to_rel: &Path, mode: libc::c_uint) -> io::Result<u64> {
+        io::copy(&mut self.file(rel)?, &mut to.create(to_rel, mode)?)
+    }
+
+    /// Hash a file's content, as `cas::hash_file` would.
+    pub fn hash(&self, rel: &Path) -> io::Result<Vec<u8>> {
+        cas::hash_open_file(self.file(rel)?)
+    }
+
+    /// `lstat`: a final symlink is reported as such.
+    pub fn lstat(&self, rel: &Path) -> io::Result<libc::stat> {
+        let at = self.at(rel)?;
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        cvt(unsafe { libc::fstatat(at.fd(), at.name.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) })?;
+        Ok(st)
+    }
+
+    pub fn exists(&self, rel: &Path) -> bool {
+        self.lstat(rel).is_ok()
+    }
+
+    pub fn is_dir(&self, rel: &Path) -> bool {
+        self.lstat(rel).is_ok_and(|st| is_dir_mode(&st))
+    }
+
+    pub fn is_file(&self, rel: &Path) -> bool {
+        self.lstat(rel).is_ok_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFREG)
+    }
+
+    /// Names in a directory, without `.` and `..`.
+    pub fn read_dir(&self, rel: &Path) -> io::Result<Vec<OsString>> {
+        let fd = self.openat(rel, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC, 0)?;
+        let dp = unsafe { libc::fdopendir(fd) };
+        if dp.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let mut names = Vec::new();
+        loop {
+            let entry = unsafe { libc::readdir(dp) };
+            if entry.is_null() {
+                break;
+            }
+            let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+            if name != b"." && name != b".." {
+                names.push(OsString::from_vec(name.to_vec()));
+            }
+        }
+        unsafe { libc::closedir(dp) };
+        Ok(names)
+    }
+
+    pub fn mkdir(&self, rel: &Path, mode: libc::mode_t) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::mkdirat(at.fd(), at.name.as_ptr(), mode) }).map(drop)
+    }
+
+    /// Create a special file, or an empty regular file, as `mknod(2)`.
+    pub fn mknod(&self, rel: &Path, mode: libc::mode_t, dev: libc::dev_t) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::mknodat(at.fd(), at.name.as_ptr(), mode, dev) }).map(drop)
+    }
+
+    /// Like `std::fs::create_dir_all`.
+    pub fn create_dir_all(&self, rel: &Path) -> io::Result<()> {
+        let mut current = PathBuf::new();
+        for component in rel.components() {
+            current.push(component);
+            match self.mkdir(&current, 0o777) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && self.is_dir(&current) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn unlink(&self, rel: &Path) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::unlinkat(at.fd(), at.name.as_ptr(), 0) }).map(drop)
+    }
+
+    pub fn rmdir(&self, rel: &Path) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::unlinkat(at.fd(), at.name.as_ptr(), libc::AT_REMOVEDIR) }).map(drop)
+    }
+
+    /// Like `std::fs::remove_dir_all`; doesn't follow symlinks.
+    pub fn remove_dir_all(&self, rel: &Path) -> io::Result<()> {
+        if !is_dir_mode(&self.lstat(rel)?) {
+            return self.unlink(rel);
+        }
+        for name in self.read_dir(rel)? {
+            self.remove_dir_all(&rel.join(name))?;
+        }
+        self.rmdir(rel)
+    }
+
+    /// Rename within the directory.
+    pub fn rename(&self, old: &Path, new: &Path) -> io::Result<()> {
+        self.renameat(old, new, 0)
+    }
+
+    /// Rename within the directory with `RENAME_NOREPLACE` or
+    /// `RENAME_EXCHANGE` (`renameat2` on Linux, `renameatx_np` on macOS).
+    pub fn renameat(&self, old: &Path, new: &Path, flags: u32) -> io::Result<()> {
+        let (old, new) = (self.at(old)?, self.at(new)?);
+        let ret = if flags == 0 {
+            unsafe { libc::renameat(old.fd(), old.name.as_ptr(), new.fd(), new.name.as_ptr()) }
+        } else {
+            #[cfg(target_os = "linux")]
+            let ret = unsafe { libc::renameat2(old.fd(), old.name.as_ptr(), new.fd(), new.name.as_ptr(), flags) };
+            #[cfg(not(target_os = "linux"))]
+            let ret = unsafe { libc::renameatx_np(old.fd(), old.name.as_ptr(), new.fd(), new.name.as_ptr(), flags) };
+            ret
+        };
+        cvt(ret).map(drop)
+    }
+
+    /// Hard link `new` to the file at `old`. A symlink at `old` is linked
+    /// itself, not followed.
+    pub fn link(&self, old: &Path, new: &Path) -> io::Result<()> {
+        let (old, new) = (self.at(old)?, self.at(new)?);
+        cvt(unsafe { libc::linkat(old.fd(), old.name.as_ptr(), new.fd(), new.name.as_ptr(), 0) }).map(drop)
+    }
+
+    pub fn symlink(&self, target: &Path, rel: &Path) -> io::Result<()> {
+        let c_target = super::passthrough::path_to_cstring(target)?;
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::symlinkat(c_target.as_ptr(), at.fd(), at.name.as_ptr()) }).map(drop)
+    }
+
+    pub fn read_link(&self, rel: &Path) -> io::Result<PathBuf> {
+        let at = self.at(rel)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        loop {
+            let n = unsafe {
+                libc::readlinkat(at.fd(), at.name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // A full buffer may mean the target was cut short
+            if (n as usize) < buf.len() {
+                buf.truncate(n as usize);
+                return Ok(PathBuf::from(OsString::from_vec(buf)));
+            }
+            buf.resize(buf.len() * 2, 0);
+        }
+    }
+
+    pub fn chmod(&self, rel: &Path, mode: libc::mode_t) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::fchmodat(at.fd(), at.name.as_ptr(), mode, libc::AT_SYMLINK_NOFOLLOW) }).map(drop)
+    }
+
+    pub fn chown(&self, rel: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::fchownat(at.fd(), at.name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) }).map(drop)
+    }
+
+    pub fn utimens(&self, rel: &Path, times: &[libc::timespec; 2]) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::utimensat(at.fd(), at.name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) }).map(drop)
+    }
+
+    /// There is no `chflagsat`, so this goes through a descriptor.
+    pub fn chflags(&self, rel: &Path, flags: u32) -> io::Result<()> {
+        let fd = unsafe { OwnedFd::from_raw_fd(self.openat(rel, libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC, 0)?) };
+        { let _ = (fd, flags); Ok(()) }
+    }
+
+    pub fn truncate(&self, rel: &Path, size: libc::off_t) -> io::Result<()> {
+        let fd = unsafe { OwnedFd::from_raw_fd(self.openat(rel, libc::O_WRONLY | libc::O_CLOEXEC, 0)?) };
+        cvt(unsafe { libc::ftruncate(fd.as_raw_fd(), size) }).map(drop)
+    }
+
+    /// Reach `rel` itself to get at its extended attributes, whatever kind
+    /// of file it is and whether or not it may be read: through an `O_PATH`
+    /// descriptor, which the `f*xattr` calls refuse, so they are made on its
+    /// `/proc/self/fd` path instead. That path leads to the file the
+    /// descriptor refers to without resolving anything, even for a symlink.
+    #[cfg(target_os = "linux")]
+    fn xattr_path(&self, rel: &Path) -> io::Result<(OwnedFd, CString)> {
+        let fd = unsafe { OwnedFd::from_raw_fd(self.openat(rel, libc::O_PATH | libc::O_CLOEXEC, 0)?) };
+        let path = CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).expect("no NUL in a number");
+        Ok((fd, path))
+    }
+
+    /// Open `rel` itself to get at its extended attributes, whatever kind of
+    /// file it is.
+    #[cfg(not(target_os = "linux"))]
+    fn xattr_fd(&self, rel: &Path) -> io::Result<OwnedFd> {
+        let flags = libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC | libc::O_SYMLINK;
+        Ok(unsafe { OwnedFd::from_raw_fd(self.openat(rel, flags, 0)?) })
+    }
+
+    pub fn getxattr(&self, rel: &Path, name: &std::ffi::OsStr) -> io::Result<Vec<u8>> {
+        let name = c_name(name)?;
+        #[cfg(target_os = "linux")]
+        {
+            let (_fd, path) = self.xattr_path(rel)?;
+            read_sized(|buf, size| unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size) })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let fd = self.xattr_fd(rel)?;
+            read_sized(|buf, size| unsafe { libc::fgetxattr(fd.as_raw_fd(), name.as_ptr(), buf, size, 0, 0) })
+        }
+    }
+
+    /// The names of `rel`'s extended attributes, each ending in a NUL.
+    pub fn listxattr(&self, rel: &Path) -> io::Result<Vec<u8>> {
+        #[cfg(target_os = "linux")]
+        {
+            let (_fd, path) = self.xattr_path(rel)?;
+            read_sized(|buf, size| unsafe { libc::listxattr(path.as_ptr(), buf as *mut libc::c_char, size) })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let fd = self.xattr_fd(rel)?;
+            read_sized(|buf, size| unsafe { libc::flistxattr(fd.as_raw_fd(), buf as *mut libc::c_char, size, 0) })
+        }
+    }
+
+    pub fn setxattr(&self, rel: &Path, name: &std::ffi::OsStr, value: &[u8], flags: libc::c_int) -> io::Result<()> {
+        let name = c_name(name)?;
+        let value_ptr = value.as_ptr() as *const libc::c_void;
+        #[cfg(target_os = "linux")]
+        let ret = {
+            let (_fd, path) = self.xattr_path(rel)?;
+            unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), flags) }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let ret = {
+            let fd = self.xattr_fd(rel)?;
+            unsafe { libc::fsetxattr(fd.as_raw_fd(), name.as_ptr(), value_ptr, value.len(), 0, flags) }
+        };
+        cvt(ret).map(drop)
+    }
+
+    pub fn removexattr(&self, rel: &Path, name: &std::ffi::OsStr) -> io::Result<()> {
+        let name = c_name(name)?;
+        #[cfg(target_os = "linux")]
+        let ret = {
+            let (_fd, path) = self.xattr_path(rel)?;
+            unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let ret = {
+            let fd = self.xattr_fd(rel)?;
+            unsafe { libc::fremovexattr(fd.as_raw_fd(), name.as_ptr(), 0) }
+        };
+        cvt(ret).map(drop)
+    }
+
+    pub fn access(&self, rel: &Path, mask: libc::c_int) -> io::Result<()> {
+        let at = self.at(rel)?;
+        cvt(unsafe { libc::faccessat(at.fd(), at.name.as_ptr(), mask, libc::AT_SYMLINK_NOFOLLOW) }).map(drop)
+    }
+
+    /// `fstatfs` on the directory itself.
+    pub fn statfs(&self) -> io::Result<libc::statfs> {
+        let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+        cvt(unsafe { libc::fstatfs(self.fd.as_raw_fd(), &mut st) })?;
+        Ok(st)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The descriptor keeps working after the directory is renamed away
+    #[test]
+    fn test_dir_survives_rename() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("a")).unwrap();
+        let dir = Dir::open(&tmp.path().join("a")).unwrap();
+        std::fs::rename(tmp.path().join("a"), tmp.path().join("b")).unwrap();
+
+        dir.create_dir_all(Path::new("x/y")).unwrap();
+        dir.write(Path::new("x/y/f.txt"), b"hello").unwrap();
+        assert_eq!(std::fs::read(tmp.path().join("b/x/y/f.txt")).unwrap(), b"hello");
+        assert_eq!(dir.read_dir(Path::new("x")).unwrap(), vec![OsString::from("y")]);
+        assert_eq!(dir.hash(Path::new("x/y/f.txt")).unwrap(), cas::hash_bytes(b"hello"));
+
+        dir.remove_dir_all(Path::new("x")).unwrap();
+        assert!(!dir.exists(Path::new("x")));
+        assert!(dir.read(Path::new("/etc/hostname")).is_err());
+    }
+
+    /// Symlinks anywhere in a path are refused rather than followed out of
+    /// the tree
+    #[test]
+    fn test_dir_refuses_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        let root = tmp.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("dir")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), root.join("file")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new"), root.join("dangling")).unwrap();
+        let dir = Dir::open(&root).unwrap();
+
+        assert!(dir.read(Path::new("dir/secret")).is_err());
+        assert!(dir.read(Path::new("file")).is_err());
+        assert!(dir.write(Path::new("dangling"), b"x").is_err());
+        assert!(!outside.join("new").exists());
+        assert!(dir.unlink(Path::new("dir/secret")).is_err());
+        assert!(dir.read(Path::new("../outside/secret")).is_err());
+
+        // The links themselves are still there to be seen
+        assert_eq!(dir.lstat(Path::new("file")).unwrap().st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(dir.read_link(Path::new("dir")).unwrap(), outside);
+        dir.chmod(Path::new(""), 0o755).unwrap();
+    }
+
+    #[test]
+    fn test_contained() {
+        assert!(contained(Path::new(""), Path::new("a/b")));
+        assert!(contained(Path::new("src"), Path::new("../README.md")));
+        assert!(contained(Path::new("a/b"), Path::new("../../c")));
+        assert!(!contained(Path::new("a"), Path::new("../../c")));
+        assert!(!contained(Path::new(""), Path::new("..")));
+        assert!(!contained(Path::new("a"), Path::new("/etc/passwd")));
+        assert!(contained(Path::new(""), Path::new("./a/../b")));
+    }
+}
@@ -10,10 +10,12 @@ const HASH_THRESHOLD: u64 = 10 * 1024 * 1024;
 /// Uses SHA-256 for files <= 10MB, xxHash (XXH3-128) for larger files.
 /// Returns None if the file doesn't exist or can't be read.
 pub fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
-    let metadata = std::fs::metadata(path)?;
-    let size = metadata.len();
+    hash_open_file(File::open(path)?)
+}
 
-    let mut file = File::open(path)?;
+/// Compute the hash of an already open file, from its current position.
+pub fn hash_open_file(mut file: File) -> io::Result<Vec<u8>> {
+    let size = file.metadata()?.len();
 
     if size <= HASH_THRESHOLD {
         hash_sha256(&mut file)
@@ -49,6 +51,15 @@ fn hash_xxh3(file: &mut File) -> io::Result<Vec<u8>> {
     Ok(hash.to_be_bytes().to_vec())
 }
 
+/// Compute the hash `hash_file` would produce for a file with these contents.
+pub fn hash_bytes(data: &[u8]) -> Vec<u8> {
+    if data.len() as u64 <= HASH_THRESHOLD {
+        Sha256::digest(data).to_vec()
+    } else {
+        xxhash_rust::xxh3::xxh3_128(data).to_be_bytes().to_vec()
+    }
+}
+
 /// Format a hash as a hex string.
 pub fn hash_hex(hash: &[u8]) -> String {
     hash.iter().map(|b| format!("{:02x}", b)).collect()
@@ -1,12 +1,18 @@
 use dashmap::DashMap;
 use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use super::inodes::{exchanged, moved};
+use crate::identity::AgentId;
+use crate::state::conflicts::ConflictInfo;
+use crate::state::hash_table::FileId;
+
 /// Snapshot of an open file handle for display purposes.
 pub struct OpenFileInfo {
     pub path: PathBuf,
-    pub sid: u32,
+    pub agent: AgentId,
     pub flags: i32,
 }
 
@@ -18,14 +24,37 @@ pub struct HandleState {
     pub real_fd: RawFd,
     /// Path relative to backing root.
     pub path: PathBuf,
+    /// CAS identity of the opened file (None for virtual files).
+    pub id: Option<FileId>,
     /// SHA-256 or xxHash at the time this handle was opened.
     pub hash_at_open: Option<Vec<u8>>,
     /// Open flags.
     pub flags: i32,
     /// Whether this handle has been used for writing.
     pub has_written: bool,
-    /// Session ID of the process that opened this handle.
-    pub sid: u32,
+    /// Opened with O_APPEND under the append policy: writes skip the CAS
+    /// check and are serialized per file.
+    pub appending: bool,
+    /// Agent that opened this handle.
+    pub agent: AgentId,
+    /// Set when a stale writer was let through into a shadow file instead of
+    /// being rejected at open time (deferred rejection or auto-merge).
+    pub shadow: Option<ShadowFile>,
+}
+
+/// Private file receiving a stale writer's data until it is merged or
+/// rejected at flush.
+#[derive(Debug, Clone)]
+pub struct ShadowFile {
+    /// Name of the shadow file in the shadow directory.
+    pub path: PathBuf,
+    /// The rejection that will be reported when the handle is flushed.
+    pub conflict: ConflictInfo,
+    /// Whether the shadow holds a change that hasn't been saved as a conflict yet.
+    /// Starts true for O_TRUNC opens, since truncation alone is a rejected write.
+    pub dirty: bool,
+    /// What the writer last saw, if its changes can be merged into the current file.
+    pub base: Option<Arc<[u8]>>,
 }
 
 pub struct HandleTable {
@@ -41,16 +70,27 @@ impl HandleTable {
         }
     }
 
-    pub fn alloc(&self, real_fd: RawFd, path: PathBuf, flags: i32, hash: Option<Vec<u8>>, sid: u32) -> u64 {
+    pub fn alloc(
+        &self,
+        real_fd: RawFd,
+        path: PathBuf,
+        id: Option<FileId>,
+        flags: i32,
+        hash: Option<Vec<u8>>,
+        agent: AgentId,
+    ) -> u64 {
         let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
         let state = HandleState {
             fh,
             real_fd,
             path,
+            id,
             hash_at_open: hash,
             flags,
             has_written: false,
-            sid,
+            appending: false,
+            agent,
+            shadow: None,
         };
         self.handles.insert(fh, state);
         fh
@@ -68,6 +108,37 @@ impl HandleTable {
         self.handles.remove(&fh).map(|(_, v)| v)
     }
 
+    /// Whether any handle has file `id` open.
+    pub fn any_open(&self, id: FileId) -> bool {
+        self.handles.iter().any(|h| h.id == Some(id))
+    }
+
+    /// Follow a rename from `old` to `new` in every handle opened at or
+    /// below `old`.
+    pub fn rename(&self, old: &Path, new: &Path) {
+        self.relabel(|path| moved(path, old, new));
+    }
+
+    /// Follow an exchange of `a` and `b` in every handle opened at or below
+    /// either.
+    pub fn exchange(&self, a: &Path, b: &Path) {
+        self.relabel(|path| exchanged(path, a, b));
+    }
+
+    fn relabel(&self, to: impl Fn(&Path) -> Option<PathBuf>) {
+        for mut entry in self.handles.iter_mut() {
+            let h = entry.value_mut();
+            if let Some(path) = to(&h.path) {
+                h.path = path;
+            }
+            if let Some(ref mut shadow) = h.shadow {
+                if let Some(path) = to(&shadow.conflict.path) {
+                    shadow.conflict.path = path;
+                }
+            }
+        }
+    }
+
     /// Returns the number of open file handles.
     pub fn count(&self) -> usize {
         self.handles.len()
@@ -91,7 +162,7 @@ impl HandleTable {
                 let h = entry.value();
                 OpenFileInfo {
                     path: h.path.clone(),
-                    sid: h.sid,
+                    agent: h.agent.clone(),
                     flags: h.flags,
                 }
             })
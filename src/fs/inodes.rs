@@ -1,76 +1,174 @@
 use dashmap::DashMap;
+use parking_lot::RwLock;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Reserved inode range for synthetic .dibs/ entries.
-pub const SYNTHETIC_INODE_BASE: u64 = u64::MAX - 1000;
+/// Reserved inode range for synthetic .dibs/ entries. Large enough for the
+/// dynamically allocated entries under .dibs/conflicts/ and .dibs/objects/.
+pub const SYNTHETIC_INODE_BASE: u64 = u64::MAX - (1 << 24);
 
 /// Well-known synthetic inodes.
 pub const DIBS_DIR_INO: u64 = SYNTHETIC_INODE_BASE;
 pub const DIBS_STATUS_INO: u64 = SYNTHETIC_INODE_BASE + 1;
 pub const DIBS_LOCKS_INO: u64 = SYNTHETIC_INODE_BASE + 2;
 pub const DIBS_CONFLICTS_DIR_INO: u64 = SYNTHETIC_INODE_BASE + 3;
+pub const DIBS_OBJECTS_DIR_INO: u64 = SYNTHETIC_INODE_BASE + 4;
+pub const DIBS_SELF_DIR_INO: u64 = SYNTHETIC_INODE_BASE + 5;
+pub const DIBS_NAMES_INO: u64 = SYNTHETIC_INODE_BASE + 6;
+
+/// Where `path` ends up when `old` is renamed to `new`, if it is `old` or
+/// lies below it.
+pub fn moved(path: &Path, old: &Path, new: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(old).ok()?;
+    if rest.as_os_str().is_empty() {
+        Some(new.to_path_buf())
+    } else {
+        Some(new.join(rest))
+    }
+}
+
+/// Where `path` ends up when `a` and `b` swap places, if it is at or below
+/// either.
+pub fn exchanged(path: &Path, a: &Path, b: &Path) -> Option<PathBuf> {
+    moved(path, a, b).or_else(|| moved(path, b, a))
+}
 
 pub struct InodeTable {
-    ino_to_path: DashMap<u64, PathBuf>,
+    /// Every path an inode is known by, most recently seen last. Hard links
+    /// give one inode several.
+    ino_to_paths: DashMap<u64, Vec<PathBuf>>,
     path_to_ino: DashMap<PathBuf, u64>,
+    /// Held for writing while a subtree moves, so that no request resolves a
+    /// path from a half-renamed directory.
+    subtree: RwLock<()>,
     next_synthetic: AtomicU64,
 }
 
 impl InodeTable {
     pub fn new() -> Self {
         Self {
-            ino_to_path: DashMap::new(),
+            ino_to_paths: DashMap::new(),
             path_to_ino: DashMap::new(),
-            next_synthetic: AtomicU64::new(DIBS_CONFLICTS_DIR_INO + 1),
+            subtree: RwLock::new(()),
+            next_synthetic: AtomicU64::new(DIBS_NAMES_INO + 1),
         }
     }
 
-    /// Insert or update a mapping using the real inode from stat().
+    /// Insert or update a mapping using the real inode from stat(). Other
+    /// paths already known for the inode are kept.
     pub fn insert(&self, ino: u64, path: PathBuf) {
-        // Remove any old path mapping for this inode
-        if let Some((_, old_path)) = self.ino_to_path.remove(&ino) {
-            self.path_to_ino.remove(&old_path);
-        }
-        // Remove any old inode mapping for this path
-        if let Some((_, old_ino)) = self.path_to_ino.remove(&path) {
-            if old_ino != ino {
-                self.ino_to_path.remove(&old_ino);
-            }
-        }
-        self.ino_to_path.insert(ino, path.clone());
-        self.path_to_ino.insert(path, ino);
+        let _guard = self.subtree.read();
+        self.attach(ino, path);
     }
 
+    /// The path an inode was most recently seen under.
     pub fn get_path(&self, ino: u64) -> Option<PathBuf> {
-        self.ino_to_path.get(&ino).map(|r| r.value().clone())
+        let _guard = self.subtree.read();
+        self.ino_to_paths.get(&ino).and_then(|r| r.value().last().cloned())
     }
 
     pub fn get_ino(&self, path: &Path) -> Option<u64> {
+        let _guard = self.subtree.read();
         self.path_to_ino.get(path).map(|r| *r.value())
     }
 
     pub fn remove_by_ino(&self, ino: u64) {
-        if let Some((_, path)) = self.ino_to_path.remove(&ino) {
-            self.path_to_ino.remove(&path);
+        let _guard = self.subtree.read();
+        if let Some((_, paths)) = self.ino_to_paths.remove(&ino) {
+            for path in paths {
+                self.path_to_ino.remove_if(&path, |_, i| *i == ino);
+            }
         }
     }
 
+    /// Forget one path. The inode stays known under its other paths, if any.
     pub fn remove_by_path(&self, path: &Path) {
+        let _guard = self.subtree.read();
         if let Some((_, ino)) = self.path_to_ino.remove(path) {
-            self.ino_to_path.remove(&ino);
+            self.detach(ino, path);
         }
     }
 
-    /// Rename a path in the inode table.
+    /// Forget a path and every path below it.
+    pub fn remove_tree(&self, path: &Path) {
+        let _guard = self.subtree.write();
+        for (p, ino) in self.below(path) {
+            self.path_to_ino.remove(&p);
+            self.detach(ino, &p);
+        }
+    }
+
+    /// Rename a path, and for a directory every path below it, in one step.
+    /// An inode the new path led to before has been replaced, and loses that
+    /// path.
     pub fn rename(&self, old_path: &Path, new_path: &Path) {
-        if let Some((_, ino)) = self.path_to_ino.remove(old_path) {
-            self.ino_to_path.insert(ino, new_path.to_path_buf());
-            self.path_to_ino.insert(new_path.to_path_buf(), ino);
+        let _guard = self.subtree.write();
+        for (path, ino) in self.below(old_path) {
+            self.path_to_ino.remove(&path);
+            self.detach(ino, &path);
+            if let Some(new) = moved(&path, old_path, new_path) {
+                self.attach(ino, new);
+            }
+        }
+    }
+
+    /// Swap two paths, and everything below them, in one step.
+    pub fn exchange(&self, a: &Path, b: &Path) {
+        let _guard = self.subtree.write();
+        let swapped: Vec<(PathBuf, u64)> = self.below(a).into_iter().chain(self.below(b)).collect();
+        for (path, ino) in &swapped {
+            self.path_to_ino.remove(path);
+            self.detach(*ino, path);
+        }
+        for (path, ino) in swapped {
+            if let Some(new) = exchanged(&path, a, b) {
+                self.attach(ino, new);
+            }
+        }
+    }
+
+    /// Every known path at or below `path`, with its inode.
+    fn below(&self, path: &Path) -> Vec<(PathBuf, u64)> {
+        self.path_to_ino
+            .iter()
+            .filter(|e| e.key().starts_with(path))
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect()
+    }
+
+    fn attach(&self, ino: u64, path: PathBuf) {
+        // Remove any old inode mapping for this path
+        if let Some(old_ino) = self.path_to_ino.insert(path.clone(), ino) {
+            if old_ino != ino {
+                self.detach(old_ino, &path);
+            }
         }
+        let mut paths = self.ino_to_paths.entry(ino).or_default();
+        paths.retain(|p| *p != path);
+        paths.push(path);
     }
 
-    /// Allocate a new synthetic inode (for conflict files, etc).
+    /// Drop `path` from an inode's paths, and the inode once it has none.
+    fn detach(&self, ino: u64, path: &Path) {
+        if let Some(mut paths) = self.ino_to_paths.get_mut(&ino) {
+            paths.retain(|p| p != path);
+        }
+        self.ino_to_paths.remove_if(&ino, |_, paths| paths.is_empty());
+    }
+
+    /// Get the synthetic inode for a virtual path under .dibs/, allocating
+    /// one the first time the path is seen.
+    pub fn synthetic_ino(&self, vpath: &Path) -> u64 {
+        let _guard = self.subtree.read();
+        let ino = *self
+            .path_to_ino
+            .entry(vpath.to_path_buf())
+            .or_insert_with(|| self.alloc_synthetic());
+        self.ino_to_paths.insert(ino, vec![vpath.to_path_buf()]);
+        ino
+    }
+
+    /// Allocate a new synthetic inode (for conflict files, objects, etc).
     pub fn alloc_synthetic(&self) -> u64 {
         self.next_synthetic.fetch_add(1, Ordering::Relaxed)
     }
@@ -80,3 +178,77 @@ impl InodeTable {
         ino >= SYNTHETIC_INODE_BASE
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hard links: one inode, several paths, each removable on its own
+    #[test]
+    fn test_inode_with_many_paths() {
+        let inodes = InodeTable::new();
+        inodes.insert(10, PathBuf::from("a.txt"));
+        inodes.insert(10, PathBuf::from("b.txt"));
+        assert_eq!(inodes.get_ino(Path::new("a.txt")), Some(10));
+        assert_eq!(inodes.get_ino(Path::new("b.txt")), Some(10));
+        assert_eq!(inodes.get_path(10), Some(PathBuf::from("b.txt")));
+
+        inodes.remove_by_path(Path::new("b.txt"));
+        assert_eq!(inodes.get_path(10), Some(PathBuf::from("a.txt")));
+        inodes.remove_by_path(Path::new("a.txt"));
+        assert_eq!(inodes.get_path(10), None);
+    }
+
+    /// Renaming over a path takes it from the inode it led to
+    #[test]
+    fn test_rename_over_path() {
+        let inodes = InodeTable::new();
+        inodes.insert(10, PathBuf::from("a.txt"));
+        inodes.insert(10, PathBuf::from("link.txt"));
+        inodes.insert(20, PathBuf::from("a.txt.tmp"));
+
+        inodes.rename(Path::new("a.txt.tmp"), Path::new("a.txt"));
+        assert_eq!(inodes.get_ino(Path::new("a.txt")), Some(20));
+        assert_eq!(inodes.get_ino(Path::new("a.txt.tmp")), None);
+        assert_eq!(inodes.get_path(20), Some(PathBuf::from("a.txt")));
+        assert_eq!(inodes.get_path(10), Some(PathBuf::from("link.txt")));
+    }
+
+    /// Renaming a directory moves every path below it, and nothing else
+    #[test]
+    fn test_rename_subtree() {
+        let inodes = InodeTable::new();
+        inodes.insert(10, PathBuf::from("src/components"));
+        inodes.insert(11, PathBuf::from("src/components/a.ts"));
+        inodes.insert(12, PathBuf::from("src/components/deep/b.ts"));
+        inodes.insert(13, PathBuf::from("src/components.ts"));
+
+        inodes.rename(Path::new("src/components"), Path::new("src/ui"));
+        assert_eq!(inodes.get_path(10), Some(PathBuf::from("src/ui")));
+        assert_eq!(inodes.get_path(11), Some(PathBuf::from("src/ui/a.ts")));
+        assert_eq!(inodes.get_path(12), Some(PathBuf::from("src/ui/deep/b.ts")));
+        assert_eq!(inodes.get_path(13), Some(PathBuf::from("src/components.ts")));
+        assert_eq!(inodes.get_ino(Path::new("src/components/a.ts")), None);
+
+        inodes.remove_tree(Path::new("src/ui"));
+        assert_eq!(inodes.get_path(11), None);
+        assert_eq!(inodes.get_path(12), None);
+        assert_eq!(inodes.get_path(13), Some(PathBuf::from("src/components.ts")));
+    }
+
+    /// Exchanging two directories swaps everything below them
+    #[test]
+    fn test_exchange() {
+        let inodes = InodeTable::new();
+        inodes.insert(10, PathBuf::from("a"));
+        inodes.insert(11, PathBuf::from("a/x.txt"));
+        inodes.insert(20, PathBuf::from("b"));
+        inodes.insert(21, PathBuf::from("b/x.txt"));
+
+        inodes.exchange(Path::new("a"), Path::new("b"));
+        assert_eq!(inodes.get_path(10), Some(PathBuf::from("b")));
+        assert_eq!(inodes.get_path(11), Some(PathBuf::from("b/x.txt")));
+        assert_eq!(inodes.get_path(20), Some(PathBuf::from("a")));
+        assert_eq!(inodes.get_ino(Path::new("a/x.txt")), Some(21));
+    }
+}
@@ -0,0 +1,202 @@
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use fuser::{Errno, ReplyEmpty};
+use parking_lot::Mutex;
+
+use super::passthrough::{F_SETLK, flock, flock_range, lock};
+use crate::state::hash_table::FileId;
+
+/// Longest pause between two attempts at a waiting lock.
+const MAX_RETRY_PAUSE: Duration = Duration::from_millis(100);
+
+/// The backing descriptors locks are passed through on, one per file and
+/// lock owner. An owner is a process for `fcntl()` locks and an open file for
+/// `flock()`, so whichever of its handles an owner unlocks through, it
+/// unlocks what it locked.
+pub struct LockTable {
+    fds: DashMap<(FileId, u64), Arc<OwnedFd>>,
+    /// Queue of the thread that retries waiting lock requests, started with
+    /// the first.
+    waiters: Mutex<Option<mpsc::Sender<Waiter>>>,
+}
+
+/// A lock request that waits for conflicting locks to go away.
+pub struct Waiter {
+    /// The owner's descriptor, kept open while the request waits.
+    pub fd: Arc<OwnedFd>,
+    pub fl: libc::flock,
+    /// Whether the request still stands, given the descriptor it waits on.
+    pub wanted: Box<dyn Fn(&OwnedFd) -> bool + Send>,
+    /// Called once the lock is taken, before the reply.
+    pub granted: Box<dyn FnOnce() + Send>,
+    pub reply: ReplyEmpty,
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self {
+            fds: DashMap::new(),
+            waiters: Mutex::new(None),
+        }
+    }
+
+    /// The descriptor `owner` locks file `id` through, from `open` if it has
+    /// none yet.
+    pub fn get_or_open(
+        &self,
+        id: FileId,
+        owner: u64,
+        open: impl FnOnce() -> std::io::Result<OwnedFd>,
+    ) -> std::io::Result<Arc<OwnedFd>> {
+        match self.fds.entry((id, owner)) {
+            Entry::Occupied(entry) => Ok(Arc::clone(entry.get())),
+            Entry::Vacant(entry) => Ok(Arc::clone(entry.insert(Arc::new(open()?)).value())),
+        }
+    }
+
+    /// The descriptor `owner` locks file `id` through, if it has one.
+    pub fn get(&self, id: FileId, owner: u64) -> Option<Arc<OwnedFd>> {
+        self.fds.get(&(id, owner)).map(|fd| Arc::clone(&fd))
+    }
+
+    /// Whether `fd` is still the descriptor `owner` locks file `id` through.
+    pub fn is_current(&self, id: FileId, owner: u64, fd: &OwnedFd) -> bool {
+        self.fds.get(&(id, owner)).is_some_and(|current| std::ptr::eq(&**current, fd))
+    }
+
+    /// Close `owner`'s descriptor for file `id`, dropping its locks there,
+    /// unless a lock request is waiting on it.
+    pub fn close_idle(&self, id: FileId, owner: u64) {
+        self.fds.remove_if(&(id, owner), |_, fd| Arc::strong_count(fd) == 1);
+    }
+
+    /// Close every owner's descriptor for file `id`, returning the owners.
+    pub fn close_all(&self, id: FileId) -> Vec<u64> {
+        let owners: Vec<u64> = self.fds.iter().filter(|e| e.key().0 == id).map(|e| e.key().1).collect();
+        for &owner in &owners {
+            self.fds.remove(&(id, owner));
+        }
+        owners
+    }
+
+    /// Whether any owner has a descriptor for file `id`.
+    pub fn has_any(&self, id: FileId) -> bool {
+        self.fds.iter().any(|e| e.key().0 == id)
+    }
+
+    /// Retry `waiter` until it is granted or no longer wanted. One thread
+    /// does this for every waiting request, so that waiting holds up
+    /// neither the session nor a thread of its own.
+    pub fn wait(&self, waiter: Waiter) {
+        let mut queue = self.waiters.lock();
+        let sender = queue.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || retry_waiters(receiver));
+            sender
+        });
+        if let Err(mpsc::SendError(waiter)) = sender.send(waiter) {
+            waiter.reply.error(Errno::ENOLCK);
+        }
+    }
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Waiter {
+    /// Try for the lock once, returning the request if it has to wait on.
+    fn attempt(mut self) -> Option<Self> {
+        match lock(self.fd.as_raw_fd(), F_SETLK, &mut self.fl) {
+            Ok(()) if (self.wanted)(&self.fd) => {
+                (self.granted)();
+                self.reply.ok();
+            }
+            Ok(()) => {
+                let (start, end) = flock_range(&self.fl);
+                let mut unlock = flock(start, end, libc::F_UNLCK);
+                let _ = lock(self.fd.as_raw_fd(), F_SETLK, &mut unlock);
+                self.reply.error(Errno::EINTR);
+            }
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)) => {
+                if (self.wanted)(&self.fd) {
+                    return Some(self);
+                }
+                self.reply.error(Errno::EINTR);
+            }
+            Err(e) => self.reply.error(Errno::from(e)),
+        }
+        None
+    }
+}
+
+/// Retry the waiting lock requests from `receiver`, backing off while none
+/// of them gets anywhere.
+fn retry_waiters(receiver: mpsc::Receiver<Waiter>) {
+    let mut waiting: Vec<Waiter> = Vec::new();
+    let mut pause = Duration::from_millis(1);
+    loop {
+        let next = if waiting.is_empty() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            receiver.recv_timeout(pause)
+        };
+        let mut progress = false;
+        match next {
+            Ok(waiter) => {
+                waiting.push(waiter);
+                progress = true;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                for waiter in waiting {
+                    waiter.reply.error(Errno::EINTR);
+                }
+                return;
+            }
+        }
+        waiting.extend(receiver.try_iter());
+        let before = waiting.len();
+        waiting = waiting.into_iter().filter_map(Waiter::attempt).collect();
+        pause = if progress || waiting.len() < before {
+            Duration::from_millis(1)
+        } else {
+            (pause * 2).min(MAX_RETRY_PAUSE)
+        };
+    }
+}
+
+/// Whether the thread behind a request, `pid`, has been signalled since, or
+/// is gone. FUSE only tells a filesystem that a request was interrupted if
+/// it answers interrupts, and fuser doesn't, so a waiting lock request looks
+/// for the signal that would have interrupted a lock wait on a local file.
+#[cfg(target_os = "linux")]
+pub fn caller_interrupted(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        return true;
+    };
+    let mask = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:") & !mask("SigIgn:") != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn caller_interrupted(pid: u32) -> bool {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+}
@@ -1,42 +1,57 @@
+pub mod backing;
 pub mod cas;
 pub mod handles;
 pub mod inodes;
+pub mod locks;
 pub mod passthrough;
 pub mod virtual_dir;
 
 use std::ffi::OsStr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use fuser::{
-    AccessFlags, BsdFileFlags, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags,
-    Generation, INodeNo, KernelConfig, LockOwner, OpenFlags, ReplyAttr, ReplyCreate, ReplyData,
-    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, RenameFlags,
-    Request, TimeOrNow, WriteFlags,
+    AccessFlags, BsdFileFlags, CopyFileRangeFlags, Errno, FileAttr, FileHandle, FileType, Filesystem,
+    FopenFlags, Generation, INodeNo, InitFlags, KernelConfig, LockOwner, Notifier, OpenFlags, ReplyAttr,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLock, ReplyLseek, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, RenameFlags, Request, TimeOrNow, WriteFlags,
 };
+use parking_lot::Mutex;
 use tracing::{debug, info, warn};
 
-use self::handles::{DirHandleTable, HandleTable};
+use self::backing::Dir;
+use self::handles::{DirHandleTable, HandleTable, ShadowFile};
 use self::inodes::*;
+use self::locks::{LockTable, Waiter, caller_interrupted};
 use self::passthrough::*;
 use self::virtual_dir::*;
-use crate::config::DibsConfig;
-use crate::state::hash_table::CasTable;
+use crate::config::{AgentMount, AttrPolicy, DibsConfig, PolicyKind};
+use crate::identity::{self, AgentId, AgentNames, FixedResolver, IdentityResolver, PidCache};
+use crate::merge;
+use crate::policy::Policies;
+use crate::state::conflicts::{ConflictInfo, ConflictStore, Rejected, Rejection};
+use crate::state::explain::{self, ConflictReport, ConflictReports};
+use crate::state::hash_table::{AppendRun, CasTable, FileId};
+use crate::state::objects::ObjectStore;
 
 const TTL: Duration = Duration::from_secs(1);
 
-/// Get the session ID for a given PID. Falls back to the PID itself on error.
-fn get_sid(pid: u32) -> u32 {
-    let sid = unsafe { libc::getsid(pid as i32) };
-    if sid < 0 { pid } else { sid as u32 }
-}
+/// Conflict store directory in the backing fs, hidden from the mount.
+pub const CONFLICT_STORE_NAME: &str = ".dibs-conflicts";
+
+/// Subdirectory of the conflict store directory holding shadow files.
+const SHADOW_DIR_NAME: &str = ".shadow";
 
 pub struct DibsFs {
     pub config: DibsConfig,
-    /// The backing directory root.
+    /// The backing directory root, as given on the command line.
     pub backing: PathBuf,
+    /// The backing directory itself, opened before mounting so that it can
+    /// still be reached when the mount covers its path.
+    pub root: Arc<Dir>,
     /// Inode table mapping inodes <-> paths (relative to backing root).
     pub inodes: InodeTable,
     /// File handle table.
@@ -45,55 +60,180 @@ pub struct DibsFs {
     pub dir_handles: Arc<DirHandleTable>,
     /// CAS tracking table.
     pub cas_table: Arc<CasTable>,
+    /// Content behind the reader hashes in `cas_table`.
+    pub objects: Arc<ObjectStore>,
+    /// Latest conflict explanation per agent, for .dibs/self/.
+    pub reports: Arc<ConflictReports>,
+    /// Names given to sessions by `dibs run`, for status and logs.
+    pub names: Arc<AgentNames>,
+    /// Per-path policies from `--strict` and `--policy`.
+    pub policies: Arc<Policies>,
+    /// Agents resolved from /proc, shared by every mount's resolver.
+    pub pids: Arc<PidCache>,
+    /// Descriptors that locks are passed through on.
+    pub locks: Arc<LockTable>,
+    /// Maps request callers to agents.
+    identity: Box<dyn IdentityResolver>,
+    /// The agent this mount belongs to, for `--agent NAME=MOUNTPOINT` mounts.
+    agent_mount: Option<AgentMount>,
     /// Start time for uptime reporting.
     pub start_time: std::time::Instant,
-    /// Conflict storage directory in the backing fs.
-    pub conflict_dir: Option<PathBuf>,
+    /// Registry of saved conflicts (if `--save-conflicts` is enabled).
+    pub conflicts: Option<Arc<ConflictStore>>,
+    /// Where stale writers' shadow files go (if `--defer-rejection` or
+    /// `--auto-merge` is enabled).
+    shadow_dir: Option<Arc<Dir>>,
+    /// Every mount's channel for invalidating what its kernel caches.
+    notifiers: Arc<Mutex<Vec<Notifier>>>,
 }
 
 impl DibsFs {
-    pub fn new(config: DibsConfig) -> Self {
+    pub fn new(config: DibsConfig) -> std::io::Result<Self> {
         let backing = config.backing.clone();
-        let conflict_dir = if config.save_conflicts {
-            let dir = config.backing.join(".dibs-conflicts");
-            let _ = std::fs::create_dir_all(&dir);
-            Some(dir)
+        let root = Arc::new(Dir::open(&backing)?);
+        let conflicts = if config.save_conflicts {
+            match ConflictStore::open_in(
+                &root,
+                Path::new(CONFLICT_STORE_NAME),
+                config.max_conflicts,
+                config.conflict_max_age_hours,
+            ) {
+                Ok(store) => Some(Arc::new(store)),
+                Err(e) => {
+                    warn!("Failed to open conflict store: {}", e);
+                    None
+                }
+            }
         } else {
             None
         };
 
-        Self {
+        let objects = Arc::new(ObjectStore::new(config.object_store_mb * 1024 * 1024));
+        let policies = Arc::new(Policies::new(config.strict, &config.policies));
+        let pids = Arc::new(PidCache::new());
+        let identity = identity::resolver(config.identity, config.ancestry_root, &pids);
+
+        let shadow_dir = if config.defer_rejection || config.auto_merge {
+            let dir = Path::new(CONFLICT_STORE_NAME).join(SHADOW_DIR_NAME);
+            // Shadow files only live as long as their handle; anything left
+            // over is from a previous run.
+            let _ = root.remove_dir_all(&dir);
+            match root.create_dir_all(&dir).and_then(|()| root.open_dir(&dir)) {
+                Ok(dir) => Some(Arc::new(dir)),
+                Err(e) => {
+                    warn!("Failed to create shadow directory: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
             config,
             backing,
+            root,
             inodes: InodeTable::new(),
             file_handles: Arc::new(HandleTable::new()),
             dir_handles: Arc::new(DirHandleTable::new()),
             cas_table: Arc::new(CasTable::new()),
+            objects,
+            reports: Arc::new(ConflictReports::new()),
+            names: Arc::new(AgentNames::new()),
+            policies,
+            pids,
+            locks: Arc::new(LockTable::new()),
+            identity,
+            agent_mount: None,
             start_time: std::time::Instant::now(),
-            conflict_dir,
+            conflicts,
+            shadow_dir,
+            notifiers: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// A filesystem for another FUSE session of the same daemon. Tracking
+    /// state is shared, so every mount sees the others' reads and writes;
+    /// only the kernel-facing inode and directory handle tables are its own.
+    /// With `agent`, every request through the mount belongs to that agent.
+    pub fn for_mount(&self, agent: Option<&AgentMount>) -> Self {
+        let identity: Box<dyn IdentityResolver> = match agent {
+            Some(mount) => Box::new(FixedResolver(AgentId::new("agent", &mount.name))),
+            None => identity::resolver(self.config.identity, self.config.ancestry_root, &self.pids),
+        };
+        Self {
+            config: self.config.clone(),
+            backing: self.backing.clone(),
+            root: Arc::clone(&self.root),
+            inodes: InodeTable::new(),
+            file_handles: Arc::clone(&self.file_handles),
+            dir_handles: Arc::new(DirHandleTable::new()),
+            cas_table: Arc::clone(&self.cas_table),
+            objects: Arc::clone(&self.objects),
+            reports: Arc::clone(&self.reports),
+            names: Arc::clone(&self.names),
+            policies: Arc::clone(&self.policies),
+            pids: Arc::clone(&self.pids),
+            locks: Arc::clone(&self.locks),
+            identity,
+            agent_mount: agent.cloned(),
+            start_time: self.start_time,
+            conflicts: self.conflicts.clone(),
+            shadow_dir: self.shadow_dir.clone(),
+            notifiers: Arc::clone(&self.notifiers),
+        }
+    }
+
+    /// Register the notification channel of a mount's FUSE session.
+    pub fn add_notifier(&self, notifier: Notifier) {
+        self.notifiers.lock().push(notifier);
+    }
+
+    /// Have every mount's kernel drop the attributes it caches for `ino`,
+    /// such as the size a shadow handle's writes gave the file.
+    fn invalidate_attrs(&self, ino: u64) {
+        for notifier in self.notifiers.lock().iter() {
+            if let Err(e) = notifier.inval_inode(INodeNo(ino), -1, 0) {
+                debug!("Failed to invalidate attributes of inode {}: {}", ino, e);
+            }
         }
     }
 
-    /// Convert a relative path (from inode table) to full backing path.
-    fn backing_path(&self, rel: &Path) -> PathBuf {
-        self.backing.join(rel)
+    /// The agent a request comes from.
+    fn agent(&self, req: &Request) -> AgentId {
+        self.identity.resolve(req.pid(), req.uid())
     }
 
     /// Convert a backing inode to its relative path, resolving via lookup if needed.
-    fn resolve_path(&self, parent: u64, name: &OsStr) -> (PathBuf, PathBuf) {
+    fn resolve_path(&self, parent: u64, name: &OsStr) -> PathBuf {
         let parent_rel = if parent == 1 {
             PathBuf::new()
         } else {
             self.inodes.get_path(parent).unwrap_or_default()
         };
-        let rel = parent_rel.join(name);
-        let full = self.backing_path(&rel);
-        (rel, full)
+        parent_rel.join(name)
+    }
+
+    /// With `--contain-symlinks`, whether a symlink at `rel` pointing at
+    /// `target` may be created or followed: relative targets must stay below
+    /// the root, absolute ones must lead into one of this daemon's mount
+    /// points.
+    fn symlink_allowed(&self, rel: &Path, target: &Path) -> bool {
+        if !self.config.contain_symlinks {
+            return true;
+        }
+        if target.is_absolute() {
+            let mountpoints = self.config.mountpoint.iter().chain(self.config.agents.iter().map(|m| &m.mountpoint));
+            return mountpoints
+                .filter_map(|mp| target.strip_prefix(mp).ok())
+                .any(|inside| backing::contained(Path::new(""), inside));
+        }
+        backing::contained(rel.parent().unwrap_or(Path::new("")), target)
     }
 
     /// Stat a path and register in inode table.
-    fn lookup_and_register(&self, rel: &Path, full: &Path) -> std::io::Result<FileAttr> {
-        let st = lstat(full)?;
+    fn lookup_and_register(&self, rel: &Path) -> std::io::Result<FileAttr> {
+        let st = self.root.lstat(rel)?;
         let mut attr = stat_to_file_attr(&st);
         // For the root directory, force inode to 1
         if rel.as_os_str().is_empty() {
@@ -105,6 +245,339 @@ impl DibsFs {
         Ok(attr)
     }
 
+    /// Save rejected write data, along with the file's current content, to
+    /// the conflict store if configured.
+    fn save_conflict(&self, info: &ConflictInfo, data: &[u8]) {
+        if let Some(ref conflicts) = self.conflicts {
+            let current = self.root.read(&info.path).ok();
+            match conflicts.save(info, data, current.as_deref()) {
+                Ok(id) => info!(
+                    "Saved {}'s rejected write to {} as conflict {}",
+                    self.names.label(&info.agent),
+                    info.path.display(),
+                    id
+                ),
+                Err(e) => warn!("Failed to save rejected write to {}: {}", info.path.display(), e),
+            }
+        }
+    }
+
+    /// Record an explanation of a rejected write to file `id` for the
+    /// rejected agent: who changed the file, and a diff from what the agent
+    /// last read.
+    fn explain_conflict(&self, id: FileId, info: &ConflictInfo) {
+        let base = info.reader_hash.as_deref().and_then(|h| self.objects.get(h));
+        let current = self
+            .root
+            .lstat(&info.path)
+            .ok()
+            .filter(|st| st.st_size as u64 <= self.objects.max_object_size())
+            .and_then(|_| self.root.read(&info.path).ok());
+        let diff = match (base, current) {
+            (Some(base), Some(current)) => {
+                explain::unified_diff(&info.path.to_string_lossy(), &base, &current)
+            }
+            _ => None,
+        };
+        self.reports.record(ConflictReport {
+            path: info.path.clone(),
+            agent: info.agent.clone(),
+            time: chrono::Utc::now(),
+            kind: info.kind,
+            reason: info.reason.clone(),
+            reader_hash: info.reader_hash.clone(),
+            actual_hash: info.actual_hash.clone(),
+            changed_by: self.cas_table.last_write(id),
+            diff,
+        });
+    }
+
+    /// Whether `agent`'s last read of file `id` at `rel` still holds: None
+    /// without a receipt to go by, false if the file has changed since the
+    /// receipt was taken, or been written since it was evicted.
+    fn receipt_fresh(&self, agent: &AgentId, id: FileId, rel: &Path) -> std::io::Result<Option<bool>> {
+        match self.cas_table.get_reader_hash(agent, id) {
+            Some(reader_hash) => Ok(Some(reader_hash == self.root.hash(rel)?)),
+            None if self.cas_table.evicted_stale(agent, id) => Ok(Some(false)),
+            None => Ok(None),
+        }
+    }
+
+    /// CAS check for operations that remove or replace file `id` at `rel` as
+    /// a whole: if `agent` holds a receipt for it, the file must not have
+    /// changed since. Explains the conflict and returns false if it has.
+    fn receipt_current(&self, agent: &AgentId, id: FileId, rel: &Path, op: &str, reason: &str) -> bool {
+        if !matches!(self.receipt_fresh(agent, id, rel), Ok(Some(false))) {
+            return true;
+        }
+        let reader_hash = self.cas_table.get_reader_hash(agent, id);
+        warn!(
+            "CAS conflict on {} {} ({}): {}",
+            op,
+            rel.display(),
+            self.names.label(agent),
+            if reader_hash.is_some() {
+                "file changed since last read"
+            } else {
+                "file written since its receipt was evicted"
+            }
+        );
+        self.explain_conflict(id, &ConflictInfo {
+            path: rel.to_path_buf(),
+            agent: agent.clone(),
+            reader_hash,
+            actual_hash: self.root.hash(rel).ok(),
+            kind: Rejection::Stale,
+            reason: reason.to_string(),
+        });
+        false
+    }
+
+    /// Under a strict policy for `rel`, whether `agent` may overwrite or
+    /// remove the file there (`st`) by `op`: only with a receipt for it,
+    /// unless it's empty. Explains the rejection if not.
+    fn strict_allows(&self, agent: &AgentId, rel: &Path, st: &libc::stat, op: &str) -> bool {
+        if st.st_mode & libc::S_IFMT != libc::S_IFREG || st.st_size == 0 || !self.policies.applies(PolicyKind::Strict, rel) {
+            return true;
+        }
+        let id = FileId::of(st);
+        if self.cas_table.get_reader_hash(agent, id).is_some() {
+            return true;
+        }
+        warn!(
+            "Strict: refused {} of {} ({}): never read",
+            op,
+            rel.display(),
+            self.names.label(agent)
+        );
+        self.explain_conflict(id, &ConflictInfo {
+            path: rel.to_path_buf(),
+            agent: agent.clone(),
+            reader_hash: None,
+            actual_hash: self.root.hash(rel).ok(),
+            kind: Rejection::NeverRead,
+            reason: format!("strict: {} of a file the agent never read", op),
+        });
+        false
+    }
+
+    /// Whether something has appeared at `rel` since `agent` looked it up
+    /// and found it missing, so that an `op` meant to create it would meet
+    /// another agent's file. Explains the conflict if so.
+    fn appeared(&self, agent: &AgentId, rel: &Path, op: &str) -> bool {
+        if !self.cas_table.saw_absent(agent, rel) {
+            return false;
+        }
+        let Ok(st) = self.root.lstat(rel) else {
+            return false;
+        };
+        warn!(
+            "CAS conflict on {} {} ({}): created since it was seen missing",
+            op,
+            rel.display(),
+            self.names.label(agent)
+        );
+        self.explain_conflict(FileId::of(&st), &ConflictInfo {
+            path: rel.to_path_buf(),
+            agent: agent.clone(),
+            reader_hash: None,
+            actual_hash: self.root.hash(rel).ok(),
+            kind: Rejection::Appeared,
+            reason: format!("{} of a path created since it was seen missing", op),
+        });
+        true
+    }
+
+    /// Whether the writers of file `id` at `rel` coordinate through locks:
+    /// it falls under the locks policy, or someone has locked it before.
+    fn coordinated(&self, id: FileId, rel: &Path) -> bool {
+        if self.policies.applies(PolicyKind::Locks, rel) {
+            self.cas_table.coordinate(id, rel, None);
+            return true;
+        }
+        self.cas_table.is_coordinated(id)
+    }
+
+    /// The file handle `fh` takes locks on, and its path.
+    fn lock_target(&self, fh: u64) -> Option<(FileId, PathBuf)> {
+        let h = self.file_handles.get(fh).filter(|h| h.real_fd >= 0)?;
+        Some((h.id?, h.path.clone()))
+    }
+
+    /// Open the file at `rel` for taking locks on: read-write, as flock()
+    /// asks for write locks whatever the access mode, or read-only if that's
+    /// all it can be opened for.
+    fn open_lock_fd(&self, rel: &Path) -> std::io::Result<OwnedFd> {
+        let fd = self
+            .root
+            .openat(rel, libc::O_RDWR | libc::O_CLOEXEC, 0)
+            .or_else(|_| self.root.openat(rel, libc::O_RDONLY | libc::O_CLOEXEC, 0))?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// CAS check before handle `fh` changes file `id` at `rel` by `op`. Write
+    /// ownership is normally acquired at open time; if it wasn't, re-hash
+    /// the backing file and compare against the reader hash as a safety net.
+    /// Shadow handles are already rejected and only change their shadow
+    /// file. Explains the conflict, saving the rejected `data` if there is
+    /// any, and returns false if stale; marks the handle as having written
+    /// otherwise.
+    fn check_write(
+        &self,
+        fh: u64,
+        id: FileId,
+        rel: &Path,
+        agent: &AgentId,
+        op: &str,
+        data: Option<&[u8]>,
+    ) -> bool {
+        let is_shadow = self.file_handles.get(fh).is_some_and(|h| h.shadow.is_some());
+        if !is_shadow && !self.cas_table.has_active_writer(id) && !self.cas_table.is_coordinated(id) {
+            let actual_hash = self.root.hash(rel).unwrap_or_default();
+            if let Err(e) = self.cas_table.check_and_acquire_write(id, rel, fh, agent, &self.file_handles, &actual_hash) {
+                warn!("CAS conflict on {} ({}): {}", op, self.names.label(agent), e);
+                let info = self.conflict_info(id, rel, fh, agent, &actual_hash, e);
+                self.explain_conflict(id, &info);
+                if let Some(data) = data {
+                    self.save_conflict(&info, data);
+                }
+                return false;
+            }
+        }
+        if let Some(mut h) = self.file_handles.get_mut(fh) {
+            h.has_written = true;
+        }
+        true
+    }
+
+    /// Describe a rejected write by handle `fh` for the conflict store.
+    fn conflict_info(&self, id: FileId, rel: &Path, fh: u64, agent: &AgentId, actual_hash: &[u8], rejected: Rejected) -> ConflictInfo {
+        let reader_hash = self
+            .file_handles
+            .get(fh)
+            .and_then(|h| h.hash_at_open.clone())
+            .or_else(|| self.cas_table.get_reader_hash(agent, id));
+        ConflictInfo {
+            path: rel.to_path_buf(),
+            agent: agent.clone(),
+            reader_hash,
+            actual_hash: Some(actual_hash.to_vec()),
+            kind: rejected.kind,
+            reason: rejected.reason,
+        }
+    }
+
+    /// Create the shadow file for a deferred rejection on handle `fh`.
+    /// Unless the open truncates, the shadow starts as a copy of the backing
+    /// file so that writes at an offset still produce a complete file.
+    fn open_shadow(&self, fh: u64, rel: &Path, flags: i32) -> std::io::Result<(i32, PathBuf)> {
+        let shadow_dir = self.shadow_dir.as_ref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no shadow directory configured")
+        })?;
+        let shadow_path = PathBuf::from(fh.to_string());
+        if flags & libc::O_TRUNC == 0 {
+            self.root.copy(rel, shadow_dir, &shadow_path, 0o600)?;
+        }
+        // Keep the caller's access mode, O_APPEND and O_TRUNC; the shadow itself is always created.
+        let shadow_flags =
+            (flags & (libc::O_ACCMODE | libc::O_APPEND | libc::O_TRUNC)) | libc::O_CREAT;
+        let fd = shadow_dir.openat(&shadow_path, shadow_flags, 0o600)?;
+        Ok((fd, shadow_path))
+    }
+
+    /// Hash a backing file, keeping its content in the object store if it
+    /// fits. Used wherever the hash becomes a reader receipt.
+    fn snapshot(&self, rel: &Path) -> std::io::Result<Vec<u8>> {
+        if self.root.lstat(rel)?.st_size as u64 > self.objects.max_object_size() {
+            return self.root.hash(rel);
+        }
+        Ok(self.objects.insert(self.root.read(rel)?))
+    }
+
+    /// Append `data` through `fd`, opened with O_APPEND, to wherever the file
+    /// at `rel` ends by now, as part of `run`. Runs under the file's lock.
+    /// The file is only hashed when a run starts: where the last append of a
+    /// run left the end, nothing else has changed its size since.
+    fn write_append(&self, rel: &Path, fd: i32, data: &[u8], run: &mut Option<AppendRun>) -> std::io::Result<usize> {
+        let size = self.root.lstat(rel)?.st_size as u64;
+        if run.as_ref().is_none_or(|run| run.end != size) {
+            *run = Some(AppendRun {
+                before: self.root.hash(rel)?,
+                end: size,
+            });
+        }
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if let Some(run) = run {
+            run.end += n as u64;
+        }
+        Ok(n as usize)
+    }
+
+    /// Settle file `id`'s run of appends: receipts that were current before
+    /// it stay current, unless the file changed size otherwise since.
+    fn settle_appends(&self, id: FileId, rel: &Path) {
+        self.cas_table.settle_appends(id, |run| {
+            if self.root.lstat(rel).ok()?.st_size as u64 != run.end {
+                return None;
+            }
+            self.snapshot(rel).ok()
+        });
+    }
+
+    /// Resolve the shadow file of a stale writer: merge it into the backing
+    /// file if possible, otherwise save it as a conflict. Returns the new
+    /// merge base for the handle if the merge was committed.
+    fn settle_shadow(&self, id: FileId, rel: &Path, fh: u64, agent: &AgentId, shadow: &ShadowFile) -> Option<Arc<[u8]>> {
+        let shadow_dir = self.shadow_dir.as_ref()?;
+        let incoming = match shadow_dir.read(&shadow.path) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to read shadow file {}: {}", shadow.path.display(), e);
+                return None;
+            }
+        };
+        let mut conflict = shadow.conflict.clone();
+        if let Some(ref base) = shadow.base {
+            match self.commit_merge(id, rel, fh, agent, base, &incoming) {
+                Ok(()) => {
+                    info!("Auto-merged {}'s stale write to {}", self.names.label(agent), rel.display());
+                    return Some(Arc::from(incoming));
+                }
+                Err(e) => {
+                    conflict.reason = format!("{}; auto-merge failed: {}", conflict.reason, e);
+                }
+            }
+        }
+        warn!("CAS conflict (deferred, {}): {}", self.names.label(agent), conflict.reason);
+        self.explain_conflict(id, &conflict);
+        self.save_conflict(&conflict, &incoming);
+        None
+    }
+
+    /// Merge `incoming` with the changes made to `rel` since `base` and write
+    /// the result to the backing file. The agent never read the merged
+    /// result, so its receipt is for `incoming`: rewriting the file from its
+    /// own copy later is checked against the merged file.
+    fn commit_merge(&self, id: FileId, rel: &Path, fh: u64, agent: &AgentId, base: &[u8], incoming: &[u8]) -> Result<(), String> {
+        self.cas_table.acquire_write(id, rel, fh)?;
+        let result = self
+            .root
+            .read(rel)
+            .map_err(|e| e.to_string())
+            .and_then(|current| merge::merge(rel, base, &current, incoming).map_err(|e| e.to_string()))
+            .and_then(|merged| self.root.write(rel, &merged).map_err(|e| e.to_string()));
+        let result = result.map(|()| {
+            let hash = self.objects.insert(incoming.to_vec());
+            self.cas_table.record_write(agent, id, rel);
+            self.cas_table.update_reader(agent, id, hash);
+        });
+        self.cas_table.release_write(id, fh);
+        result
+    }
+
     /// Check if a name refers to the virtual .dibs directory.
     fn is_dibs_name(name: &OsStr) -> bool {
         name.as_bytes() == DIBS_DIR_NAME.as_bytes()
@@ -115,6 +588,12 @@ impl DibsFs {
         InodeTable::is_synthetic(ino)
     }
 
+    /// Check if a path is in the shadow directory. Unlike the rest of the
+    /// conflict store, it can't be reached through the mount at all.
+    fn is_shadow_path(rel: &Path) -> bool {
+        rel.starts_with(Path::new(CONFLICT_STORE_NAME).join(SHADOW_DIR_NAME))
+    }
+
     /// Build a synthetic FileAttr for a virtual directory.
     fn dibs_dir_attr(ino: u64) -> FileAttr {
         FileAttr {
@@ -157,6 +636,14 @@ impl DibsFs {
         }
     }
 
+    /// Attributes of .dibs/names, the only writable virtual file.
+    fn names_attr(&self) -> FileAttr {
+        FileAttr {
+            perm: 0o644,
+            ..Self::dibs_file_attr(DIBS_NAMES_INO, self.names.render().len() as u64)
+        }
+    }
+
     /// Generate status JSON.
     fn status_json(&self) -> String {
         let uptime = self.start_time.elapsed().as_secs();
@@ -165,27 +652,89 @@ impl DibsFs {
         serde_json::json!({
             "tracked_files": tracked,
             "active_locks": active_locks,
+            "objects": self.objects.len(),
+            "object_bytes": self.objects.size(),
             "uptime_seconds": uptime,
             "session_id": self.config.session_id,
+            "backing": self.backing,
+            "agent": self.agent_mount.as_ref().map(|m| &m.name),
+            "agents": self.agents_json(),
         })
         .to_string()
     }
 
-    /// Generate locks JSON.
+    /// Each `--agent` mount's and each named session's view of the shared
+    /// state.
+    fn agents_json(&self) -> Vec<serde_json::Value> {
+        let open = self.file_handles.list_open();
+        let view = |name: &str, agent: &AgentId| {
+            serde_json::json!({
+                "name": name,
+                "agent": agent,
+                "files_read": self.cas_table.files_read(agent),
+                "open_files": open.iter().filter(|f| &f.agent == agent).count(),
+                "last_conflict": self.reports.last(agent).map(|r| serde_json::json!({
+                    "path": r.path,
+                    "time": r.time.to_rfc3339(),
+                    "reason": r.reason,
+                })),
+            })
+        };
+        let mounts = self.config.agents.iter().map(|mount| {
+            let mut entry = view(&mount.name, &AgentId::new("agent", &mount.name));
+            entry["mountpoint"] = serde_json::json!(mount.mountpoint);
+            entry
+        });
+        let named = self.names.list().into_iter().map(|(agent, name)| view(&name, &agent));
+        mounts.chain(named).collect()
+    }
+
+    /// Generate locks JSON, naming the agent behind each write owner.
     fn locks_json(&self) -> String {
-        let entries = self.cas_table.all_entries();
+        let mut entries = self.cas_table.all_entries();
+        for entry in &mut entries {
+            entry.agent = entry
+                .write_owner
+                .and_then(|fh| self.file_handles.get(fh).map(|h| self.names.label(&h.agent)));
+        }
         serde_json::to_string_pretty(&entries).unwrap_or_else(|_| "[]".to_string())
     }
 }
 
+/// Answer an extended attribute request for up to `size` bytes with `value`:
+/// with its size if asked for none, or `ERANGE` if it doesn't fit.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(Errno::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
 impl Filesystem for DibsFs {
     fn init(
         &mut self,
         _req: &Request,
-        _config: &mut KernelConfig,
+        config: &mut KernelConfig,
     ) -> std::io::Result<()> {
         info!("dibs filesystem initialized, backing={}", self.backing.display());
 
+        // Have O_TRUNC delivered with open() instead of as a separate setattr,
+        // so truncation only happens after the CAS check has passed.
+        if let Err(e) = config.add_capabilities(InitFlags::FUSE_ATOMIC_O_TRUNC) {
+            warn!("Kernel does not support atomic O_TRUNC: {:?}", e);
+        }
+
+        // Byte-range locks and flock() come to setlk so they can be passed
+        // through; without these the kernel keeps them to each mount. Only
+        // Linux has lock commands that dibs can take on another's behalf.
+        #[cfg(target_os = "linux")]
+        if let Err(e) = config.add_capabilities(InitFlags::FUSE_POSIX_LOCKS | InitFlags::FUSE_FLOCK_LOCKS) {
+            warn!("Kernel does not support passing locks through: {:?}", e);
+        }
+
         // Register root inode
         self.inodes.insert(1, PathBuf::new());
 
@@ -196,7 +745,7 @@ impl Filesystem for DibsFs {
         info!("dibs filesystem shutting down");
     }
 
-    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
         let parent = u64::from(parent);
         debug!("lookup(parent={}, name={:?})", parent, name);
 
@@ -223,24 +772,66 @@ impl Filesystem for DibsFs {
                 reply.entry(&TTL, &Self::dibs_dir_attr(DIBS_CONFLICTS_DIR_INO), Generation(0));
                 return;
             }
+            if name_bytes == DIBS_OBJECTS_NAME.as_bytes() {
+                reply.entry(&TTL, &Self::dibs_dir_attr(DIBS_OBJECTS_DIR_INO), Generation(0));
+                return;
+            }
+            if name_bytes == DIBS_SELF_NAME.as_bytes() {
+                reply.entry(&TTL, &Self::dibs_dir_attr(DIBS_SELF_DIR_INO), Generation(0));
+                return;
+            }
+            if name_bytes == DIBS_NAMES_NAME.as_bytes() {
+                reply.entry(&TTL, &self.names_attr(), Generation(0));
+                return;
+            }
             reply.error(Errno::ENOENT);
             return;
         }
 
-        let (rel, full) = self.resolve_path(parent, name);
-        match self.lookup_and_register(&rel, &full) {
+        // Dynamic .dibs/ children (saved conflicts, objects, self)
+        if Self::is_dibs_ino(parent) {
+            let vpath = match self.virtual_path(parent) {
+                Some(p) => p.join(name),
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            match self.virtual_node(&vpath, &self.agent(req)) {
+                Some(node) => {
+                    let ino = self.virtual_ino(&vpath);
+                    reply.entry(&TTL, &Self::virtual_attr(ino, &node), Generation(0));
+                }
+                None => reply.error(Errno::ENOENT),
+            }
+            return;
+        }
+
+        let rel = self.resolve_path(parent, name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::ENOENT);
+            return;
+        }
+        match self.lookup_and_register(&rel) {
             Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
-            Err(e) => reply.error(Errno::from(e)),
+            Err(e) => {
+                // Remembered so that creating the path later can tell whether
+                // someone else got there first
+                if e.raw_os_error() == Some(libc::ENOENT) {
+                    self.cas_table.record_absent(&self.agent(req), &rel);
+                }
+                reply.error(Errno::from(e))
+            }
         }
     }
 
-    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+    fn getattr(&self, req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
         let ino = u64::from(ino);
         debug!("getattr(ino={})", ino);
 
         // Root inode
         if ino == 1 {
-            match lstat(&self.backing) {
+            match self.root.lstat(Path::new("")) {
                 Ok(st) => {
                     let mut attr = stat_to_file_attr(&st);
                     attr.ino = INodeNo(1);
@@ -266,15 +857,21 @@ impl Filesystem for DibsFs {
             reply.attr(&TTL, &Self::dibs_file_attr(DIBS_LOCKS_INO, content.len() as u64));
             return;
         }
-        if ino == DIBS_CONFLICTS_DIR_INO {
-            reply.attr(&TTL, &Self::dibs_dir_attr(DIBS_CONFLICTS_DIR_INO));
+        if ino == DIBS_NAMES_INO {
+            reply.attr(&TTL, &self.names_attr());
+            return;
+        }
+        if Self::is_dibs_ino(ino) {
+            match self.virtual_path(ino).and_then(|p| self.virtual_node(&p, &self.agent(req))) {
+                Some(node) => reply.attr(&TTL, &Self::virtual_attr(ino, &node)),
+                None => reply.error(Errno::ENOENT),
+            }
             return;
         }
 
         // Real inode
         if let Some(rel) = self.inodes.get_path(ino) {
-            let full = self.backing_path(&rel);
-            match lstat(&full) {
+            match self.root.lstat(&rel) {
                 Ok(st) => {
                     let mut attr = stat_to_file_attr(&st);
                     attr.ino = INodeNo(ino);
@@ -289,7 +886,7 @@ impl Filesystem for DibsFs {
 
     fn setattr(
         &self,
-        _req: &Request,
+        req: &Request,
         ino: INodeNo,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -308,6 +905,11 @@ impl Filesystem for DibsFs {
         let ino = u64::from(ino);
         debug!("setattr(ino={})", ino);
 
+        // Truncating .dibs/names before writing commands to it is harmless
+        if ino == DIBS_NAMES_INO {
+            reply.attr(&TTL, &self.names_attr());
+            return;
+        }
         if Self::is_dibs_ino(ino) {
             reply.error(Errno::EACCES);
             return;
@@ -320,23 +922,98 @@ impl Filesystem for DibsFs {
                 return;
             }
         };
-        let full = self.backing_path(&rel);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+
+        // Truncating a shadow handle only touches the shadow file.
+        if let Some(handle_fh) = fh {
+            let shadow_fd = self
+                .file_handles
+                .get(u64::from(handle_fh))
+                .filter(|h| h.shadow.is_some())
+                .map(|h| h.real_fd);
+            if let Some(shadow_fd) = shadow_fd {
+                if let Some(new_size) = size {
+                    if unsafe { libc::ftruncate(shadow_fd, new_size as libc::off_t) } != 0 {
+                        reply.error(Errno::from(std::io::Error::last_os_error()));
+                        return;
+                    }
+                    if let Some(mut h) = self.file_handles.get_mut(u64::from(handle_fh)) {
+                        if let Some(ref mut s) = h.shadow {
+                            s.dirty = true;
+                        }
+                    }
+                }
+                match fstat(shadow_fd) {
+                    Ok(st) => {
+                        let mut attr = stat_to_file_attr(&st);
+                        attr.ino = INodeNo(ino);
+                        reply.attr(&TTL, &attr);
+                    }
+                    Err(e) => reply.error(Errno::from(e)),
+                }
+                return;
+            }
+        }
+
+        // Only regular files carry CAS state
+        let agent = fh
+            .and_then(|fh| self.file_handles.get(u64::from(fh)).map(|h| h.agent.clone()))
+            .unwrap_or_else(|| self.agent(req));
+        let st = match self.root.lstat(&rel) {
+            Ok(st) => st,
+            Err(e) => {
+                reply.error(Errno::from(e));
                 return;
             }
         };
+        let id = Some(FileId::of(&st)).filter(|_| st.st_mode & libc::S_IFMT == libc::S_IFREG);
+
+        let changes_attrs =
+            mode.is_some() || uid.is_some() || gid.is_some() || atime.is_some() || mtime.is_some() || flags.is_some();
+        let check_attrs = changes_attrs && self.config.attr_policy == AttrPolicy::Check;
+        // An agent in the middle of writing the file is only behind on its
+        // own writes, which its receipt catches up with at flush
+        let writing = |id| {
+            self.cas_table
+                .write_owner(id)
+                .and_then(|owner| self.file_handles.get(owner))
+                .is_some_and(|h| h.agent == agent)
+        };
+        let checked = id.filter(|&id| check_attrs && !writing(id));
+        if let Some(id) = checked {
+            if !self.receipt_current(&agent, id, &rel, "setattr", "attribute change on a file that changed since last read") {
+                reply.error(Errno::EIO);
+                return;
+            }
+        }
 
-        // Handle truncate — needs CAS check
+        // Truncation destroys content like a write, so it goes through the
+        // same check and takes write ownership. A truncate by path gets a
+        // handle of its own for the duration.
         if let Some(new_size) = size {
-            if let Some(handle_fh) = fh {
-                let handle_fh = u64::from(handle_fh);
-                let sid = self.file_handles.get(handle_fh).map(|h| h.sid).unwrap_or(0);
-                let actual_hash = cas::hash_file(&full).unwrap_or_default();
-                if let Err(e) = self.cas_table.check_and_acquire_write(&rel, handle_fh, sid, &self.file_handles, &actual_hash) {
-                    warn!("CAS conflict on truncate: {}", e);
+            if !self.strict_allows(&agent, &rel, &st, "truncate") {
+                reply.error(Errno::EIO);
+                return;
+            }
+            let mut path_fh = None;
+            if let Some(id) = id {
+                let handle_fh = match fh {
+                    Some(fh) => u64::from(fh),
+                    None => *path_fh.insert(self.file_handles.alloc(
+                        -1,
+                        rel.clone(),
+                        Some(id),
+                        libc::O_WRONLY,
+                        None,
+                        agent.clone(),
+                    )),
+                };
+                let actual_hash = self.root.hash(&rel).unwrap_or_default();
+                if let Err(e) = self.cas_table.check_and_acquire_write(id, &rel, handle_fh, &agent, &self.file_handles, &actual_hash) {
+                    warn!("CAS conflict on truncate ({}): {}", self.names.label(&agent), e);
+                    self.explain_conflict(id, &self.conflict_info(id, &rel, handle_fh, &agent, &actual_hash, e));
+                    if let Some(path_fh) = path_fh {
+                        self.file_handles.remove(path_fh);
+                    }
                     reply.error(Errno::EIO);
                     return;
                 }
@@ -346,34 +1023,56 @@ impl Filesystem for DibsFs {
             } else {
                 None
             };
-            let rc = if let Some(fd) = fd {
-                unsafe { libc::ftruncate(fd, new_size as libc::off_t) }
+            let result = if let Some(fd) = fd {
+                if unsafe { libc::ftruncate(fd, new_size as libc::off_t) } == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
             } else {
-                unsafe { libc::truncate(c_path.as_ptr(), new_size as libc::off_t) }
+                self.root.truncate(&rel, new_size as libc::off_t)
             };
-            if rc != 0 {
-                reply.error(Errno::from(std::io::Error::last_os_error()));
+            // The truncating agent's receipt follows: at once for a path,
+            // at flush for a handle, as after a write
+            if let Some(id) = id {
+                match path_fh {
+                    Some(path_fh) => {
+                        if result.is_ok() {
+                            self.cas_table.record_write(&agent, id, &rel);
+                            if let Ok(new_hash) = self.snapshot(&rel) {
+                                self.cas_table.update_reader(&agent, id, new_hash);
+                            }
+                        }
+                        self.cas_table.release_write(id, path_fh);
+                        self.file_handles.remove(path_fh);
+                    }
+                    None if result.is_ok() => {
+                        if let Some(mut h) = fh.and_then(|fh| self.file_handles.get_mut(u64::from(fh))) {
+                            h.has_written = true;
+                        }
+                    }
+                    None => {}
+                }
+            }
+            if let Err(e) = result {
+                reply.error(Errno::from(e));
                 return;
             }
         }
 
         if let Some(mode) = mode {
-            unsafe {
-                if libc::chmod(c_path.as_ptr(), mode as libc::mode_t) != 0 {
-                    reply.error(Errno::from(std::io::Error::last_os_error()));
-                    return;
-                }
+            if let Err(e) = self.root.chmod(&rel, mode as libc::mode_t) {
+                reply.error(Errno::from(e));
+                return;
             }
         }
 
         if uid.is_some() || gid.is_some() {
             let new_uid = uid.map(|u| u as libc::uid_t).unwrap_or(u32::MAX);
             let new_gid = gid.map(|g| g as libc::gid_t).unwrap_or(u32::MAX);
-            unsafe {
-                if libc::chown(c_path.as_ptr(), new_uid, new_gid) != 0 {
-                    reply.error(Errno::from(std::io::Error::last_os_error()));
-                    return;
-                }
+            if let Err(e) = self.root.chown(&rel, new_uid, new_gid) {
+                reply.error(Errno::from(e));
+                return;
             }
         }
 
@@ -398,25 +1097,28 @@ impl Filesystem for DibsFs {
                 }
             };
             let times = [to_timespec(atime), to_timespec(mtime)];
-            unsafe {
-                if libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) != 0 {
-                    reply.error(Errno::from(std::io::Error::last_os_error()));
-                    return;
-                }
+            if let Err(e) = self.root.utimens(&rel, &times) {
+                reply.error(Errno::from(e));
+                return;
             }
         }
 
         if let Some(flags) = flags {
-            unsafe {
-                if libc::chflags(c_path.as_ptr(), flags.bits()) != 0 {
-                    reply.error(Errno::from(std::io::Error::last_os_error()));
-                    return;
-                }
+            if let Err(e) = self.root.chflags(&rel, flags.bits()) {
+                reply.error(Errno::from(e));
+                return;
+            }
+        }
+
+        // The content is unchanged, so a checked receipt stays good
+        if let Some(id) = checked {
+            if let Some(hash) = self.cas_table.get_reader_hash(&agent, id) {
+                self.cas_table.update_reader(&agent, id, hash);
             }
         }
 
         // Return updated attrs
-        match lstat(&full) {
+        match self.root.lstat(&rel) {
             Ok(st) => {
                 let mut attr = stat_to_file_attr(&st);
                 attr.ino = INodeNo(ino);
@@ -432,13 +1134,30 @@ impl Filesystem for DibsFs {
         debug!("open(ino={}, flags={})", ino, raw_flags);
 
         // Virtual files
-        if ino == DIBS_STATUS_INO || ino == DIBS_LOCKS_INO {
-            let fh = self.file_handles.alloc(-1, PathBuf::from(".dibs/virtual"), raw_flags, None, 0);
+        if ino == DIBS_STATUS_INO || ino == DIBS_LOCKS_INO || ino == DIBS_NAMES_INO {
+            let fh = self.file_handles.alloc(-1, PathBuf::from(".dibs/virtual"), None, raw_flags, None, self.agent(req));
             reply.opened(FileHandle(fh), FopenFlags::empty());
             return;
         }
         if Self::is_dibs_ino(ino) {
-            reply.error(Errno::EACCES);
+            if raw_flags & libc::O_ACCMODE != libc::O_RDONLY {
+                reply.error(Errno::EACCES);
+                return;
+            }
+            match self.virtual_path(ino) {
+                Some(vpath) if matches!(self.virtual_node(&vpath, &self.agent(req)), Some(VirtualNode::File(_))) => {
+                    // Per-agent files differ between callers, so they
+                    // must bypass the page cache
+                    let fopen = if Self::is_per_agent(&vpath) {
+                        FopenFlags::FOPEN_DIRECT_IO
+                    } else {
+                        FopenFlags::empty()
+                    };
+                    let fh = self.file_handles.alloc(-1, vpath, None, raw_flags, None, self.agent(req));
+                    reply.opened(FileHandle(fh), fopen);
+                }
+                _ => reply.error(Errno::EACCES),
+            }
             return;
         }
 
@@ -449,41 +1168,79 @@ impl Filesystem for DibsFs {
                 return;
             }
         };
-        let full = self.backing_path(&rel);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        // Whatever the path leads to now is what gets hashed and opened
+        let st = match self.root.lstat(&rel) {
+            Ok(st) => st,
+            Err(e) => {
+                reply.error(Errno::from(e));
                 return;
             }
         };
-
+        let id = FileId::of(&st);
         let access_mode = raw_flags & libc::O_ACCMODE;
-        let sid = get_sid(req.pid());
-
-        // For write modes, hash the file BEFORE libc::open which may truncate it.
-        // This pre-truncation hash is the actual state we compare against the reader hash.
-        let pre_open_hash = if access_mode != libc::O_RDONLY {
-            cas::hash_file(&full).ok()
-        } else {
-            None
-        };
+        let agent = self.agent(req);
+
+        // Writers of a file coordinated through locks keep out of each
+        // other's way themselves, so it opens without a CAS check or write
+        // ownership. Each agent's mount has its own page cache, which would
+        // hide the other agents' writes from it, so that is bypassed.
+        if self.coordinated(id, &rel) {
+            let fd = match self.root.openat(&rel, raw_flags, 0) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    reply.error(Errno::from(e));
+                    return;
+                }
+            };
+            let fh = self.file_handles.alloc(fd, rel.clone(), Some(id), raw_flags, None, agent.clone());
+            self.cas_table.clear_absent(&agent, &rel);
+            debug!("open: {} is coordinated through locks agent={}", rel.display(), self.names.label(&agent));
+            reply.opened(FileHandle(fh), FopenFlags::FOPEN_DIRECT_IO);
+            return;
+        }
 
-        let fd = unsafe { libc::open(c_path.as_ptr(), raw_flags) };
-        if fd < 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
+        // Appending can't destroy what the agent never saw
+        if access_mode != libc::O_RDONLY && raw_flags & libc::O_APPEND == 0 && !self.strict_allows(&agent, &rel, &st, "write") {
+            reply.error(Errno::EIO);
             return;
         }
 
-        let hash = if access_mode == libc::O_RDONLY {
-            // Read-only: compute hash (file wasn't modified by open), record in reader_hashes
-            let h = cas::hash_file(&full).ok();
-            if let Some(ref h) = h {
-                self.cas_table.record_reader(&rel, h.clone(), sid);
-                debug!("open: tracked {} hash={} sid={}", rel.display(), cas::hash_hex(h), sid);
+        // Under the append policy, appenders take no write ownership and
+        // skip the CAS check; `write` serializes their appends instead. The
+        // kernel's idea of where the file ends may be out of date, so its
+        // page cache is bypassed.
+        if access_mode != libc::O_RDONLY
+            && raw_flags & libc::O_APPEND != 0
+            && raw_flags & libc::O_TRUNC == 0
+            && self.policies.applies(PolicyKind::Append, &rel)
+        {
+            let fd = match self.root.openat(&rel, raw_flags, 0) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    reply.error(Errno::from(e));
+                    return;
+                }
+            };
+            let fh = self.file_handles.alloc(fd, rel.clone(), Some(id), raw_flags, None, agent.clone());
+            if let Some(mut h) = self.file_handles.get_mut(fh) {
+                h.appending = true;
             }
-            h
-        } else {
+            self.cas_table.clear_absent(&agent, &rel);
+            debug!("open: appending to {} agent={}", rel.display(), self.names.label(&agent));
+            reply.opened(FileHandle(fh), FopenFlags::FOPEN_DIRECT_IO);
+            return;
+        }
+
+        // For write modes, hash the file BEFORE libc::open which may truncate it.
+        // This pre-truncation hash is the actual state we compare against the reader hash.
+        // O_RDWR records it as a reader receipt, so keep the content too.
+        let pre_open_hash = match access_mode {
+            libc::O_RDONLY => None,
+            libc::O_RDWR => self.snapshot(&rel).ok(),
+            _ => self.root.hash(&rel).ok(),
+        };
+
+        if access_mode != libc::O_RDONLY {
             // O_WRONLY or O_RDWR: CAS check using pre-truncation hash, acquire write ownership
             // O_WRONLY: hash_at_open = None (CAS uses reader_hashes)
             // O_RDWR: hash_at_open = pre_open_hash (CAS uses hash_at_open directly)
@@ -492,37 +1249,99 @@ impl Filesystem for DibsFs {
             } else {
                 None
             };
-            let fh = self.file_handles.alloc(fd, rel.clone(), raw_flags, handle_hash, sid);
+            // The handle is allocated before the backing file is opened so that a
+            // rejected O_TRUNC open never gets to truncate it.
+            let fh = self.file_handles.alloc(-1, rel.clone(), Some(id), raw_flags, handle_hash, agent.clone());
             if let Some(ref actual) = pre_open_hash {
-                if let Err(e) = self.cas_table.check_and_acquire_write(&rel, fh, sid, &self.file_handles, actual) {
-                    warn!("CAS conflict on open: {}", e);
+                if let Err(e) = self.cas_table.check_and_acquire_write(id, &rel, fh, &agent, &self.file_handles, actual) {
+                    let base = if self.config.auto_merge {
+                        self.cas_table
+                            .get_reader_hash(&agent, id)
+                            .and_then(|h| self.objects.get(&h))
+                    } else {
+                        None
+                    };
+                    if self.config.defer_rejection || base.is_some() {
+                        match self.open_shadow(fh, &rel, raw_flags) {
+                            Ok((shadow_fd, shadow_path)) => {
+                                info!("CAS conflict on open ({}), deferring to flush: {}", self.names.label(&agent), e);
+                                let conflict = self.conflict_info(id, &rel, fh, &agent, actual, e);
+                                if let Some(mut h) = self.file_handles.get_mut(fh) {
+                                    h.real_fd = shadow_fd;
+                                    h.shadow = Some(ShadowFile {
+                                        path: shadow_path,
+                                        conflict,
+                                        dirty: raw_flags & libc::O_TRUNC != 0,
+                                        base,
+                                    });
+                                }
+                                // What it writes must not reach the page cache
+                                // other handles of the file read from
+                                reply.opened(FileHandle(fh), FopenFlags::FOPEN_DIRECT_IO);
+                                return;
+                            }
+                            Err(shadow_err) => {
+                                warn!("Failed to create shadow file for {}: {}", rel.display(), shadow_err);
+                            }
+                        }
+                    }
+                    warn!("CAS conflict on open ({}): {}", self.names.label(&agent), e);
+                    self.explain_conflict(id, &self.conflict_info(id, &rel, fh, &agent, actual, e));
                     self.file_handles.remove(fh);
-                    unsafe { libc::close(fd); }
                     reply.error(Errno::EIO);
                     return;
                 }
             } else {
                 // File didn't exist before open (new file) — ensure entry for write_owner
-                self.cas_table.ensure_entry(&rel);
+                self.cas_table.ensure_entry(id, &rel);
+            }
+            let fd = match self.root.openat(&rel, raw_flags, 0) {
+                Ok(fd) => fd,
+                Err(err) => {
+                    self.cas_table.release_write(id, fh);
+                    self.file_handles.remove(fh);
+                    reply.error(Errno::from(err));
+                    return;
+                }
+            };
+            if let Some(mut h) = self.file_handles.get_mut(fh) {
+                h.real_fd = fd;
             }
             if access_mode == libc::O_RDWR {
                 // O_RDWR also records in reader_hashes
                 if let Some(ref h) = pre_open_hash {
-                    self.cas_table.record_reader(&rel, h.clone(), sid);
+                    self.cas_table.record_reader(id, h.clone(), &agent);
                 }
             }
-            debug!("open: write-mode {} sid={}", rel.display(), sid);
+            self.cas_table.clear_absent(&agent, &rel);
+            debug!("open: write-mode {} agent={}", rel.display(), self.names.label(&agent));
             reply.opened(FileHandle(fh), FopenFlags::empty());
             return;
+        }
+
+        let fd = match self.root.openat(&rel, raw_flags, 0) {
+            Ok(fd) => fd,
+            Err(e) => {
+                reply.error(Errno::from(e));
+                return;
+            }
         };
 
-        let fh = self.file_handles.alloc(fd, rel, raw_flags, hash, sid);
+        // Read-only: compute hash (file wasn't modified by open), record in reader_hashes
+        let hash = self.snapshot(&rel).ok();
+        if let Some(ref h) = hash {
+            self.cas_table.record_reader(id, h.clone(), &agent);
+            debug!("open: tracked {} hash={} agent={}", rel.display(), cas::hash_hex(h), self.names.label(&agent));
+        }
+        self.cas_table.clear_absent(&agent, &rel);
+
+        let fh = self.file_handles.alloc(fd, rel, Some(id), raw_flags, hash, agent);
         reply.opened(FileHandle(fh), FopenFlags::empty());
     }
 
     fn read(
         &self,
-        _req: &Request,
+        req: &Request,
         ino: INodeNo,
         fh: FileHandle,
         offset: u64,
@@ -563,6 +1382,29 @@ impl Filesystem for DibsFs {
             return;
         }
 
+        // Virtual names file
+        if ino == DIBS_NAMES_INO {
+            let content = self.names.render();
+            let bytes = content.as_bytes();
+            let start = (offset as usize).min(bytes.len());
+            let end = std::cmp::min(start + size as usize, bytes.len());
+            reply.data(&bytes[start..end]);
+            return;
+        }
+
+        // Dynamic virtual files
+        if Self::is_dibs_ino(ino) {
+            match self.virtual_path(ino).and_then(|p| self.virtual_node(&p, &self.agent(req))) {
+                Some(VirtualNode::File(content)) => {
+                    let start = (offset as usize).min(content.len());
+                    let end = std::cmp::min(start + size as usize, content.len());
+                    reply.data(&content[start..end]);
+                }
+                _ => reply.error(Errno::ENOENT),
+            }
+            return;
+        }
+
         let handle = match self.file_handles.get(fh) {
             Some(h) => h,
             None => {
@@ -597,48 +1439,52 @@ impl Filesystem for DibsFs {
         let fh = u64::from(fh);
         debug!("write(ino={}, fh={}, offset={}, size={})", ino, fh, offset, data.len());
 
+        // Each write to .dibs/names is a batch of complete commands
+        if ino == DIBS_NAMES_INO {
+            match self.names.apply(&String::from_utf8_lossy(data)) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(e) => {
+                    warn!("Bad .dibs/names command: {}", e);
+                    reply.error(Errno::EINVAL);
+                }
+            }
+            return;
+        }
         if Self::is_dibs_ino(ino) {
             reply.error(Errno::EACCES);
             return;
         }
 
-        // Get the handle's path and SID for CAS check
-        let (real_fd, rel_path, sid) = match self.file_handles.get(fh) {
-            Some(h) => (h.real_fd, h.path.clone(), h.sid),
+        // Get the handle's path and agent for CAS check
+        let handle = self
+            .file_handles
+            .get(fh)
+            .and_then(|h| Some((h.real_fd, h.id?, h.path.clone(), h.agent.clone(), h.appending)));
+        let (real_fd, id, rel_path, agent, appending) = match handle {
+            Some(h) => h,
             None => {
                 reply.error(Errno::EBADF);
                 return;
             }
         };
 
-        // CAS check — if write ownership wasn't already acquired in open(),
-        // re-hash the backing file and compare against the reader hash.
-        // (Normally ownership is acquired at open time; this is a safety net.)
-        if !self.cas_table.has_active_writer(&rel_path) {
-            let full = self.backing_path(&rel_path);
-            let actual_hash = cas::hash_file(&full).unwrap_or_default();
-            if let Err(e) = self.cas_table.check_and_acquire_write(&rel_path, fh, sid, &self.file_handles, &actual_hash) {
-                warn!("CAS conflict on write: {}", e);
-
-                // Save conflict data if configured
-                if let Some(ref conflict_dir) = self.conflict_dir {
-                    let ts = chrono::Utc::now().format("%Y%m%d_%H%M%S_%3f");
-                    let fname = rel_path
-                        .file_name()
-                        .map(|n| n.to_string_lossy().to_string())
-                        .unwrap_or_else(|| "unknown".to_string());
-                    let conflict_path = conflict_dir.join(format!("{}_{}", ts, fname));
-                    let _ = std::fs::write(&conflict_path, data);
+        if appending {
+            match self.cas_table.append(id, &rel_path, &agent, |run| self.write_append(&rel_path, real_fd, data, run)) {
+                Ok(Ok(n)) => reply.written(n as u32),
+                Ok(Err(e)) => reply.error(Errno::from(e)),
+                Err(e) => {
+                    warn!("CAS conflict on append ({}): {}", self.names.label(&agent), e);
+                    let actual_hash = self.root.hash(&rel_path).unwrap_or_default();
+                    self.explain_conflict(id, &self.conflict_info(id, &rel_path, fh, &agent, &actual_hash, e));
+                    reply.error(Errno::EIO);
                 }
-
-                reply.error(Errno::EIO);
-                return;
             }
+            return;
         }
 
-        // Mark handle as having written
-        if let Some(mut h) = self.file_handles.get_mut(fh) {
-            h.has_written = true;
+        if !self.check_write(fh, id, &rel_path, &agent, "write", Some(data)) {
+            reply.error(Errno::EIO);
+            return;
         }
 
         let n = unsafe {
@@ -652,7 +1498,7 @@ impl Filesystem for DibsFs {
         }
     }
 
-    fn flush(&self, _req: &Request, ino: INodeNo, fh: FileHandle, _lock_owner: LockOwner, reply: ReplyEmpty) {
+    fn flush(&self, _req: &Request, ino: INodeNo, fh: FileHandle, lock_owner: LockOwner, reply: ReplyEmpty) {
         let ino = u64::from(ino);
         let fh = u64::from(fh);
         debug!("flush(ino={}, fh={})", ino, fh);
@@ -662,28 +1508,83 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (has_written, rel_path, sid) = match self.file_handles.get(fh) {
-            Some(h) => (h.has_written, h.path.clone(), h.sid),
+        let handle = self
+            .file_handles
+            .get(fh)
+            .and_then(|h| Some((h.has_written, h.id?, h.path.clone(), h.agent.clone(), h.shadow.clone(), h.appending)));
+        let (has_written, id, rel_path, agent, shadow, appending) = match handle {
+            Some(h) => h,
             None => {
                 reply.ok();
                 return;
             }
         };
 
-        if has_written {
-            // Re-hash the file after write and update the reader hash for this SID
-            let full = self.backing_path(&rel_path);
-            if let Ok(new_hash) = cas::hash_file(&full) {
-                self.cas_table.update_reader(sid, &rel_path, new_hash.clone());
+        // Closing any of its descriptors for a file drops a process's
+        // fcntl() locks there, which FUSE leaves to the flush
+        if let Some(fd) = self.locks.get(id, lock_owner.0) {
+            let mut fl = flock(0, i64::MAX as u64, libc::F_UNLCK);
+            let _ = lock(fd.as_raw_fd(), F_SETLK, &mut fl);
+            drop(fd);
+            let by_policy = self.policies.applies(PolicyKind::Locks, &rel_path);
+            self.cas_table.unlock(id, lock_owner.0, None, by_policy);
+            self.locks.close_idle(id, lock_owner.0);
+        }
+
+        if appending {
+            self.settle_appends(id, &rel_path);
+            reply.ok();
+            return;
+        }
+
+        // Stale writer: now that its content is complete, merge it into the
+        // backing file, or keep it as a conflict and report the rejection.
+        if let Some(shadow) = shadow {
+            if has_written || shadow.dirty {
+                let merged_base = self.settle_shadow(id, &rel_path, fh, &agent, &shadow);
+                self.invalidate_attrs(ino);
+                let merged = merged_base.is_some();
+                if let Some(mut h) = self.file_handles.get_mut(fh) {
+                    h.has_written = false;
+                    if let Some(ref mut s) = h.shadow {
+                        s.dirty = false;
+                        // Further writes on this handle merge against what it wrote.
+                        if merged {
+                            s.base = merged_base;
+                        }
+                    }
+                }
+                if merged {
+                    reply.ok();
+                } else {
+                    reply.error(Errno::EIO);
+                }
+            } else {
+                reply.ok();
+            }
+            return;
+        }
+
+        if has_written && self.cas_table.is_coordinated(id) {
+            // Nothing to hash for: its writers don't go by receipts
+            self.cas_table.record_write(&agent, id, &rel_path);
+            if let Some(mut h) = self.file_handles.get_mut(fh) {
+                h.has_written = false;
+            }
+        } else if has_written {
+            // Re-hash the file after write and update the reader hash for this agent
+            self.cas_table.record_write(&agent, id, &rel_path);
+            if let Ok(new_hash) = self.snapshot(&rel_path) {
+                self.cas_table.update_reader(&agent, id, new_hash.clone());
                 // Update the handle's hash for future checks
                 if let Some(mut h) = self.file_handles.get_mut(fh) {
                     h.hash_at_open = Some(new_hash);
                     h.has_written = false;
                 }
-                debug!("flush: updated hash for {} sid={}", rel_path.display(), sid);
+                debug!("flush: updated hash for {} agent={}", rel_path.display(), self.names.label(&agent));
             }
             // Release write ownership
-            self.cas_table.release_write(&rel_path, fh);
+            self.cas_table.release_write(id, fh);
         }
 
         reply.ok();
@@ -692,10 +1593,10 @@ impl Filesystem for DibsFs {
     fn release(
         &self,
         _req: &Request,
-        _ino: INodeNo,
+        ino: INodeNo,
         fh: FileHandle,
         _flags: OpenFlags,
-        _lock_owner: Option<LockOwner>,
+        lock_owner: Option<LockOwner>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
@@ -703,7 +1604,36 @@ impl Filesystem for DibsFs {
         debug!("release(fh={})", fh);
 
         if let Some(handle) = self.file_handles.remove(fh) {
-            self.cas_table.release_write(&handle.path, fh);
+            if let Some(id) = handle.id {
+                self.cas_table.release_write(id, fh);
+                if handle.appending {
+                    self.settle_appends(id, &handle.path);
+                }
+                let by_policy = self.policies.applies(PolicyKind::Locks, &handle.path);
+                // flock() locks go with the open file being released
+                if let Some(owner) = lock_owner {
+                    self.cas_table.unlock(id, owner.0, None, by_policy);
+                    self.locks.close_idle(id, owner.0);
+                }
+                // With the file closed everywhere, nobody is left to unlock
+                // what is still locked
+                if self.locks.has_any(id) && !self.file_handles.any_open(id) {
+                    for owner in self.locks.close_all(id) {
+                        self.cas_table.unlock(id, owner, None, by_policy);
+                    }
+                }
+            }
+
+            if let (Some(shadow), Some(id)) = (&handle.shadow, handle.id) {
+                // Released without a flush after the last write — still settle the data.
+                if handle.has_written || shadow.dirty {
+                    self.settle_shadow(id, &handle.path, fh, &handle.agent, shadow);
+                    self.invalidate_attrs(u64::from(ino));
+                }
+                if let Some(ref shadow_dir) = self.shadow_dir {
+                    let _ = shadow_dir.unlink(&shadow.path);
+                }
+            }
 
             if handle.real_fd >= 0 {
                 unsafe {
@@ -714,16 +1644,186 @@ impl Filesystem for DibsFs {
         reply.ok();
     }
 
-    fn opendir(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+    fn fsync(&self, _req: &Request, ino: INodeNo, fh: FileHandle, datasync: bool, reply: ReplyEmpty) {
+        let fh = u64::from(fh);
+        debug!("fsync(ino={}, fh={}, datasync={})", u64::from(ino), fh, datasync);
+
+        let Some(real_fd) = self.file_handles.get(fh).map(|h| h.real_fd) else {
+            reply.error(Errno::EBADF);
+            return;
+        };
+        // Virtual files have nothing on disk
+        if real_fd < 0 {
+            reply.ok();
+            return;
+        }
+        match fsync(real_fd, datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn getlk(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        lock_owner: LockOwner,
+        start: u64,
+        end: u64,
+        typ: i32,
+        _pid: u32,
+        reply: ReplyLock,
+    ) {
+        let fh = u64::from(fh);
+        debug!("getlk(ino={}, fh={}, start={}, end={}, typ={})", u64::from(ino), fh, start, end, typ);
+
+        let Some((id, rel)) = self.lock_target(fh) else {
+            reply.error(Errno::ENOLCK);
+            return;
+        };
+        // Through the owner's own descriptor, so its own locks don't count
+        let fd = match self.locks.get(id, lock_owner.0) {
+            Some(fd) => fd,
+            None => match self.open_lock_fd(&rel) {
+                Ok(fd) => Arc::new(fd),
+                Err(e) => {
+                    reply.error(Errno::from(e));
+                    return;
+                }
+            },
+        };
+        let mut fl = flock(start, end, typ);
+        if let Err(e) = lock(fd.as_raw_fd(), F_GETLK, &mut fl) {
+            reply.error(Errno::from(e));
+            return;
+        }
+        if fl.l_type as i32 == libc::F_UNLCK {
+            reply.locked(start, end, libc::F_UNLCK, 0);
+            return;
+        }
+        // Open file description locks have no owning process
+        let (start, end) = flock_range(&fl);
+        reply.locked(start, end, fl.l_type as i32, fl.l_pid.max(0) as u32);
+    }
+
+    fn setlk(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        lock_owner: LockOwner,
+        start: u64,
+        end: u64,
+        typ: i32,
+        _pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let fh = u64::from(fh);
+        let owner = lock_owner.0;
+        debug!(
+            "setlk(ino={}, fh={}, owner={:x}, start={}, end={}, typ={}, sleep={})",
+            u64::from(ino),
+            fh,
+            owner,
+            start,
+            end,
+            typ,
+            sleep
+        );
+
+        let Some((id, rel)) = self.lock_target(fh) else {
+            reply.error(Errno::ENOLCK);
+            return;
+        };
+
+        // A file's writers coordinate through its locks while someone holds
+        // one, from the first write lock on, so the CAS table follows every
+        // lock that was granted. An owner's descriptor is closed once it
+        // holds none.
+        let mut fl = flock(start, end, typ);
+        if typ == libc::F_UNLCK {
+            if let Some(fd) = self.locks.get(id, owner) {
+                if let Err(e) = lock(fd.as_raw_fd(), F_SETLK, &mut fl) {
+                    reply.error(Errno::from(e));
+                    return;
+                }
+                drop(fd);
+                let by_policy = self.policies.applies(PolicyKind::Locks, &rel);
+                if !self.cas_table.unlock(id, owner, Some((start, end)), by_policy) {
+                    self.locks.close_idle(id, owner);
+                }
+            }
+            reply.ok();
+            return;
+        }
+
+        let fd = match self.locks.get_or_open(id, owner, || self.open_lock_fd(&rel)) {
+            Ok(fd) => fd,
+            Err(e) => {
+                reply.error(Errno::from(e));
+                return;
+            }
+        };
+        let write = typ == libc::F_WRLCK;
+        match lock(fd.as_raw_fd(), F_SETLK, &mut fl) {
+            Ok(()) => {
+                self.cas_table.lock(id, &rel, fh, owner, (start, end), write);
+                reply.ok();
+                return;
+            }
+            Err(e) if sleep && matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)) => {}
+            Err(e) => {
+                drop(fd);
+                if !self.cas_table.holds_lock(id, owner) {
+                    self.locks.close_idle(id, owner);
+                }
+                reply.error(Errno::from(e));
+                return;
+            }
+        }
+
+        // Waiting for the lock here would hold up every other request. The
+        // request stands until its caller is interrupted, the handle is
+        // released or the owner has given up its descriptor.
+        debug!("setlk: {} waits for a lock on {}", self.names.label(&self.agent(req)), rel.display());
+        let pid = req.pid();
+        let lock_table = Arc::clone(&self.locks);
+        let file_handles = Arc::clone(&self.file_handles);
+        let cas_table = Arc::clone(&self.cas_table);
+        self.locks.wait(Waiter {
+            fd,
+            fl,
+            wanted: Box::new(move |fd| {
+                !caller_interrupted(pid) && file_handles.get(fh).is_some() && lock_table.is_current(id, owner, fd)
+            }),
+            granted: Box::new(move || cas_table.lock(id, &rel, fh, owner, (start, end), write)),
+            reply,
+        });
+    }
+
+    fn opendir(&self, req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
         let ino = u64::from(ino);
         debug!("opendir(ino={})", ino);
 
         // Virtual .dibs/ directory
-        if ino == DIBS_DIR_INO || ino == DIBS_CONFLICTS_DIR_INO {
+        if ino == DIBS_DIR_INO {
             let fh = self.dir_handles.alloc(-1, PathBuf::from(".dibs"));
             reply.opened(FileHandle(fh), FopenFlags::empty());
             return;
         }
+        if Self::is_dibs_ino(ino) {
+            match self.virtual_path(ino) {
+                Some(vpath) if matches!(self.virtual_node(&vpath, &self.agent(req)), Some(VirtualNode::Dir(_))) => {
+                    let fh = self.dir_handles.alloc(-1, vpath);
+                    reply.opened(FileHandle(fh), FopenFlags::empty());
+                }
+                Some(_) => reply.error(Errno::ENOTDIR),
+                None => reply.error(Errno::ENOENT),
+            }
+            return;
+        }
 
         let rel = if ino == 1 {
             PathBuf::new()
@@ -737,34 +1837,21 @@ impl Filesystem for DibsFs {
             }
         };
 
-        let full = self.backing_path(&rel);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        let real_fd = match self.root.openat(&rel, libc::O_RDONLY | libc::O_DIRECTORY, 0) {
+            Ok(fd) => fd,
+            Err(e) => {
+                reply.error(Errno::from(e));
                 return;
             }
         };
 
-        let dp = unsafe { libc::opendir(c_path.as_ptr()) };
-        if dp.is_null() {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
-            return;
-        }
-
-        let fd = unsafe { libc::dirfd(dp) };
-        let real_fd = unsafe { libc::dup(fd) };
-        unsafe {
-            libc::closedir(dp);
-        }
-
         let fh = self.dir_handles.alloc(real_fd, rel);
         reply.opened(FileHandle(fh), FopenFlags::empty());
     }
 
     fn readdir(
         &self,
-        _req: &Request,
+        req: &Request,
         ino: INodeNo,
         _fh: FileHandle,
         offset: u64,
@@ -781,6 +1868,9 @@ impl Filesystem for DibsFs {
                 (DIBS_STATUS_INO, FileType::RegularFile, DIBS_STATUS_NAME),
                 (DIBS_LOCKS_INO, FileType::RegularFile, DIBS_LOCKS_NAME),
                 (DIBS_CONFLICTS_DIR_INO, FileType::Directory, DIBS_CONFLICTS_NAME),
+                (DIBS_OBJECTS_DIR_INO, FileType::Directory, DIBS_OBJECTS_NAME),
+                (DIBS_SELF_DIR_INO, FileType::Directory, DIBS_SELF_NAME),
+                (DIBS_NAMES_INO, FileType::RegularFile, DIBS_NAMES_NAME),
             ];
             for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
                 if reply.add(INodeNo(*ino), (i + 1) as u64, *kind, name) {
@@ -791,11 +1881,35 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        if ino == DIBS_CONFLICTS_DIR_INO {
-            let entries = vec![
-                (DIBS_CONFLICTS_DIR_INO, FileType::Directory, "."),
-                (DIBS_DIR_INO, FileType::Directory, ".."),
+        // Dynamic .dibs/ directories (saved conflicts, objects, self)
+        if Self::is_dibs_ino(ino) {
+            let vpath = match self.virtual_path(ino) {
+                Some(p) => p,
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            let children = match self.virtual_node(&vpath, &self.agent(req)) {
+                Some(VirtualNode::Dir(children)) => children,
+                Some(VirtualNode::File(_)) => {
+                    reply.error(Errno::ENOTDIR);
+                    return;
+                }
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            let parent_ino = vpath.parent().map(|p| self.virtual_ino(p)).unwrap_or(DIBS_DIR_INO);
+            let mut entries = vec![
+                (ino, FileType::Directory, ".".to_string()),
+                (parent_ino, FileType::Directory, "..".to_string()),
             ];
+            for (name, kind) in children {
+                let child_ino = self.virtual_ino(&vpath.join(&name));
+                entries.push((child_ino, kind, name));
+            }
             for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
                 if reply.add(INodeNo(*ino), (i + 1) as u64, *kind, name) {
                     break;
@@ -818,8 +1932,7 @@ impl Filesystem for DibsFs {
             }
         };
 
-        let full = self.backing_path(&rel);
-        let entries = match std::fs::read_dir(&full) {
+        let entries = match self.root.read_dir(&rel) {
             Ok(rd) => rd,
             Err(e) => {
                 reply.error(Errno::from(e));
@@ -848,19 +1961,17 @@ impl Filesystem for DibsFs {
         }
 
         for entry in entries {
-            let entry = match entry {
-                Ok(e) => e,
-                Err(_) => continue,
-            };
-            let name = entry.file_name().to_string_lossy().to_string();
+            let name = entry.to_string_lossy().to_string();
             // Skip .dibs-conflicts internal directory
-            if name == ".dibs-conflicts" {
+            if name == CONFLICT_STORE_NAME {
                 continue;
             }
 
-            let child_rel = rel.join(&name);
-            let child_full = self.backing_path(&child_rel);
-            if let Ok(st) = lstat(&child_full) {
+            let child_rel = rel.join(&entry);
+            if Self::is_shadow_path(&child_rel) {
+                continue;
+            }
+            if let Ok(st) = self.root.lstat(&child_rel) {
                 let attr = stat_to_file_attr(&st);
                 self.inodes.insert(u64::from(attr.ino), child_rel);
                 all_entries.push((u64::from(attr.ino), attr.kind, name));
@@ -888,6 +1999,24 @@ impl Filesystem for DibsFs {
         reply.ok();
     }
 
+    fn fsyncdir(&self, _req: &Request, ino: INodeNo, fh: FileHandle, datasync: bool, reply: ReplyEmpty) {
+        let fh = u64::from(fh);
+        debug!("fsyncdir(ino={}, fh={}, datasync={})", u64::from(ino), fh, datasync);
+
+        let Some(real_fd) = self.dir_handles.get(fh).map(|h| h.real_fd) else {
+            reply.error(Errno::EBADF);
+            return;
+        };
+        if real_fd < 0 {
+            reply.ok();
+            return;
+        }
+        match fsync(real_fd, datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
     fn create(
         &self,
         req: &Request,
@@ -906,21 +2035,34 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (rel, full) = self.resolve_path(parent, name);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        // The kernel only creates after the agent's lookup found nothing, so
+        // a file there now was created by someone else in the meantime.
+        // Lockers expect that and sort it out between themselves.
+        let rel = self.resolve_path(parent, name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        let agent = self.agent(req);
+        let locks = self.policies.applies(PolicyKind::Locks, &rel);
+        let fd = match self.root.openat(&rel, flags | libc::O_CREAT | libc::O_EXCL, mode) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) && flags & libc::O_EXCL == 0 => {
+                if !locks && self.appeared(&agent, &rel, "create") {
+                    reply.error(Errno::EIO);
+                    return;
+                }
+                self.root.openat(&rel, flags | libc::O_CREAT, mode)
+            }
+            result => result,
+        };
+        let fd = match fd {
+            Ok(fd) => fd,
+            Err(e) => {
+                reply.error(Errno::from(e));
                 return;
             }
         };
 
-        let fd = unsafe { libc::open(c_path.as_ptr(), flags | libc::O_CREAT, mode) };
-        if fd < 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
-            return;
-        }
-
         let st = match fstat(fd) {
             Ok(st) => st,
             Err(e) => {
@@ -931,22 +2073,29 @@ impl Filesystem for DibsFs {
         };
 
         let attr = stat_to_file_attr(&st);
+        let id = FileId::of(&st);
         self.inodes.insert(u64::from(attr.ino), rel.clone());
 
-        let sid = get_sid(req.pid());
+        if self.coordinated(id, &rel) {
+            self.cas_table.clear_absent(&agent, &rel);
+            let fh = self.file_handles.alloc(fd, rel, Some(id), flags, None, agent);
+            reply.created(&TTL, &attr, Generation(0), FileHandle(fh), FopenFlags::FOPEN_DIRECT_IO);
+            return;
+        }
 
         // Hash the newly created file (empty or truncated)
-        let hash = cas::hash_file(&full).unwrap_or_default();
-        self.cas_table.record_reader(&rel, hash.clone(), sid);
-        self.cas_table.ensure_entry(&rel);
-        let fh = self.file_handles.alloc(fd, rel, flags, Some(hash), sid);
+        let hash = self.snapshot(&rel).unwrap_or_default();
+        self.cas_table.record_reader(id, hash.clone(), &agent);
+        self.cas_table.ensure_entry(id, &rel);
+        self.cas_table.clear_absent(&agent, &rel);
+        let fh = self.file_handles.alloc(fd, rel, Some(id), flags, Some(hash), agent);
 
         reply.created(&TTL, &attr, Generation(0), FileHandle(fh), FopenFlags::empty());
     }
 
     fn mkdir(
         &self,
-        _req: &Request,
+        req: &Request,
         parent: INodeNo,
         name: &OsStr,
         mode: u32,
@@ -961,22 +2110,65 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (rel, full) = self.resolve_path(parent, name);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
-                return;
+        // The kernel refuses names it already knows to exist, so this only
+        // catches a directory made since its lookup. Nothing gets clobbered,
+        // but the agent should know why.
+        let rel = self.resolve_path(parent, name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        let agent = self.agent(req);
+        if let Err(e) = self.root.mkdir(&rel, mode as libc::mode_t) {
+            if e.raw_os_error() == Some(libc::EEXIST) {
+                self.appeared(&agent, &rel, "mkdir");
             }
-        };
+            reply.error(Errno::from(e));
+            return;
+        }
+        self.cas_table.clear_absent(&agent, &rel);
 
-        let rc = unsafe { libc::mkdir(c_path.as_ptr(), mode as libc::mode_t) };
-        if rc != 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
+        match self.lookup_and_register(&rel) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn mknod(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let parent = u64::from(parent);
+        debug!("mknod(parent={}, name={:?}, mode={:#o}, rdev={})", parent, name, mode, rdev);
+
+        if Self::is_dibs_ino(parent) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+
+        // As with mkdir, only a node made since the lookup can be in the way
+        let rel = self.resolve_path(parent, name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        let agent = self.agent(req);
+        if let Err(e) = self.root.mknod(&rel, mode as libc::mode_t, rdev as libc::dev_t) {
+            if e.raw_os_error() == Some(libc::EEXIST) {
+                self.appeared(&agent, &rel, "mknod");
+            }
+            reply.error(Errno::from(e));
             return;
         }
+        self.cas_table.clear_absent(&agent, &rel);
 
-        match self.lookup_and_register(&rel, &full) {
+        match self.lookup_and_register(&rel) {
             Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
             Err(e) => reply.error(Errno::from(e)),
         }
@@ -991,39 +2183,39 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (rel, full) = self.resolve_path(parent, name);
-
-        // CAS check: if this session has a reader hash for the file,
-        // verify the file hasn't changed since they last read it.
-        let sid = get_sid(req.pid());
-        if let Some(reader_hash) = self.cas_table.get_reader_hash(sid, &rel) {
-            if let Ok(actual_hash) = cas::hash_file(&full) {
-                if reader_hash != actual_hash {
-                    warn!(
-                        "CAS conflict on unlink {}: file changed since last read",
-                        rel.display()
-                    );
-                    reply.error(Errno::EIO);
-                    return;
-                }
-            }
+        let rel = self.resolve_path(parent, name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::EACCES);
+            return;
         }
-
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        let st = match self.root.lstat(&rel) {
+            Ok(st) => st,
+            Err(e) => {
+                reply.error(Errno::from(e));
                 return;
             }
         };
+        let id = FileId::of(&st);
 
-        let rc = unsafe { libc::unlink(c_path.as_ptr()) };
-        if rc != 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
+        // CAS check: if this agent has a reader hash for the file,
+        // verify the file hasn't changed since they last read it.
+        let agent = self.agent(req);
+        if !self.receipt_current(&agent, id, &rel, "unlink", "unlink of a file that changed since last read")
+            || !self.strict_allows(&agent, &rel, &st, "unlink")
+        {
+            reply.error(Errno::EIO);
+            return;
+        }
+
+        if let Err(e) = self.root.unlink(&rel) {
+            reply.error(Errno::from(e));
             return;
         }
 
-        self.cas_table.remove(&rel);
+        // Other links keep the file, and its CAS state, alive
+        if st.st_nlink <= 1 {
+            self.cas_table.remove(id);
+        }
         self.inodes.remove_by_path(&rel);
         reply.ok();
     }
@@ -1037,22 +2229,19 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (rel, full) = self.resolve_path(parent, name);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
-                return;
-            }
-        };
-
-        let rc = unsafe { libc::rmdir(c_path.as_ptr()) };
-        if rc != 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
+        let rel = self.resolve_path(parent, name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        if let Err(e) = self.root.rmdir(&rel) {
+            reply.error(Errno::from(e));
             return;
         }
 
-        self.inodes.remove_by_path(&rel);
+        // Only an empty directory can go, but paths below it may linger
+        // from files removed behind the mount's back
+        self.inodes.remove_tree(&rel);
         reply.ok();
     }
 
@@ -1063,14 +2252,14 @@ impl Filesystem for DibsFs {
         name: &OsStr,
         newparent: INodeNo,
         newname: &OsStr,
-        _flags: RenameFlags,
+        flags: RenameFlags,
         reply: ReplyEmpty,
     ) {
         let parent = u64::from(parent);
         let newparent = u64::from(newparent);
         debug!(
-            "rename(parent={}, name={:?}, newparent={}, newname={:?})",
-            parent, name, newparent, newname
+            "rename(parent={}, name={:?}, newparent={}, newname={:?}, flags={})",
+            parent, name, newparent, newname, flags
         );
 
         if Self::is_dibs_ino(parent) || Self::is_dibs_ino(newparent) {
@@ -1078,67 +2267,90 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (old_rel, old_full) = self.resolve_path(parent, name);
-        let (new_rel, new_full) = self.resolve_path(newparent, newname);
-
-        // CAS check: if this session has reader hashes, verify files haven't changed
-        let sid = get_sid(req.pid());
-        if let Some(reader_hash) = self.cas_table.get_reader_hash(sid, &old_rel) {
-            if let Ok(actual_hash) = cas::hash_file(&old_full) {
-                if reader_hash != actual_hash {
-                    warn!(
-                        "CAS conflict on rename source {}: file changed since last read",
-                        old_rel.display()
-                    );
-                    reply.error(Errno::EIO);
-                    return;
-                }
-            }
+        let flags = flags.bits();
+        let exchange = flags & backing::RENAME_EXCHANGE != 0;
+        let old_rel = self.resolve_path(parent, name);
+        let new_rel = self.resolve_path(newparent, newname);
+        if Self::is_shadow_path(&old_rel) || Self::is_shadow_path(&new_rel) {
+            reply.error(Errno::EACCES);
+            return;
         }
-        if new_full.exists() {
-            if let Some(reader_hash) = self.cas_table.get_reader_hash(sid, &new_rel) {
-                if let Ok(actual_hash) = cas::hash_file(&new_full) {
-                    if reader_hash != actual_hash {
-                        warn!(
-                            "CAS conflict on rename dest {}: file changed since last read",
-                            new_rel.display()
-                        );
-                        reply.error(Errno::EIO);
-                        return;
-                    }
-                }
+        let id = match self.root.lstat(&old_rel) {
+            Ok(st) => FileId::of(&st),
+            Err(e) => {
+                reply.error(Errno::from(e));
+                return;
             }
-        }
+        };
+        // The file at the new path: swapped with the source on an exchange,
+        // replaced by it otherwise. With RENAME_NOREPLACE there is none, or
+        // the rename fails.
+        let target = if flags & backing::RENAME_NOREPLACE != 0 {
+            None
+        } else {
+            self.root.lstat(&new_rel).ok().filter(|st| FileId::of(st) != id)
+        };
+        let target_id = target.as_ref().map(FileId::of);
 
-        let old_c = match path_to_cstring(&old_full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        // CAS check: if this agent has reader hashes, verify files haven't changed
+        let agent = self.agent(req);
+        if !self.receipt_current(&agent, id, &old_rel, "rename source", "rename of a file that changed since last read") {
+            reply.error(Errno::EIO);
+            return;
+        }
+        if target.is_some() && self.appeared(&agent, &new_rel, "rename") {
+            reply.error(Errno::EIO);
+            return;
+        }
+        // An exchange keeps both files
+        if let Some(st) = target.as_ref().filter(|_| !exchange) {
+            if !self.strict_allows(&agent, &new_rel, st, "rename over") {
+                reply.error(Errno::EIO);
                 return;
             }
-        };
-        let new_c = match path_to_cstring(&new_full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        }
+        if let Some(target_id) = target_id {
+            let (op, reason) = if exchange {
+                ("rename exchange", "exchange with a file that changed since last read")
+            } else {
+                ("rename dest", "rename over a file that changed since last read")
+            };
+            if !self.receipt_current(&agent, target_id, &new_rel, op, reason) {
+                reply.error(Errno::EIO);
                 return;
             }
-        };
+        }
 
-        let rc = unsafe { libc::rename(old_c.as_ptr(), new_c.as_ptr()) };
-        if rc != 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
+        // A failed rename, such as RENAME_NOREPLACE finding the new path
+        // taken, leaves the tables as they are
+        if let Err(e) = self.root.renameat(&old_rel, &new_rel, flags) {
+            reply.error(Errno::from(e));
             return;
         }
 
-        self.inodes.rename(&old_rel, &new_rel);
-        self.cas_table.rename(&old_rel, &new_rel);
+        self.cas_table.clear_absent(&agent, &new_rel);
+
+        // A directory takes everything below it along
+        if exchange {
+            self.inodes.exchange(&old_rel, &new_rel);
+            self.file_handles.exchange(&old_rel, &new_rel);
+            if let Some(target_id) = target_id {
+                self.cas_table.exchange(id, &old_rel, target_id, &new_rel);
+            }
+        } else {
+            self.inodes.rename(&old_rel, &new_rel);
+            self.file_handles.rename(&old_rel, &new_rel);
+            self.cas_table.rename(id, &old_rel, &new_rel, target_id);
+            if let Some(st) = target.filter(|st| st.st_nlink <= 1) {
+                self.cas_table.remove(FileId::of(&st));
+            }
+        }
         reply.ok();
     }
 
     fn symlink(
         &self,
-        _req: &Request,
+        req: &Request,
         parent: INodeNo,
         link_name: &OsStr,
         target: &Path,
@@ -1152,29 +2364,27 @@ impl Filesystem for DibsFs {
             return;
         }
 
-        let (rel, full) = self.resolve_path(parent, link_name);
-        let c_target = match path_to_cstring(target) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
-                return;
-            }
-        };
-        let c_link = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
-                return;
+        let rel = self.resolve_path(parent, link_name);
+        if Self::is_shadow_path(&rel) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        if !self.symlink_allowed(&rel, target) {
+            warn!("Refused symlink {} -> {}: it leads out of the mount", rel.display(), target.display());
+            reply.error(Errno::EACCES);
+            return;
+        }
+        let agent = self.agent(req);
+        if let Err(e) = self.root.symlink(target, &rel) {
+            if e.raw_os_error() == Some(libc::EEXIST) {
+                self.appeared(&agent, &rel, "symlink");
             }
-        };
-
-        let rc = unsafe { libc::symlink(c_target.as_ptr(), c_link.as_ptr()) };
-        if rc != 0 {
-            reply.error(Errno::from(std::io::Error::last_os_error()));
+            reply.error(Errno::from(e));
             return;
         }
+        self.cas_table.clear_absent(&agent, &rel);
 
-        match self.lookup_and_register(&rel, &full) {
+        match self.lookup_and_register(&rel) {
             Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
             Err(e) => reply.error(Errno::from(e)),
         }
@@ -1191,9 +2401,11 @@ impl Filesystem for DibsFs {
                 return;
             }
         };
-        let full = self.backing_path(&rel);
-
-        match std::fs::read_link(&full) {
+        match self.root.read_link(&rel) {
+            Ok(target) if !self.symlink_allowed(&rel, &target) => {
+                warn!("Refused to follow {} -> {}: it leads out of the mount", rel.display(), target.display());
+                reply.error(Errno::EACCES);
+            }
             Ok(target) => reply.data(target.as_os_str().as_bytes()),
             Err(e) => reply.error(Errno::from(e)),
         }
@@ -1201,41 +2413,168 @@ impl Filesystem for DibsFs {
 
     fn link(
         &self,
-        _req: &Request,
-        _ino: INodeNo,
-        _newparent: INodeNo,
-        _newname: &OsStr,
+        req: &Request,
+        ino: INodeNo,
+        newparent: INodeNo,
+        newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        // Hard links not supported — they complicate CAS tracking
-        reply.error(Errno::ENOTSUP);
-    }
+        let ino = u64::from(ino);
+        let newparent = u64::from(newparent);
+        debug!("link(ino={}, newparent={}, newname={:?})", ino, newparent, newname);
 
-    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
-        let c_path = match path_to_cstring(&self.backing) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        if Self::is_dibs_ino(ino) || Self::is_dibs_ino(newparent) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+
+        let rel = match self.inodes.get_path(ino) {
+            Some(p) => p,
+            None => {
+                reply.error(Errno::ENOENT);
                 return;
             }
         };
+        let new_rel = self.resolve_path(newparent, newname);
+        if Self::is_shadow_path(&new_rel) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        let agent = self.agent(req);
+        if let Err(e) = self.root.link(&rel, &new_rel) {
+            if e.raw_os_error() == Some(libc::EEXIST) {
+                self.appeared(&agent, &new_rel, "link");
+            }
+            reply.error(Errno::from(e));
+            return;
+        }
+        self.cas_table.clear_absent(&agent, &new_rel);
 
-        unsafe {
-            let mut st: libc::statfs = std::mem::zeroed();
-            if libc::statfs(c_path.as_ptr(), &mut st) == 0 {
-                reply.statfs(
-                    st.f_blocks,
-                    st.f_bfree,
-                    st.f_bavail,
-                    st.f_files,
-                    st.f_ffree,
-                    st.f_bsize as u32,
-                    255,
-                    st.f_bsize as u32,
-                );
-            } else {
-                reply.error(Errno::from(std::io::Error::last_os_error()));
+        // CAS state is keyed by the file, so the new name shares it as is
+        match self.lookup_and_register(&new_rel) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        match self.root.statfs() {
+            Ok(st) => reply.statfs(
+                st.f_blocks,
+                st.f_bfree,
+                st.f_bavail,
+                st.f_files,
+                st.f_ffree,
+                st.f_bsize as u32,
+                255,
+                st.f_bsize as u32,
+            ),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn setxattr(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let ino = u64::from(ino);
+        debug!("setxattr(ino={}, name={:?}, size={}, flags={:#x})", ino, name, value.len(), flags);
+
+        if Self::is_dibs_ino(ino) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        if name.as_bytes().starts_with(DIBS_XATTR_PREFIX.as_bytes()) {
+            reply.error(Errno::EPERM);
+            return;
+        }
+        let Some(rel) = self.inodes.get_path(ino) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        match self.root.setxattr(&rel, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn getxattr(&self, req: &Request, ino: INodeNo, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let ino = u64::from(ino);
+        debug!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+
+        if Self::is_dibs_ino(ino) {
+            reply.error(Errno::NO_XATTR);
+            return;
+        }
+        let Some(rel) = self.inodes.get_path(ino) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        let value = match name.to_str().and_then(|n| n.strip_prefix(DIBS_XATTR_PREFIX)) {
+            Some(query) => self.dibs_xattr(&rel, query, &self.agent(req)).map(|v| v.map(String::into_bytes)),
+            None => self.root.getxattr(&rel, name).map(Some),
+        };
+        match value {
+            Ok(Some(value)) => reply_xattr(reply, size, &value),
+            Ok(None) => reply.error(Errno::NO_XATTR),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn listxattr(&self, _req: &Request, ino: INodeNo, size: u32, reply: ReplyXattr) {
+        let ino = u64::from(ino);
+        debug!("listxattr(ino={}, size={})", ino, size);
+
+        if Self::is_dibs_ino(ino) {
+            reply_xattr(reply, size, &[]);
+            return;
+        }
+        let Some(rel) = self.inodes.get_path(ino) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        // The user.dibs. queries aren't listed, so that copying a file with
+        // its attributes doesn't try to set them on the copy. Real attributes
+        // by those names are hidden behind them.
+        match self.root.listxattr(&rel) {
+            Ok(names) => {
+                let names: Vec<u8> = names
+                    .split_inclusive(|&b| b == 0)
+                    .filter(|name| !name.starts_with(DIBS_XATTR_PREFIX.as_bytes()))
+                    .flatten()
+                    .copied()
+                    .collect();
+                reply_xattr(reply, size, &names);
             }
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn removexattr(&self, _req: &Request, ino: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        let ino = u64::from(ino);
+        debug!("removexattr(ino={}, name={:?})", ino, name);
+
+        if Self::is_dibs_ino(ino) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        if name.as_bytes().starts_with(DIBS_XATTR_PREFIX.as_bytes()) {
+            reply.error(Errno::EPERM);
+            return;
+        }
+        let Some(rel) = self.inodes.get_path(ino) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        match self.root.removexattr(&rel, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from(e)),
         }
     }
 
@@ -1260,20 +2599,112 @@ impl Filesystem for DibsFs {
                 return;
             }
         };
-        let full = self.backing_path(&rel);
-        let c_path = match path_to_cstring(&full) {
-            Ok(p) => p,
-            Err(_) => {
-                reply.error(Errno::EINVAL);
+        match self.root.access(&rel, mask.bits()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
+
+    fn fallocate(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        length: u64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let ino = u64::from(ino);
+        let fh = u64::from(fh);
+        debug!("fallocate(ino={}, fh={}, offset={}, length={}, mode={:#x})", ino, fh, offset, length, mode);
+
+        if Self::is_dibs_ino(ino) {
+            reply.error(Errno::EACCES);
+            return;
+        }
+        let handle = self.file_handles.get(fh).map(|h| (h.real_fd, h.id, h.path.clone(), h.agent.clone()));
+        let Some((real_fd, id, rel_path, agent)) = handle else {
+            reply.error(Errno::EBADF);
+            return;
+        };
+
+        // Punching, collapsing or zeroing a range, or growing the file,
+        // changes what it reads as just like a write does
+        if let Some(id) = id.filter(|_| fallocate_writes(mode)) {
+            if !self.check_write(fh, id, &rel_path, &agent, "fallocate", None) {
+                reply.error(Errno::EIO);
                 return;
             }
-        };
+        }
+        match fallocate(real_fd, mode, offset, length) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from(e)),
+        }
+    }
 
-        let rc = unsafe { libc::access(c_path.as_ptr(), mask.bits()) };
-        if rc == 0 {
-            reply.ok();
-        } else {
+    fn lseek(&self, _req: &Request, ino: INodeNo, fh: FileHandle, offset: i64, whence: i32, reply: ReplyLseek) {
+        let fh = u64::from(fh);
+        debug!("lseek(ino={}, fh={}, offset={}, whence={})", u64::from(ino), fh, offset, whence);
+
+        // Only SEEK_DATA and SEEK_HOLE get this far. Virtual files have no
+        // backing descriptor to ask, so callers read them through.
+        let Some(real_fd) = self.file_handles.get(fh).map(|h| h.real_fd) else {
+            reply.error(Errno::EBADF);
+            return;
+        };
+        if real_fd < 0 {
+            reply.error(Errno::EINVAL);
+            return;
+        }
+        let n = unsafe { libc::lseek(real_fd, offset as libc::off_t, whence) };
+        if n < 0 {
             reply.error(Errno::from(std::io::Error::last_os_error()));
+        } else {
+            reply.offset(n as i64);
+        }
+    }
+
+    fn copy_file_range(
+        &self,
+        _req: &Request,
+        ino_in: INodeNo,
+        fh_in: FileHandle,
+        offset_in: u64,
+        ino_out: INodeNo,
+        fh_out: FileHandle,
+        offset_out: u64,
+        len: u64,
+        _flags: CopyFileRangeFlags,
+        reply: ReplyWrite,
+    ) {
+        let (fh_in, fh_out) = (u64::from(fh_in), u64::from(fh_out));
+        debug!(
+            "copy_file_range(ino_in={}, fh_in={}, offset_in={}, ino_out={}, fh_out={}, offset_out={}, len={})",
+            u64::from(ino_in), fh_in, offset_in, u64::from(ino_out), fh_out, offset_out, len
+        );
+
+        // The kernel copies virtual files through reads and writes instead
+        if Self::is_dibs_ino(u64::from(ino_in)) || Self::is_dibs_ino(u64::from(ino_out)) {
+            reply.error(Errno::EXDEV);
+            return;
+        }
+        let fd_in = self.file_handles.get(fh_in).map(|h| h.real_fd);
+        let out = self.file_handles.get(fh_out).map(|h| (h.real_fd, h.id, h.path.clone(), h.agent.clone()));
+        let (Some(fd_in), Some((fd_out, id, rel_path, agent))) = (fd_in, out) else {
+            reply.error(Errno::EBADF);
+            return;
+        };
+
+        if let Some(id) = id {
+            if !self.check_write(fh_out, id, &rel_path, &agent, "copy_file_range", None) {
+                reply.error(Errno::EIO);
+                return;
+            }
+        }
+        match copy_file_range(fd_in, offset_in, fd_out, offset_out, len.min(u32::MAX as u64)) {
+            Ok(n) => reply.written(n as u32),
+            Err(e) => reply.error(Errno::from(e)),
         }
     }
 }
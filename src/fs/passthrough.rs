@@ -15,7 +15,7 @@ pub fn stat_to_file_attr(st: &libc::stat) -> FileAttr {
         atime: system_time_from_parts(st.st_atime, st.st_atime_nsec),
         mtime: system_time_from_parts(st.st_mtime, st.st_mtime_nsec),
         ctime: system_time_from_parts(st.st_ctime, st.st_ctime_nsec),
-        crtime: system_time_from_parts(st.st_birthtime, st.st_birthtime_nsec),
+        crtime: system_time_from_parts(st.st_ctime, st.st_ctime_nsec),
         kind: mode_to_filetype(st.st_mode as u32),
         perm: (st.st_mode as u32 & 0o7777) as u16,
         nlink: st.st_nlink as u32,
@@ -23,7 +23,7 @@ pub fn stat_to_file_attr(st: &libc::stat) -> FileAttr {
         gid: st.st_gid,
         rdev: st.st_rdev as u32,
         blksize: st.st_blksize as u32,
-        flags: st.st_flags,
+        flags: 0,
     }
 }
 
@@ -49,19 +49,6 @@ pub fn mode_to_filetype(mode: u32) -> fuser::FileType {
     }
 }
 
-/// Perform lstat() on a path.
-pub fn lstat(path: &Path) -> std::io::Result<libc::stat> {
-    let c_path = path_to_cstring(path)?;
-    unsafe {
-        let mut st: libc::stat = std::mem::zeroed();
-        if libc::lstat(c_path.as_ptr(), &mut st) == 0 {
-            Ok(st)
-        } else {
-            Err(std::io::Error::last_os_error())
-        }
-    }
-}
-
 /// Perform fstat() on a file descriptor.
 pub fn fstat(fd: i32) -> std::io::Result<libc::stat> {
     unsafe {
@@ -78,3 +65,112 @@ pub fn path_to_cstring(path: &Path) -> std::io::Result<std::ffi::CString> {
     std::ffi::CString::new(path.as_os_str().as_bytes())
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "path contains null byte"))
 }
+
+/// Lock commands for `lock`. On Linux these take open file description
+/// locks, which belong to a backing descriptor rather than to the daemon, so
+/// two lock owners lock each other out as two processes would. Elsewhere the
+/// locks would be the daemon's, and closing any descriptor of the file would
+/// drop them, so dibs leaves locks to the kernel there.
+#[cfg(target_os = "linux")]
+pub const F_GETLK: i32 = libc::F_OFD_GETLK;
+#[cfg(target_os = "linux")]
+pub const F_SETLK: i32 = libc::F_OFD_SETLK;
+#[cfg(target_os = "linux")]
+pub const F_SETLKW: i32 = libc::F_OFD_SETLKW;
+#[cfg(not(target_os = "linux"))]
+pub const F_GETLK: i32 = libc::F_GETLK;
+#[cfg(not(target_os = "linux"))]
+pub const F_SETLK: i32 = libc::F_SETLK;
+#[cfg(not(target_os = "linux"))]
+pub const F_SETLKW: i32 = libc::F_SETLKW;
+
+/// A byte-range lock as FUSE describes it: bytes `start` to `end`
+/// inclusive, where an `end` of `i64::MAX` runs to the end of the file.
+pub fn flock(start: u64, end: u64, typ: i32) -> libc::flock {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = typ as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = start as libc::off_t;
+    fl.l_len = if end >= i64::MAX as u64 { 0 } else { (end - start + 1) as libc::off_t };
+    fl
+}
+
+/// The range of `fl` the way FUSE describes it, as `(start, end)`.
+pub fn flock_range(fl: &libc::flock) -> (u64, u64) {
+    let start = fl.l_start as u64;
+    let end = if fl.l_len == 0 { i64::MAX as u64 } else { start + fl.l_len as u64 - 1 };
+    (start, end)
+}
+
+/// Perform a lock command (`F_GETLK`, `F_SETLK` or `F_SETLKW`) on `fd`.
+pub fn lock(fd: i32, cmd: i32, fl: &mut libc::flock) -> std::io::Result<()> {
+    if unsafe { libc::fcntl(fd, cmd, fl as *mut libc::flock) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Flush `fd` to disk; only its data and size if `datasync`.
+pub fn fsync(fd: i32, datasync: bool) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let ret = if datasync { unsafe { libc::fdatasync(fd) } } else { unsafe { libc::fsync(fd) } };
+    #[cfg(not(target_os = "linux"))]
+    let ret = {
+        let _ = datasync;
+        unsafe { libc::fsync(fd) }
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `fallocate` in `mode` can change what the file reads as. Only
+/// reserving space without changing the size leaves it alone.
+pub fn fallocate_writes(mode: i32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        mode != libc::FALLOC_FL_KEEP_SIZE
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        mode != 0
+    }
+}
+
+/// Allocate, punch or collapse a range of `fd`. Only Linux has `fallocate`.
+pub fn fallocate(fd: i32, mode: i32, offset: u64, length: u64) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if unsafe { libc::fallocate(fd, mode, offset as libc::off_t, length as libc::off_t) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (fd, mode, offset, length);
+        Err(std::io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+}
+
+/// Copy `len` bytes from `fd_in` at `off_in` to `fd_out` at `off_out`
+/// within the backing filesystem. Elsewhere than Linux, fails with `EXDEV`
+/// so that the caller copies through reads and writes instead.
+pub fn copy_file_range(fd_in: i32, off_in: u64, fd_out: i32, off_out: u64, len: u64) -> std::io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut off_in = off_in as libc::off64_t;
+        let mut off_out = off_out as libc::off64_t;
+        let n = unsafe { libc::copy_file_range(fd_in, &mut off_in, fd_out, &mut off_out, len as usize, 0) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (fd_in, off_in, fd_out, off_out, len);
+        Err(std::io::Error::from_raw_os_error(libc::EXDEV))
+    }
+}
@@ -1,7 +1,171 @@
 /// Virtual .dibs/ directory handling.
-/// Implemented in Phase 4.
+use std::path::{Path, PathBuf};
+
+use fuser::{FileAttr, FileType};
+
+use super::cas;
+use super::inodes::*;
+use super::DibsFs;
+use crate::identity::AgentId;
+use crate::state::hash_table::FileId;
+use crate::state::conflicts::ENTRY_FILES;
 
 pub const DIBS_DIR_NAME: &str = ".dibs";
 pub const DIBS_STATUS_NAME: &str = "status";
 pub const DIBS_LOCKS_NAME: &str = "locks";
 pub const DIBS_CONFLICTS_NAME: &str = "conflicts";
+pub const DIBS_OBJECTS_NAME: &str = "objects";
+pub const DIBS_SELF_NAME: &str = "self";
+pub const DIBS_NAMES_NAME: &str = "names";
+pub const LAST_CONFLICT_NAME: &str = "last-conflict.diff";
+
+/// Prefix of the read-only extended attributes dibs answers itself for
+/// files in the mount: `fresh`, `hash`, `writer` and `last_writer`.
+pub const DIBS_XATTR_PREFIX: &str = "user.dibs.";
+
+/// Current state of a dynamic entry under .dibs/.
+pub enum VirtualNode {
+    /// Directory with its children's names and kinds.
+    Dir(Vec<(String, FileType)>),
+    /// Read-only file with its full content.
+    File(Vec<u8>),
+}
+
+impl DibsFs {
+    /// Virtual path of a dynamic .dibs/ inode: the conflicts, objects and
+    /// self directories and everything below them. The fixed status/locks files
+    /// are handled directly.
+    pub(super) fn virtual_path(&self, ino: u64) -> Option<PathBuf> {
+        if ino == DIBS_CONFLICTS_DIR_INO {
+            return Some(Path::new(DIBS_DIR_NAME).join(DIBS_CONFLICTS_NAME));
+        }
+        if ino == DIBS_OBJECTS_DIR_INO {
+            return Some(Path::new(DIBS_DIR_NAME).join(DIBS_OBJECTS_NAME));
+        }
+        if ino == DIBS_SELF_DIR_INO {
+            return Some(Path::new(DIBS_DIR_NAME).join(DIBS_SELF_NAME));
+        }
+        if !InodeTable::is_synthetic(ino) {
+            return None;
+        }
+        self.inodes.get_path(ino)
+    }
+
+    /// Inode for a virtual path, allocating a synthetic one for dynamic entries.
+    pub(super) fn virtual_ino(&self, vpath: &Path) -> u64 {
+        let dibs = Path::new(DIBS_DIR_NAME);
+        if vpath == dibs {
+            DIBS_DIR_INO
+        } else if vpath == dibs.join(DIBS_CONFLICTS_NAME) {
+            DIBS_CONFLICTS_DIR_INO
+        } else if vpath == dibs.join(DIBS_OBJECTS_NAME) {
+            DIBS_OBJECTS_DIR_INO
+        } else if vpath == dibs.join(DIBS_SELF_NAME) {
+            DIBS_SELF_DIR_INO
+        } else {
+            self.inodes.synthetic_ino(vpath)
+        }
+    }
+
+    /// Resolve a dynamic virtual path to its current node as seen by `agent`,
+    /// or None if it doesn't exist (e.g. the conflict was pruned).
+    pub(super) fn virtual_node(&self, vpath: &Path, agent: &AgentId) -> Option<VirtualNode> {
+        let rest = vpath.strip_prefix(DIBS_DIR_NAME).ok()?;
+        let parts: Vec<&str> = rest.iter().map(|c| c.to_str()).collect::<Option<_>>()?;
+        match parts.split_first() {
+            Some((&DIBS_CONFLICTS_NAME, rest)) => self.conflicts_node(rest),
+            Some((&DIBS_OBJECTS_NAME, rest)) => self.objects_node(rest),
+            Some((&DIBS_SELF_NAME, rest)) => self.self_node(rest, agent),
+            _ => None,
+        }
+    }
+
+    /// .dibs/conflicts/<id>/<part>
+    fn conflicts_node(&self, parts: &[&str]) -> Option<VirtualNode> {
+        let conflicts = self.conflicts.as_ref();
+
+        match parts {
+            [] => {
+                let ids = conflicts.map(|c| c.list()).unwrap_or_default();
+                Some(VirtualNode::Dir(
+                    ids.into_iter().map(|id| (id, FileType::Directory)).collect(),
+                ))
+            }
+            [id] if conflicts?.contains(id) => Some(VirtualNode::Dir(
+                ENTRY_FILES
+                    .iter()
+                    .map(|name| (name.to_string(), FileType::RegularFile))
+                    .collect(),
+            )),
+            [id, part] => conflicts?.read_part(id, part).ok().map(VirtualNode::File),
+            _ => None,
+        }
+    }
+
+    /// .dibs/objects/<hex>
+    fn objects_node(&self, parts: &[&str]) -> Option<VirtualNode> {
+        match parts {
+            [] => Some(VirtualNode::Dir(
+                self.objects
+                    .list()
+                    .into_iter()
+                    .map(|hex| (hex, FileType::RegularFile))
+                    .collect(),
+            )),
+            [hex] => self.objects.get_hex(hex).map(|data| VirtualNode::File(data.to_vec())),
+            _ => None,
+        }
+    }
+
+    /// .dibs/self/last-conflict.diff, different for every agent
+    fn self_node(&self, parts: &[&str], agent: &AgentId) -> Option<VirtualNode> {
+        let report = self.reports.last(agent);
+        match parts {
+            [] => Some(VirtualNode::Dir(
+                report
+                    .iter()
+                    .map(|_| (LAST_CONFLICT_NAME.to_string(), FileType::RegularFile))
+                    .collect(),
+            )),
+            [name] if *name == LAST_CONFLICT_NAME => report.map(|r| VirtualNode::File(r.render().into_bytes())),
+            _ => None,
+        }
+    }
+
+    /// Whether a virtual path's content depends on the calling agent.
+    pub(super) fn is_per_agent(vpath: &Path) -> bool {
+        vpath.starts_with(Path::new(DIBS_DIR_NAME).join(DIBS_SELF_NAME))
+    }
+
+    /// Attributes for a dynamic virtual node.
+    pub(super) fn virtual_attr(ino: u64, node: &VirtualNode) -> FileAttr {
+        match node {
+            VirtualNode::Dir(_) => Self::dibs_dir_attr(ino),
+            VirtualNode::File(content) => Self::dibs_file_attr(ino, content.len() as u64),
+        }
+    }
+
+    /// Value of the query attribute `user.dibs.<name>` of file `rel`, as
+    /// `agent` sees it. None if there's no such attribute, or it has no
+    /// value: `fresh` for a file the agent never read, `writer` for a file
+    /// nobody is writing, and so on. Only regular files have any.
+    pub(super) fn dibs_xattr(&self, rel: &Path, name: &str, agent: &AgentId) -> std::io::Result<Option<String>> {
+        let st = self.root.lstat(rel)?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return Ok(None);
+        }
+        let id = FileId::of(&st);
+        Ok(match name {
+            // Whether the agent's last read is still what the file holds,
+            // as its next write would be checked
+            "fresh" => self.receipt_fresh(agent, id, rel)?.map(|fresh| fresh.to_string()),
+            "hash" => Some(cas::hash_hex(&self.root.hash(rel)?)),
+            "writer" => self
+                .cas_table
+                .write_owner(id)
+                .and_then(|fh| self.file_handles.get(fh).map(|h| self.names.label(&h.agent))),
+            "last_writer" => self.cas_table.last_write(id).map(|(agent, _)| self.names.label(&agent)),
+            _ => None,
+        })
+    }
+}
@@ -0,0 +1,487 @@
+/// Telling agents apart.
+///
+/// Everything dibs tracks per agent (reader receipts, handle owners, conflict
+/// reports) is keyed by an `AgentId`. An `IdentityResolver` turns the PID and
+/// UID of a FUSE request into one; the strategy is chosen with `--identity`.
+use std::fmt;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::warn;
+
+use crate::config::IdentityStrategy;
+
+/// Environment variable read by the `env` strategy.
+pub const AGENT_ENV_VAR: &str = "DIBS_AGENT";
+
+/// Opaque agent identifier. Two requests belong to the same agent iff their
+/// IDs are equal; the text is only meant for display ("session 4242").
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AgentId(Arc<str>);
+
+impl AgentId {
+    /// An ID of the given kind, e.g. `AgentId::new("uid", 501)`.
+    pub fn new(kind: &str, value: impl fmt::Display) -> Self {
+        Self(format!("{} {}", kind, value).into())
+    }
+
+    /// The agent owning Unix session `sid`.
+    pub fn session(sid: u32) -> Self {
+        Self::new("session", sid)
+    }
+
+    /// The session this agent is, if it is one.
+    pub fn sid(&self) -> Option<u32> {
+        self.0.strip_prefix("session ")?.parse().ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AgentId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for AgentId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+/// Accepts the bare session numbers written before agent IDs existed.
+impl<'de> Deserialize<'de> for AgentId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Session(u32),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Text(s) => Self(s.into()),
+            Repr::Session(sid) => Self::session(sid),
+        })
+    }
+}
+
+/// Maps the caller of a FUSE request to the agent it belongs to.
+pub trait IdentityResolver: Send + Sync {
+    fn resolve(&self, pid: u32, uid: u32) -> AgentId;
+}
+
+/// Build the resolver for a `--identity` strategy. Strategies that read a
+/// process's /proc files remember the answers in `pids`.
+pub fn resolver(strategy: IdentityStrategy, ancestry_root: u32, pids: &Arc<PidCache>) -> Box<dyn IdentityResolver> {
+    if matches!(strategy, IdentityStrategy::Cgroup | IdentityStrategy::Env) && !cfg!(target_os = "linux") {
+        warn!("--identity {:?} needs /proc; falling back to session IDs", strategy);
+        return Box::new(SessionResolver);
+    }
+    match strategy {
+        IdentityStrategy::Session => Box::new(SessionResolver),
+        IdentityStrategy::Uid => Box::new(UidResolver),
+        IdentityStrategy::Cgroup => Box::new(CgroupResolver { pids: Arc::clone(pids) }),
+        IdentityStrategy::Ancestry => Box::new(AncestryResolver {
+            root: ancestry_root,
+            pids: Arc::clone(pids),
+        }),
+        IdentityStrategy::Env => Box::new(EnvResolver { pids: Arc::clone(pids) }),
+    }
+}
+
+/// One agent per Unix session (`getsid`). Falls back to the PID itself.
+pub struct SessionResolver;
+
+impl IdentityResolver for SessionResolver {
+    fn resolve(&self, pid: u32, _uid: u32) -> AgentId {
+        let sid = unsafe { libc::getsid(pid as i32) };
+        AgentId::session(if sid < 0 { pid } else { sid as u32 })
+    }
+}
+
+/// One agent per Unix user.
+pub struct UidResolver;
+
+impl IdentityResolver for UidResolver {
+    fn resolve(&self, _pid: u32, uid: u32) -> AgentId {
+        AgentId::new("uid", uid)
+    }
+}
+
+/// One agent per cgroup, e.g. per container or systemd scope. A process is
+/// taken to stay in the cgroup it was first seen in.
+pub struct CgroupResolver {
+    pub pids: Arc<PidCache>,
+}
+
+impl IdentityResolver for CgroupResolver {
+    fn resolve(&self, pid: u32, uid: u32) -> AgentId {
+        self.pids.resolve(pid, || match std::fs::read_to_string(format!("/proc/{}/cgroup", pid)) {
+            Ok(data) => match parse_cgroup(&data) {
+                Some(path) => AgentId::new("cgroup", path),
+                None => SessionResolver.resolve(pid, uid),
+            },
+            Err(_) => SessionResolver.resolve(pid, uid),
+        })
+    }
+}
+
+/// One agent per child of the `root` process: every process is attributed to
+/// its ancestor directly below `root`. Processes that don't descend from
+/// `root` fall back to their session. A process is taken to keep the
+/// ancestor it was first seen with.
+pub struct AncestryResolver {
+    pub root: u32,
+    pub pids: Arc<PidCache>,
+}
+
+impl IdentityResolver for AncestryResolver {
+    fn resolve(&self, pid: u32, uid: u32) -> AgentId {
+        self.pids.resolve(pid, || {
+            let mut current = pid;
+            // Bounded in case of a cycle from PID reuse mid-walk
+            for _ in 0..1024 {
+                match parent_pid(current) {
+                    Some(parent) if parent == self.root => return AgentId::new("process", current),
+                    Some(parent) if parent > 1 => current = parent,
+                    _ => break,
+                }
+            }
+            SessionResolver.resolve(pid, uid)
+        })
+    }
+}
+
+/// Agents name themselves with `DIBS_AGENT` in their environment. Processes
+/// without it fall back to their session.
+pub struct EnvResolver {
+    pub pids: Arc<PidCache>,
+}
+
+impl IdentityResolver for EnvResolver {
+    fn resolve(&self, pid: u32, uid: u32) -> AgentId {
+        self.pids.resolve(pid, || match std::fs::read(format!("/proc/{}/environ", pid)) {
+            Ok(data) => match parse_environ(&data, AGENT_ENV_VAR) {
+                Some(name) => AgentId::new("agent", name),
+                None => SessionResolver.resolve(pid, uid),
+            },
+            Err(_) => SessionResolver.resolve(pid, uid),
+        })
+    }
+}
+
+/// Agents already resolved from a process's /proc files, by PID. Each entry
+/// carries the process's start time, so a reused PID is resolved afresh.
+pub struct PidCache {
+    agents: DashMap<u32, (u64, AgentId)>,
+}
+
+impl PidCache {
+    pub fn new() -> Self {
+        Self {
+            agents: DashMap::new(),
+        }
+    }
+
+    /// The agent of process `pid`, from `resolve` if it isn't known yet.
+    fn resolve(&self, pid: u32, resolve: impl FnOnce() -> AgentId) -> AgentId {
+        let Some(start) = start_time(pid) else {
+            return resolve();
+        };
+        if let Some(entry) = self.agents.get(&pid).filter(|entry| entry.0 == start) {
+            return entry.1.clone();
+        }
+        let agent = resolve();
+        self.agents.insert(pid, (start, agent.clone()));
+        agent
+    }
+
+    /// Forget processes that have exited.
+    pub fn prune(&self) {
+        self.agents.retain(|&pid, (start, _)| start_time(pid) == Some(*start));
+    }
+}
+
+impl Default for PidCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every request is the same agent; used for `--agent NAME=MOUNTPOINT`
+/// mounts, where the mount point itself says who is calling.
+pub struct FixedResolver(pub AgentId);
+
+impl IdentityResolver for FixedResolver {
+    fn resolve(&self, _pid: u32, _uid: u32) -> AgentId {
+        self.0.clone()
+    }
+}
+
+/// Human-readable names for sessions, registered by `dibs run` through
+/// `.dibs/names`. Names are labels only: the agent is still the session, so a
+/// read before registration counts the same as one after it.
+pub struct AgentNames {
+    by_agent: DashMap<AgentId, (u32, String)>,
+}
+
+impl AgentNames {
+    pub fn new() -> Self {
+        Self {
+            by_agent: DashMap::new(),
+        }
+    }
+
+    /// Apply `.dibs/names` commands, one per line: `SID NAME` names a
+    /// session, a bare `SID` forgets its name.
+    pub fn apply(&self, commands: &str) -> Result<(), String> {
+        for line in commands.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (sid, name) = match line.split_once(char::is_whitespace) {
+                Some((sid, name)) => (sid, Some(name.trim())),
+                None => (line, None),
+            };
+            let sid: u32 = sid.parse().map_err(|_| format!("invalid session ID {:?}", sid))?;
+            match name {
+                Some(name) => {
+                    self.by_agent.insert(AgentId::session(sid), (sid, name.to_string()));
+                }
+                None => {
+                    self.by_agent.remove(&AgentId::session(sid));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The agent's registered name, or its ID if it has none.
+    pub fn label(&self, agent: &AgentId) -> String {
+        match self.by_agent.get(agent) {
+            Some(entry) => entry.1.clone(),
+            None => agent.to_string(),
+        }
+    }
+
+    /// Registered agents and their names, ordered by name.
+    pub fn list(&self) -> Vec<(AgentId, String)> {
+        let mut names: Vec<_> = self.by_agent.iter().map(|e| (e.key().clone(), e.value().1.clone())).collect();
+        names.sort_by(|a, b| a.1.cmp(&b.1));
+        names
+    }
+
+    /// Content of `.dibs/names`: one `SID NAME` line per registration.
+    pub fn render(&self) -> String {
+        let mut entries: Vec<_> = self.by_agent.iter().map(|e| e.value().clone()).collect();
+        entries.sort();
+        entries.into_iter().map(|(sid, name)| format!("{} {}\n", sid, name)).collect()
+    }
+
+    /// Forget the names of sessions that have no processes left, in case
+    /// `dibs run` didn't get to unregister them.
+    pub fn prune(&self) {
+        self.by_agent.retain(|_, (sid, _)| session_alive(*sid));
+    }
+}
+
+impl Default for AgentNames {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether any process is still in session `sid`.
+#[cfg(target_os = "linux")]
+pub fn session_alive(sid: u32) -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return true;
+    };
+    entries
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .any(|pid| session_of(pid) == Some(sid))
+}
+
+/// Whether any process is still in session `sid`. Without /proc, only the
+/// session's first process group (the one `dibs run` starts) is checked.
+#[cfg(not(target_os = "linux"))]
+pub fn session_alive(sid: u32) -> bool {
+    unsafe { libc::kill(-(sid as libc::pid_t), 0) == 0 }
+}
+
+/// The cgroup path from `/proc/<pid>/cgroup`: the unified (v2) hierarchy if
+/// present, otherwise the first v1 hierarchy.
+fn parse_cgroup(data: &str) -> Option<&str> {
+    let paths = data.lines().filter_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let id = fields.next()?;
+        let _controllers = fields.next()?;
+        Some((id, fields.next()?))
+    });
+    let mut first = None;
+    for (id, path) in paths {
+        if id == "0" {
+            return Some(path);
+        }
+        first.get_or_insert(path);
+    }
+    first
+}
+
+/// Value of `var` in a NUL-separated environment block.
+fn parse_environ(data: &[u8], var: &str) -> Option<String> {
+    data.split(|&b| b == 0).find_map(|entry| {
+        let value = entry.strip_prefix(var.as_bytes())?.strip_prefix(b"=")?;
+        (!value.is_empty()).then(|| String::from_utf8_lossy(value).into_owned())
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn parent_pid(pid: u32) -> Option<u32> {
+    stat_field(pid, 1)
+}
+
+#[cfg(target_os = "linux")]
+fn session_of(pid: u32) -> Option<u32> {
+    stat_field(pid, 3)
+}
+
+/// When process `pid` started, in clock ticks since boot.
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<u64> {
+    stat_field(pid, 19)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Numeric field of `/proc/<pid>/stat`, counted from the one after the
+/// command name (0 = state, 1 = ppid, 2 = pgrp, 3 = session, 19 = start
+/// time).
+#[cfg(target_os = "linux")]
+fn stat_field<T: std::str::FromStr>(pid: u32, index: usize) -> Option<T> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces and parentheses; fields resume
+    // after the last ')'.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(index)?.parse().ok()
+}
+
+#[cfg(target_os = "macos")]
+fn parent_pid(pid: u32) -> Option<u32> {
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let n = unsafe {
+        libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            &mut info as *mut _ as *mut libc::c_void,
+            size,
+        )
+    };
+    (n == size).then_some(info.pbi_ppid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn parent_pid(_pid: u32) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The unified hierarchy wins over v1 controllers
+    #[test]
+    fn test_parse_cgroup() {
+        let v2 = "0::/user.slice/user-1000.slice/session-2.scope\n";
+        assert_eq!(parse_cgroup(v2), Some("/user.slice/user-1000.slice/session-2.scope"));
+
+        let mixed = "12:cpu,cpuacct:/docker/abc\n1:name=systemd:/docker/abc\n0::/docker/def\n";
+        assert_eq!(parse_cgroup(mixed), Some("/docker/def"));
+        assert_eq!(parse_cgroup("4:memory:/docker/abc\n"), Some("/docker/abc"));
+        assert_eq!(parse_cgroup(""), None);
+    }
+
+    /// Only an exact, non-empty DIBS_AGENT entry counts
+    #[test]
+    fn test_parse_environ() {
+        let env = b"PATH=/bin\0DIBS_AGENT_X=no\0DIBS_AGENT=claude-1\0";
+        assert_eq!(parse_environ(env, AGENT_ENV_VAR), Some("claude-1".to_string()));
+        assert_eq!(parse_environ(b"DIBS_AGENT=\0", AGENT_ENV_VAR), None);
+        assert_eq!(parse_environ(b"HOME=/root\0", AGENT_ENV_VAR), None);
+    }
+
+    /// Session numbers from older conflict metadata still parse
+    #[test]
+    fn test_agent_id_serde() {
+        let id = AgentId::new("agent", "claude-1");
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, "\"agent claude-1\"");
+        assert_eq!(serde_json::from_str::<AgentId>(&json).unwrap(), id);
+        assert_eq!(serde_json::from_str::<AgentId>("4242").unwrap(), AgentId::session(4242));
+        assert_eq!(AgentId::session(4242).sid(), Some(4242));
+        assert_eq!(id.sid(), None);
+    }
+
+    /// Names are set and cleared per session and only change labels
+    #[test]
+    fn test_agent_names() {
+        let names = AgentNames::new();
+        names.apply("4242 auth-refactor\n17 docs writer\n").unwrap();
+        assert_eq!(names.label(&AgentId::session(4242)), "auth-refactor");
+        assert_eq!(names.label(&AgentId::session(17)), "docs writer");
+        assert_eq!(names.label(&AgentId::session(99)), "session 99");
+        assert_eq!(names.render(), "17 docs writer\n4242 auth-refactor\n");
+
+        names.apply("4242").unwrap();
+        assert_eq!(names.label(&AgentId::session(4242)), "session 4242");
+        assert!(names.apply("abc name").is_err());
+        assert_eq!(names.list(), vec![(AgentId::session(17), "docs writer".to_string())]);
+    }
+
+    /// A process is resolved once, until its PID turns out to be reused
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pid_cache() {
+        let me = std::process::id();
+        let pids = PidCache::new();
+        assert_eq!(pids.resolve(me, || AgentId::new("agent", "a")), AgentId::new("agent", "a"));
+        assert_eq!(pids.resolve(me, || AgentId::new("agent", "b")), AgentId::new("agent", "a"));
+
+        // The same PID with another start time is another process
+        pids.agents.insert(me, (0, AgentId::new("agent", "old")));
+        assert_eq!(pids.resolve(me, || AgentId::new("agent", "b")), AgentId::new("agent", "b"));
+
+        pids.agents.insert(u32::MAX, (0, AgentId::new("agent", "gone")));
+        pids.prune();
+        assert_eq!(pids.agents.len(), 1);
+        assert!(pids.agents.contains_key(&me));
+    }
+
+    /// The calling process is attributed to its ancestor below the root
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_ancestry() {
+        let me = std::process::id();
+        let parent = parent_pid(me).unwrap();
+        let pids = Arc::new(PidCache::new());
+        let resolver = AncestryResolver {
+            root: parent,
+            pids: Arc::clone(&pids),
+        };
+        assert_eq!(resolver.resolve(me, 0), AgentId::new("process", me));
+        assert!(pids.agents.contains_key(&me), "the walk is remembered");
+
+        // Not below the root: falls back to the session
+        let resolver = AncestryResolver {
+            root: u32::MAX,
+            pids: Arc::new(PidCache::new()),
+        };
+        assert_eq!(resolver.resolve(me, 0), SessionResolver.resolve(me, 0));
+    }
+}
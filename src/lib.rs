@@ -1,4 +1,9 @@
 pub mod config;
 pub mod error;
 pub mod fs;
+pub mod identity;
+pub mod merge;
+pub mod policy;
+pub mod resolve;
+pub mod run;
 pub mod state;
@@ -7,9 +7,11 @@ use tracing::{error, info};
 use tracing_subscriber::fmt;
 use tracing_subscriber::prelude::*;
 
-use dibs::config::{Cli, Command, DibsConfig};
+use dibs::config::{AgentMount, Cli, Command, DibsConfig, Take};
 use dibs::fs::handles::HandleTable;
+use dibs::fs::virtual_dir::{DIBS_DIR_NAME, DIBS_STATUS_NAME};
 use dibs::fs::DibsFs;
+use dibs::resolve::Resolver;
 
 use std::path::Path;
 
@@ -45,17 +47,17 @@ fn try_unmount(mountpoint: &Path) -> bool {
 }
 
 /// Block until shutdown conditions are met. Implements two-phase ctrl-C:
-/// - First signal when mount is not busy: immediate clean unmount.
-/// - First signal when mount is busy (open handles, CWD, etc.): warn and wait.
+/// - First signal when no mount is busy: immediate clean unmount.
+/// - First signal when a mount is busy (open handles, CWD, etc.): warn and wait.
 /// - Second signal: force unmount.
-/// - FUSE session exits on its own: external unmount.
+/// - Every FUSE session exits on its own: external unmount.
 ///
 /// Mount busyness is probed via a regular (non-forced) `umount` call — this
 /// catches both FUSE file handles and kernel VFS references (e.g. CWD).
+/// Mounts that unmount cleanly stay unmounted while the others are busy.
 fn wait_for_shutdown(
-    guard: &std::thread::JoinHandle<std::io::Result<()>>,
+    sessions: &[(fuser::BackgroundSession, PathBuf)],
     file_handles: &HandleTable,
-    mountpoint: &Path,
 ) -> ShutdownAction {
     let mut pipe_fds = [0 as libc::c_int; 2];
     assert_eq!(
@@ -74,9 +76,21 @@ fn wait_for_shutdown(
 
     let mut first_signal_received = false;
     let mut poll_ticks: u32 = 0;
+    let mut unmounted = vec![false; sessions.len()];
+
+    let all_finished = || sessions.iter().all(|(session, _)| session.guard.is_finished());
+    // Probe every mount that is still up; true once none is left.
+    let unmount_remaining = |unmounted: &mut [bool]| {
+        for (i, (session, mountpoint)) in sessions.iter().enumerate() {
+            if !unmounted[i] && (session.guard.is_finished() || try_unmount(mountpoint)) {
+                unmounted[i] = true;
+            }
+        }
+        unmounted.iter().all(|&u| u)
+    };
 
     // Poll the signal pipe with a timeout so we can also notice when the FUSE
-    // background thread exits (external unmount). The 200ms timeout means up to
+    // background threads exit (external unmount). The 200ms timeout means up to
     // 200ms latency detecting external unmount or handle count changes.
     let action = loop {
         let mut pfd = libc::pollfd {
@@ -99,20 +113,20 @@ fn wait_for_shutdown(
                 break ShutdownAction::ForceUnmount;
             }
 
-            // First signal — check if the session already ended (race with
+            // First signal — check if the sessions already ended (race with
             // external unmount that happened just before the signal).
-            if guard.is_finished() {
+            if all_finished() {
                 break ShutdownAction::ExternalUnmount;
             }
 
             // Probe actual mount busyness via system umount. If it succeeds,
             // the mount was cleanly removed — the FUSE session will notice.
-            if try_unmount(mountpoint) {
+            if unmount_remaining(&mut unmounted) {
                 eprintln!("dibs: unmounting (received signal)...");
                 break ShutdownAction::ExternalUnmount;
             }
 
-            // Mount is busy — warn and wait.
+            // A mount is busy — warn and wait.
             first_signal_received = true;
             let open_files = file_handles.list_open();
             if open_files.is_empty() {
@@ -127,9 +141,9 @@ fn wait_for_shutdown(
                 let display_cap = 10;
                 for info in open_files.iter().take(display_cap) {
                     eprintln!(
-                        "  {}  (SID {})",
+                        "  {}  ({})",
                         info.path.display(),
-                        info.sid,
+                        info.agent,
                     );
                 }
                 if open_files.len() > display_cap {
@@ -143,7 +157,7 @@ fn wait_for_shutdown(
         }
 
         // Poll timeout — check for external unmount or mount release.
-        if guard.is_finished() {
+        if all_finished() {
             break ShutdownAction::ExternalUnmount;
         }
 
@@ -151,7 +165,7 @@ fn wait_for_shutdown(
             poll_ticks += 1;
             // Probe every ~1 second (5 ticks * 200ms) to avoid spawning
             // umount too frequently.
-            if poll_ticks % 5 == 0 && try_unmount(mountpoint) {
+            if poll_ticks % 5 == 0 && unmount_remaining(&mut unmounted) {
                 eprintln!("dibs: all clear, unmounting...");
                 break ShutdownAction::ExternalUnmount;
             }
@@ -167,6 +181,36 @@ fn wait_for_shutdown(
     action
 }
 
+/// Create `mountpoint` if needed and make sure it isn't a stale FUSE mount.
+/// Returns its canonical path; exits on error.
+fn prepare_mountpoint(mountpoint: &Path) -> PathBuf {
+    // Create mountpoint if it doesn't exist
+    if !mountpoint.exists() {
+        if let Err(e) = std::fs::create_dir_all(mountpoint) {
+            eprintln!("Error creating mountpoint {:?}: {}", mountpoint, e);
+            std::process::exit(1);
+        }
+    }
+
+    let mountpoint = std::fs::canonicalize(mountpoint).unwrap_or_else(|e| {
+        eprintln!("Error: mountpoint {:?}: {}", mountpoint, e);
+        std::process::exit(1);
+    });
+
+    // Check for a stale FUSE mount left behind by a previous crash or
+    // forced kill.  macFUSE does not auto-cleanup these.
+    if is_stale_fuse_mount(&mountpoint) {
+        eprintln!(
+            "Error: {} is a stale FUSE mount (previous dibs session didn't clean up).\n\
+             Fix with:  umount -f {}",
+            mountpoint.display(),
+            mountpoint.display(),
+        );
+        std::process::exit(1);
+    }
+    mountpoint
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -174,10 +218,23 @@ fn main() {
         Command::Mount {
             backing,
             mountpoint,
+            in_place,
+            agents,
             session_id,
             log_file,
             eviction_minutes,
             save_conflicts,
+            max_conflicts,
+            conflict_max_age_hours,
+            object_store_mb,
+            defer_rejection,
+            auto_merge,
+            identity,
+            ancestry_root,
+            contain_symlinks,
+            attr_policy,
+            strict,
+            policies,
             readonly_fallback,
             foreground,
         } => {
@@ -191,29 +248,30 @@ fn main() {
                 std::process::exit(1);
             }
 
-            // Create mountpoint if it doesn't exist
-            if !mountpoint.exists() {
-                if let Err(e) = std::fs::create_dir_all(&mountpoint) {
-                    eprintln!("Error creating mountpoint {:?}: {}", mountpoint, e);
+            // In place, the backing directory is opened before it's covered
+            // and reached through that descriptor from then on.
+            let mountpoint = if in_place {
+                if backing.join(DIBS_DIR_NAME).join(DIBS_STATUS_NAME).exists() {
+                    eprintln!("Error: {} is already a dibs mount", backing.display());
+                    std::process::exit(1);
+                }
+                Some(prepare_mountpoint(&backing))
+            } else {
+                mountpoint.as_deref().map(prepare_mountpoint)
+            };
+            let agents: Vec<AgentMount> = agents
+                .into_iter()
+                .map(|mount| AgentMount {
+                    mountpoint: prepare_mountpoint(&mount.mountpoint),
+                    name: mount.name,
+                })
+                .collect();
+            for (i, mount) in agents.iter().enumerate() {
+                let clash = agents[..i].iter().find(|other| other.name == mount.name || other.mountpoint == mount.mountpoint);
+                if clash.is_some() || mountpoint.as_ref() == Some(&mount.mountpoint) {
+                    eprintln!("Error: --agent {}={} reuses a name or mount point", mount.name, mount.mountpoint.display());
                     std::process::exit(1);
                 }
-            }
-
-            let mountpoint = std::fs::canonicalize(&mountpoint).unwrap_or_else(|e| {
-                eprintln!("Error: mountpoint {:?}: {}", mountpoint, e);
-                std::process::exit(1);
-            });
-
-            // Check for a stale FUSE mount left behind by a previous crash or
-            // forced kill.  macFUSE does not auto-cleanup these.
-            if is_stale_fuse_mount(&mountpoint) {
-                eprintln!(
-                    "Error: {} is a stale FUSE mount (previous dibs session didn't clean up).\n\
-                     Fix with:  umount -f {}",
-                    mountpoint.display(),
-                    mountpoint.display(),
-                );
-                std::process::exit(1);
             }
 
             let sid = session_id.unwrap_or_else(|| {
@@ -247,40 +305,65 @@ fn main() {
             tracing::subscriber::set_global_default(subscriber)
                 .expect("Failed to set tracing subscriber");
 
-            let log_file_for_retry = log_file.clone();
             let config = DibsConfig {
                 backing: backing.clone(),
                 mountpoint: mountpoint.clone(),
+                agents: agents.clone(),
                 session_id: sid.clone(),
                 log_file,
                 eviction_minutes,
                 save_conflicts,
+                max_conflicts,
+                conflict_max_age_hours,
+                object_store_mb,
+                defer_rejection,
+                auto_merge,
+                identity,
+                ancestry_root,
+                contain_symlinks,
+                attr_policy,
+                strict,
+                policies,
                 readonly_fallback,
                 foreground,
             };
 
+            // The shared mount point (if any) plus one per agent
+            let mounts: Vec<(Option<&AgentMount>, &Path)> = mountpoint
+                .as_deref()
+                .map(|mp| (None, mp))
+                .into_iter()
+                .chain(agents.iter().map(|mount| (Some(mount), mount.mountpoint.as_path())))
+                .collect();
+
             info!(
-                "dibs starting: session={}, backing={}, mountpoint={}",
+                "dibs starting: session={}, backing={}, mountpoints={}",
                 sid,
                 backing.display(),
-                mountpoint.display()
+                mounts.iter().map(|(_, mp)| mp.display().to_string()).collect::<Vec<_>>().join(", ")
             );
 
-            let dibsfs = DibsFs::new(config);
+            // Holds the state shared by every mount; each FUSE session gets
+            // its own DibsFs built from it.
+            let dibsfs = DibsFs::new(config).unwrap_or_else(|e| {
+                error!("Failed to open backing directory {}: {}", backing.display(), e);
+                std::process::exit(1);
+            });
 
             // Start eviction thread
             let shutdown = Arc::new(AtomicBool::new(false));
             let cas_arc = Arc::clone(&dibsfs.cas_table);
             let eviction_handle = dibs::state::eviction::start_eviction_thread(
                 cas_arc,
+                Arc::clone(&dibsfs.objects),
+                Arc::clone(&dibsfs.reports),
+                Arc::clone(&dibsfs.names),
+                Arc::clone(&dibsfs.pids),
+                dibsfs.conflicts.clone(),
                 eviction_minutes,
                 shutdown.clone(),
             );
 
-            // Clone the file_handles Arc so we can query open handles from main
-            // after DibsFs is moved into the FUSE session.
-            let mut file_handles_arc = Arc::clone(&dibsfs.file_handles);
-
             // Mount configuration
             let mut fuse_config = fuser::Config::default();
             fuse_config.mount_options = vec![
@@ -292,75 +375,129 @@ fn main() {
 
             info!("Mounting dibs filesystem...");
 
-            // Spawn FUSE session in background thread
-            let session = match fuser::spawn_mount2(dibsfs, &mountpoint, &fuse_config) {
-                Ok(session) => session,
-                Err(e) => {
-                    if e.raw_os_error() == Some(libc::EPERM)
-                        || e.to_string().contains("allow_other")
-                    {
-                        fuse_config.acl = fuser::SessionACL::Owner;
-                        info!("Retrying mount without allow_other...");
-                        let retry_config = DibsConfig {
-                            backing: backing.clone(),
-                            mountpoint: mountpoint.clone(),
-                            session_id: sid.clone(),
-                            log_file: log_file_for_retry,
-                            eviction_minutes,
-                            save_conflicts,
-                            readonly_fallback,
-                            foreground,
-                        };
-                        let retry_dibsfs = DibsFs::new(retry_config);
-                        file_handles_arc = Arc::clone(&retry_dibsfs.file_handles);
-                        match fuser::spawn_mount2(
-                            retry_dibsfs,
-                            &mountpoint,
-                            &fuse_config,
-                        ) {
-                            Ok(session) => session,
-                            Err(e) => {
-                                error!("Failed to mount: {}", e);
-                                std::process::exit(1);
+            // Spawn a FUSE session per mount point in background threads
+            let mut sessions = Vec::with_capacity(mounts.len());
+            for (agent, mountpoint) in mounts {
+                let session = match fuser::spawn_mount2(dibsfs.for_mount(agent), mountpoint, &fuse_config) {
+                    Ok(session) => session,
+                    Err(e) => {
+                        if fuse_config.acl == fuser::SessionACL::All
+                            && (e.raw_os_error() == Some(libc::EPERM)
+                                || e.to_string().contains("allow_other"))
+                        {
+                            fuse_config.acl = fuser::SessionACL::Owner;
+                            info!("Retrying mount without allow_other...");
+                            match fuser::spawn_mount2(
+                                dibsfs.for_mount(agent),
+                                mountpoint,
+                                &fuse_config,
+                            ) {
+                                Ok(session) => session,
+                                Err(e) => {
+                                    error!("Failed to mount {}: {}", mountpoint.display(), e);
+                                    std::process::exit(1);
+                                }
                             }
+                        } else {
+                            error!("Failed to mount {}: {}", mountpoint.display(), e);
+                            std::process::exit(1);
                         }
-                    } else {
-                        error!("Failed to mount: {}", e);
-                        std::process::exit(1);
                     }
+                };
+                dibsfs.add_notifier(session.notifier());
+                match agent {
+                    Some(mount) => info!("dibs mounted at {} for agent {}", mountpoint.display(), mount.name),
+                    None => info!("dibs mounted at {}", mountpoint.display()),
                 }
-            };
-
-            info!("dibs mounted at {}", mountpoint.display());
+                sessions.push((session, mountpoint.to_path_buf()));
+            }
 
-            let action = wait_for_shutdown(&session.guard, &file_handles_arc, &mountpoint);
+            let action = wait_for_shutdown(&sessions, &dibsfs.file_handles);
 
-            // Stop the eviction thread before joining the session for clean shutdown.
+            // Stop the eviction thread before joining the sessions for clean shutdown.
             shutdown.store(true, Ordering::Relaxed);
             let _ = eviction_handle.join();
 
-            match action {
-                ShutdownAction::ForceUnmount => {
-                    if let Err(e) = session.umount_and_join() {
-                        error!("Error during unmount, trying force unmount: {}", e);
-                        let mp = mountpoint.to_string_lossy();
-                        let _ = std::process::Command::new("umount")
-                            .args(["-f", &*mp])
-                            .status();
+            for (session, mountpoint) in sessions {
+                match action {
+                    ShutdownAction::ForceUnmount => {
+                        if let Err(e) = session.umount_and_join() {
+                            error!("Error during unmount, trying force unmount: {}", e);
+                            let mp = mountpoint.to_string_lossy();
+                            let _ = std::process::Command::new("umount")
+                                .args(["-f", &*mp])
+                                .status();
+                        }
                     }
-                }
-                ShutdownAction::ExternalUnmount => {
-                    if let Err(e) = session.join() {
-                        error!("Error joining FUSE session: {}", e);
+                    ShutdownAction::ExternalUnmount => {
+                        if let Err(e) = session.join() {
+                            error!("Error joining FUSE session: {}", e);
+                        }
                     }
                 }
-            }
 
-            eprintln!("dibs: unmounted {}", mountpoint.display());
+                eprintln!("dibs: unmounted {}", mountpoint.display());
+            }
         }
         Command::Unmount { mountpoint } => {
             unmount(&mountpoint);
         }
+        Command::Resolve {
+            mountpoint,
+            id,
+            take,
+            edit,
+        } => {
+            resolve(&mountpoint, id.as_deref(), take, edit);
+        }
+        Command::Run {
+            name,
+            mount,
+            isolate,
+            command,
+        } => {
+            match dibs::run::run(&mount, &name, &command, isolate) {
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// List pending conflicts, or settle conflict `id` by taking `take`.
+fn resolve(mountpoint: &Path, id: Option<&str>, take: Option<Take>, edit: bool) {
+    let resolver = Resolver::open(mountpoint).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+
+    let (Some(id), Some(take)) = (id, take) else {
+        let pending = resolver.pending();
+        let pending: Vec<_> = match id {
+            Some(id) => pending.into_iter().filter(|m| m.id == id).collect(),
+            None => pending,
+        };
+        if pending.is_empty() {
+            eprintln!("No pending conflicts");
+        }
+        for meta in pending {
+            println!("{}  {}  {}  {}", meta.id, meta.path, meta.agent, meta.reason);
+        }
+        if id.is_some() {
+            eprintln!("Settle it with --take theirs|current|merged");
+        }
+        return;
+    };
+
+    match resolver.resolve(id, take, edit) {
+        Ok(meta) => eprintln!("Resolved {} ({}): took {}", id, meta.path, take.as_str()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
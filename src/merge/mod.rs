@@ -0,0 +1,104 @@
+pub mod structured;
+pub mod text;
+
+use std::path::Path;
+
+use thiserror::Error;
+
+use self::structured::Format;
+
+/// Largest file dibs will try to merge (1 MB).
+pub const MAX_MERGE_SIZE: usize = 1024 * 1024;
+
+/// Why an automatic merge was refused.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    #[error("not a text file")]
+    NotText,
+
+    #[error("file too large to merge")]
+    TooLarge,
+
+    #[error("overlapping changes at line {line}")]
+    Overlap { line: usize },
+
+    #[error("{key} changed on both sides")]
+    KeyConflict { key: String },
+
+    #[error("merging key by key would drop comments or anchors")]
+    Lossy,
+
+    #[error("parse error: {0}")]
+    Parse(String),
+}
+
+/// Whether `data` looks like text we can merge line by line.
+pub fn is_text(data: &[u8]) -> bool {
+    !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Three-way merge of a stale write.
+///
+/// `base` is what the writer last read, `current` is the file as it is now,
+/// and `incoming` is what the writer wants the file to be. Returns the file
+/// with both sets of changes applied, or an error if they overlap.
+///
+/// JSON and YAML files (by extension of `path`) are merged line by line
+/// first, which leaves everything neither side changed as it was; only
+/// changes too close together for that are merged key by key. TOML files are
+/// merged key by key in place. If one of the versions doesn't parse, they
+/// are merged line by line.
+pub fn merge(path: &Path, base: &[u8], current: &[u8], incoming: &[u8]) -> Result<Vec<u8>, MergeError> {
+    if [base, current, incoming].iter().any(|d| d.len() > MAX_MERGE_SIZE) {
+        return Err(MergeError::TooLarge);
+    }
+    let as_text = |data| std::str::from_utf8(data).ok().filter(|_| is_text(data));
+    let (Some(base), Some(current), Some(incoming)) = (as_text(base), as_text(current), as_text(incoming)) else {
+        return Err(MergeError::NotText);
+    };
+    let merged = match Format::from_path(path) {
+        Some(Format::Toml) => match structured::merge(Format::Toml, base, current, incoming) {
+            Err(MergeError::Parse(_)) => text::merge(base, current, incoming),
+            result => result,
+        },
+        Some(format) => match text::merge(base, current, incoming) {
+            Ok(merged) if structured::parses(format, &merged) => Ok(merged),
+            lines => match structured::merge(format, base, current, incoming) {
+                Err(MergeError::Parse(_)) => lines,
+                result => result,
+            },
+        },
+        None => text::merge(base, current, incoming),
+    };
+    merged.map(String::into_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// YAML changes a line merge can take keep the file as written
+    #[test]
+    fn test_yaml_line_merge_first() {
+        let base = "# CI\nbuild: &defaults\n  image: rust\ntest:\n  <<: *defaults\n";
+        let current = "# CI\nbuild: &defaults\n  image: rust:1.80\ntest:\n  <<: *defaults\n";
+        let incoming = "# CI\nbuild: &defaults\n  image: rust\ntest:\n  <<: *defaults\n  script: cargo test\n";
+
+        let merged = merge(Path::new("ci.yml"), base.as_bytes(), current.as_bytes(), incoming.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(merged).unwrap(),
+            "# CI\nbuild: &defaults\n  image: rust:1.80\ntest:\n  <<: *defaults\n  script: cargo test\n"
+        );
+    }
+
+    /// Changes on neighbouring lines still merge key by key
+    #[test]
+    fn test_json_falls_back_to_keys() {
+        let base = "{\n  \"a\": 1\n}\n";
+        let current = "{\n  \"a\": 1,\n  \"b\": 2\n}\n";
+        let incoming = "{\n  \"a\": 1,\n  \"c\": 3\n}\n";
+
+        let merged = merge(Path::new("x.json"), base.as_bytes(), current.as_bytes(), incoming.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(merged).unwrap(), "{\n  \"a\": 1,\n  \"b\": 2,\n  \"c\": 3\n}\n");
+    }
+}
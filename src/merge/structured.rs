@@ -0,0 +1,374 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use toml_edit::{DocumentMut, Item, Table};
+
+use super::MergeError;
+
+/// Structured formats merged key by key instead of line by line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Pick a format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Key-level three-way merge.
+///
+/// Keys added, changed or removed on only one side are applied. Nested
+/// tables/objects are merged recursively. A key changed differently on both
+/// sides is a conflict. Returns `MergeError::Parse` if any version doesn't
+/// parse, so the caller can fall back to a line merge.
+pub fn merge(format: Format, base: &str, current: &str, incoming: &str) -> Result<String, MergeError> {
+    match format {
+        Format::Toml => merge_toml(base, current, incoming),
+        Format::Json => {
+            let parse = |s: &str| serde_json::from_str::<Value>(s).map_err(|e| MergeError::Parse(e.to_string()));
+            let merged = merge_tree(parse(base)?, parse(current)?, parse(incoming)?, current, incoming)?;
+            merged.map_or_else(|v| to_json(&v, current), Ok)
+        }
+        Format::Yaml => {
+            let parse = |s: &str| serde_yaml_ng::from_str::<Value>(s).map_err(|e| MergeError::Parse(e.to_string()));
+            let merged = merge_tree(parse(base)?, parse(current)?, parse(incoming)?, current, incoming)?;
+            merged.or_else(|v| {
+                // Serializing the merged tree writes out what it means, not
+                // how either side wrote it
+                if yaml_has_markup(current) || yaml_has_markup(incoming) {
+                    return Err(MergeError::Lossy);
+                }
+                serde_yaml_ng::to_string(&v).map_err(|e| MergeError::Parse(e.to_string()))
+            })
+        }
+    }
+}
+
+/// Whether `text` is a valid document in `format`.
+pub fn parses(format: Format, text: &str) -> bool {
+    match format {
+        Format::Json => serde_json::from_str::<Value>(text).is_ok(),
+        Format::Toml => text.parse::<DocumentMut>().is_ok(),
+        Format::Yaml => serde_yaml_ng::from_str::<serde_yaml_ng::Value>(text).is_ok(),
+    }
+}
+
+/// Whether YAML `text` may hold comments, anchors or aliases, which don't
+/// survive parsing. Errs on the side of yes.
+fn yaml_has_markup(text: &str) -> bool {
+    text.lines().any(|line| {
+        line.trim_start().starts_with('#')
+            || line.contains(" #")
+            || line.split_whitespace().any(|token| token.starts_with('&') || token.starts_with('*'))
+    })
+}
+
+/// Merge parsed JSON/YAML documents. When the result is identical to one of
+/// the inputs, that input's text is returned as is (`Ok(Ok(text))`) so its
+/// formatting and comments survive; otherwise the merged value needs to be
+/// serialized (`Ok(Err(value))`).
+fn merge_tree(
+    base: Value,
+    current: Value,
+    incoming: Value,
+    current_text: &str,
+    incoming_text: &str,
+) -> Result<Result<String, Value>, MergeError> {
+    let merged = merge_value("", Some(&base), Some(&current), Some(&incoming))?.unwrap_or(Value::Null);
+    if merged == current {
+        Ok(Ok(current_text.to_string()))
+    } else if merged == incoming {
+        Ok(Ok(incoming_text.to_string()))
+    } else {
+        Ok(Err(merged))
+    }
+}
+
+/// Merge one key. `None` means the key is absent on that side.
+fn merge_value(
+    key: &str,
+    base: Option<&Value>,
+    current: Option<&Value>,
+    incoming: Option<&Value>,
+) -> Result<Option<Value>, MergeError> {
+    if incoming == base {
+        return Ok(current.cloned());
+    }
+    if current == base || current == incoming {
+        return Ok(incoming.cloned());
+    }
+    let empty = Map::new();
+    match (base, current, incoming) {
+        (Some(Value::Object(b)), Some(Value::Object(c)), Some(Value::Object(i))) => {
+            Ok(Some(Value::Object(merge_maps(key, b, c, i)?)))
+        }
+        // Both sides added the same object: merge against an empty one.
+        (None, Some(Value::Object(c)), Some(Value::Object(i))) => {
+            Ok(Some(Value::Object(merge_maps(key, &empty, c, i)?)))
+        }
+        _ => Err(MergeError::KeyConflict { key: display_key(key) }),
+    }
+}
+
+fn merge_maps(
+    prefix: &str,
+    base: &Map<String, Value>,
+    current: &Map<String, Value>,
+    incoming: &Map<String, Value>,
+) -> Result<Map<String, Value>, MergeError> {
+    let mut out = Map::new();
+    // Current's keys keep their order.
+    for (k, c) in current {
+        if let Some(v) = merge_value(&join_key(prefix, k), base.get(k), Some(c), incoming.get(k))? {
+            out.insert(k.clone(), v);
+        }
+    }
+    // Keys only incoming has go after the key that precedes them there, and
+    // after any keys current added at the same spot (as in the line merge).
+    let mut prev: Option<&String> = None;
+    for (k, i) in incoming {
+        if !current.contains_key(k) {
+            if let Some(v) = merge_value(&join_key(prefix, k), base.get(k), None, Some(i))? {
+                let mut pos = prev
+                    .and_then(|p| out.keys().position(|key| key == p))
+                    .map_or(out.len(), |p| p + 1);
+                let added_by_current = |key: &String| !base.contains_key(key) && !incoming.contains_key(key);
+                while out.keys().nth(pos).is_some_and(added_by_current) {
+                    pos += 1;
+                }
+                out.shift_insert(pos, k.clone(), v);
+            }
+        }
+        prev = Some(k);
+    }
+    Ok(out)
+}
+
+/// Pretty-print JSON with the indentation and trailing newline of `like`.
+fn to_json(value: &Value, like: &str) -> Result<String, MergeError> {
+    let indent = like
+        .lines()
+        .skip(1)
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .find(|ws| !ws.is_empty())
+        .unwrap_or("  ");
+    let mut out = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+    let mut ser = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut ser).map_err(|e| MergeError::Parse(e.to_string()))?;
+    if like.ends_with('\n') {
+        out.push(b'\n');
+    }
+    String::from_utf8(out).map_err(|e| MergeError::Parse(e.to_string()))
+}
+
+/// TOML is merged in place on current's document, so everything current
+/// didn't need to change keeps its formatting and comments.
+fn merge_toml(base: &str, current: &str, incoming: &str) -> Result<String, MergeError> {
+    let parse = |s: &str| s.parse::<DocumentMut>().map_err(|e| MergeError::Parse(e.to_string()));
+    let base = parse(base)?;
+    let mut merged = parse(current)?;
+    let incoming = parse(incoming)?;
+    merge_table("", base.as_table(), merged.as_table_mut(), incoming.as_table())?;
+    Ok(merged.to_string())
+}
+
+fn merge_table(prefix: &str, base: &Table, current: &mut Table, incoming: &Table) -> Result<(), MergeError> {
+    let mut keys: Vec<String> = Vec::new();
+    for (k, _) in current.iter().chain(incoming.iter()).chain(base.iter()) {
+        if !keys.iter().any(|key| key == k) {
+            keys.push(k.to_string());
+        }
+    }
+
+    for k in keys {
+        let key = join_key(prefix, &k);
+        let b = base.get(&k);
+        let i = incoming.get(&k);
+        if same_item(i, b) {
+            continue;
+        }
+        if same_item(current.get(&k), b) {
+            match i {
+                Some(item) => current.insert(&k, item.clone()),
+                None => current.remove(&k),
+            };
+            continue;
+        }
+        if same_item(current.get(&k), i) {
+            continue;
+        }
+        let empty = Table::new();
+        let base_table = match b {
+            Some(item) => item.as_table(),
+            None => Some(&empty),
+        };
+        match (base_table, current.get_mut(&k).and_then(Item::as_table_mut), i.and_then(Item::as_table)) {
+            (Some(b), Some(c), Some(i)) => merge_table(&key, b, c, i)?,
+            _ => return Err(MergeError::KeyConflict { key: display_key(&key) }),
+        }
+    }
+    Ok(())
+}
+
+/// Compare TOML items ignoring whitespace and comments around values.
+fn same_item(a: Option<&Item>, b: Option<&Item>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => canonical(a) == canonical(b),
+        _ => false,
+    }
+}
+
+fn canonical(item: &Item) -> String {
+    let mut item = item.clone();
+    match item {
+        Item::Value(ref mut v) => {
+            v.decor_mut().clear();
+            match v {
+                toml_edit::Value::Array(a) => a.fmt(),
+                toml_edit::Value::InlineTable(t) => t.fmt(),
+                _ => {}
+            }
+        }
+        Item::Table(ref mut t) => t.fmt(),
+        _ => {}
+    }
+    item.to_string()
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn display_key(key: &str) -> String {
+    if key.is_empty() {
+        "(root)".to_string()
+    } else {
+        key.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two agents adding different dependencies to package.json
+    #[test]
+    fn test_json_key_additions() {
+        let base = "{\n  \"name\": \"app\",\n  \"dependencies\": {\n    \"react\": \"^18\"\n  }\n}\n";
+        let current = "{\n  \"name\": \"app\",\n  \"dependencies\": {\n    \"react\": \"^18\",\n    \"zod\": \"^3\"\n  }\n}\n";
+        let incoming = "{\n  \"name\": \"app\",\n  \"scripts\": {\n    \"test\": \"vitest\"\n  },\n  \"dependencies\": {\n    \"axios\": \"^1\",\n    \"react\": \"^18\"\n  }\n}\n";
+
+        let merged = merge(Format::Json, base, current, incoming).unwrap();
+        assert_eq!(
+            merged,
+            "{\n  \"name\": \"app\",\n  \"scripts\": {\n    \"test\": \"vitest\"\n  },\n  \"dependencies\": {\n    \"react\": \"^18\",\n    \"zod\": \"^3\",\n    \"axios\": \"^1\"\n  }\n}\n"
+        );
+    }
+
+    /// The same key changed to different values is a conflict
+    #[test]
+    fn test_json_same_key_conflict() {
+        let base = r#"{"compilerOptions": {"target": "es2017"}}"#;
+        let current = r#"{"compilerOptions": {"target": "es2020"}}"#;
+        let incoming = r#"{"compilerOptions": {"target": "es2022"}}"#;
+        assert_eq!(
+            merge(Format::Json, base, current, incoming),
+            Err(MergeError::KeyConflict { key: "compilerOptions.target".to_string() })
+        );
+    }
+
+    /// Removal on one side and an unrelated change on the other
+    #[test]
+    fn test_json_removal() {
+        let base = r#"{"a": 1, "b": 2}"#;
+        let current = r#"{"a": 1}"#;
+        let incoming = r#"{"a": 1, "b": 2, "c": 3}"#;
+        let merged: Value = serde_json::from_str(&merge(Format::Json, base, current, incoming).unwrap()).unwrap();
+        assert_eq!(merged, serde_json::json!({"a": 1, "c": 3}));
+    }
+
+    /// TOML merges keep comments and layout of the current file
+    #[test]
+    fn test_toml_keeps_formatting() {
+        let base = "[package]\nname = \"app\"\n\n# runtime deps\n[dependencies]\nserde = \"1\"\n";
+        let current = "[package]\nname = \"app\"\n\n# runtime deps\n[dependencies]\nserde = \"1\"\ntokio = \"1\"   # async\n";
+        let incoming = "[package]\nname = \"app\"\nedition = \"2021\"\n\n# runtime deps\n[dependencies]\nserde = \"1\"\n";
+
+        let merged = merge(Format::Toml, base, current, incoming).unwrap();
+        assert_eq!(
+            merged,
+            "[package]\nname = \"app\"\nedition = \"2021\"\n\n# runtime deps\n[dependencies]\nserde = \"1\"\ntokio = \"1\"   # async\n"
+        );
+    }
+
+    /// Changing the same TOML key differently is a conflict, reformatting isn't a change
+    #[test]
+    fn test_toml_conflict_and_whitespace() {
+        let base = "[dependencies]\nserde = \"1\"\n";
+        let current = "[dependencies]\nserde = \"1.0.200\"\n";
+        let incoming = "[dependencies]\nserde = \"1.0.100\"\n";
+        assert_eq!(
+            merge(Format::Toml, base, current, incoming),
+            Err(MergeError::KeyConflict { key: "dependencies.serde".to_string() })
+        );
+
+        let reformatted = "[dependencies]\nserde   =   \"1\"\n";
+        assert_eq!(merge(Format::Toml, base, current, reformatted).unwrap(), current);
+    }
+
+    /// YAML keys added on both sides
+    #[test]
+    fn test_yaml_key_additions() {
+        let base = "services:\n  web:\n    image: nginx\n";
+        let current = "services:\n  web:\n    image: nginx\n  db:\n    image: postgres\n";
+        let incoming = "services:\n  web:\n    image: nginx\n    ports:\n    - 80:80\n";
+
+        let merged = merge(Format::Yaml, base, current, incoming).unwrap();
+        let merged: Value = serde_yaml_ng::from_str(&merged).unwrap();
+        assert_eq!(
+            merged,
+            serde_json::json!({"services": {"web": {"image": "nginx", "ports": ["80:80"]}, "db": {"image": "postgres"}}})
+        );
+    }
+
+    /// A YAML merge that would have to re-serialize a commented file is refused
+    #[test]
+    fn test_yaml_refuses_lossy_merge() {
+        let base = "services:\n  web:\n    image: nginx\n";
+        let current = "services:\n  # the frontend\n  web:\n    image: nginx\n  db:\n    image: postgres\n";
+        let incoming = "services:\n  web:\n    image: nginx\n    ports:\n    - 80:80\n";
+        assert_eq!(merge(Format::Yaml, base, current, incoming), Err(MergeError::Lossy));
+
+        let aliased = "services:\n  web: &web\n    image: nginx\n  admin: *web\n";
+        assert_eq!(merge(Format::Yaml, base, aliased, incoming), Err(MergeError::Lossy));
+    }
+
+    /// Unparseable input is reported so the caller can fall back
+    #[test]
+    fn test_parse_error() {
+        assert!(matches!(
+            merge(Format::Json, "{}", "{", "{}"),
+            Err(MergeError::Parse(_))
+        ));
+        assert_eq!(Format::from_path(Path::new("tsconfig.JSON")), Some(Format::Json));
+        assert_eq!(Format::from_path(Path::new("ci.yml")), Some(Format::Yaml));
+        assert_eq!(Format::from_path(Path::new("main.rs")), None);
+    }
+}
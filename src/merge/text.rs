@@ -0,0 +1,256 @@
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+use super::MergeError;
+
+/// A change to a range of base lines, relative to one side of the merge.
+#[derive(Debug, PartialEq, Eq)]
+struct Hunk<'a> {
+    /// First base line replaced.
+    start: usize,
+    /// One past the last base line replaced (== start for pure insertions).
+    end: usize,
+    /// Lines that replace `start..end`.
+    lines: &'a [&'a str],
+}
+
+impl Hunk<'_> {
+    fn is_insertion(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Whether two hunks touch the same base lines. Insertions only overlap
+    /// a change that spans their insertion point; two insertions never do.
+    fn overlaps(&self, other: &Hunk) -> bool {
+        match (self.is_insertion(), other.is_insertion()) {
+            (true, true) => false,
+            (true, false) => other.start < self.start && self.start < other.end,
+            (false, true) => self.start < other.start && other.start < self.end,
+            (false, false) => self.start < other.end && other.start < self.end,
+        }
+    }
+}
+
+/// Markers around the two versions of a region both sides changed.
+pub const MARKER_CURRENT: &str = "<<<<<<< current\n";
+pub const MARKER_SEPARATOR: &str = "=======\n";
+pub const MARKER_THEIRS: &str = ">>>>>>> theirs\n";
+
+/// Line-based three-way merge.
+///
+/// Changes on either side that don't touch the same base lines are combined.
+/// When both sides insert at the same point, the current side's lines come
+/// first. Identical changes on both sides are applied once.
+pub fn merge(base: &str, current: &str, incoming: &str) -> Result<String, MergeError> {
+    merge_lines(base, current, incoming, false)
+}
+
+/// Like `merge`, but instead of failing on overlapping changes, writes both
+/// versions of the region between conflict markers for a human to settle.
+pub fn merge_with_markers(base: &str, current: &str, incoming: &str) -> String {
+    merge_lines(base, current, incoming, true).expect("merge with markers never fails")
+}
+
+/// Whether `text` still contains conflict markers.
+pub fn has_markers(text: &str) -> bool {
+    text.split_inclusive('\n')
+        .any(|line| line == MARKER_CURRENT || line == MARKER_SEPARATOR || line == MARKER_THEIRS)
+}
+
+fn merge_lines(base: &str, current: &str, incoming: &str, markers: bool) -> Result<String, MergeError> {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let current_lines: Vec<&str> = current.split_inclusive('\n').collect();
+    let incoming_lines: Vec<&str> = incoming.split_inclusive('\n').collect();
+
+    let ours = hunks(&base_lines, &current_lines);
+    let theirs = hunks(&base_lines, &incoming_lines);
+
+    let mut out = String::with_capacity(current.len().max(incoming.len()));
+    let mut pos = 0;
+    let apply = |out: &mut String, hunk: &Hunk, pos: &mut usize| {
+        out.extend(base_lines[*pos..hunk.start].iter().copied());
+        out.extend(hunk.lines.iter().copied());
+        *pos = hunk.end;
+    };
+
+    let (mut i, mut j) = (0, 0);
+    loop {
+        match (ours.get(i), theirs.get(j)) {
+            (None, None) => break,
+            (Some(a), None) => {
+                apply(&mut out, a, &mut pos);
+                i += 1;
+            }
+            (None, Some(b)) => {
+                apply(&mut out, b, &mut pos);
+                j += 1;
+            }
+            (Some(a), Some(b)) => {
+                if a == b {
+                    apply(&mut out, a, &mut pos);
+                    i += 1;
+                    j += 1;
+                } else if a.overlaps(b) {
+                    if !markers {
+                        return Err(MergeError::Overlap { line: a.start.max(b.start) + 1 });
+                    }
+                    // Widen the region until no hunk on either side
+                    // straddles its end
+                    let start = a.start.min(b.start);
+                    let mut end = a.end.max(b.end);
+                    let (first_i, first_j) = (i, j);
+                    loop {
+                        if let Some(h) = ours.get(i).filter(|h| h.start < end) {
+                            end = end.max(h.end);
+                            i += 1;
+                        } else if let Some(h) = theirs.get(j).filter(|h| h.start < end) {
+                            end = end.max(h.end);
+                            j += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    out.extend(base_lines[pos..start].iter().copied());
+                    let region = &base_lines[start..end];
+                    out.push_str(MARKER_CURRENT);
+                    push_region(&mut out, region, start, &ours[first_i..i]);
+                    out.push_str(MARKER_SEPARATOR);
+                    push_region(&mut out, region, start, &theirs[first_j..j]);
+                    out.push_str(MARKER_THEIRS);
+                    pos = end;
+                } else if a.start < b.start || (a.start == b.start && a.is_insertion()) {
+                    apply(&mut out, a, &mut pos);
+                    i += 1;
+                } else {
+                    apply(&mut out, b, &mut pos);
+                    j += 1;
+                }
+            }
+        }
+    }
+    out.extend(base_lines[pos..].iter().copied());
+    Ok(out)
+}
+
+/// One side's version of the base lines `region` (starting at base line
+/// `offset`), ending in a newline so a marker can follow.
+fn push_region(out: &mut String, region: &[&str], offset: usize, hunks: &[Hunk]) {
+    let mut pos = 0;
+    for hunk in hunks {
+        out.extend(region[pos..hunk.start - offset].iter().copied());
+        out.extend(hunk.lines.iter().copied());
+        pos = hunk.end - offset;
+    }
+    out.extend(region[pos..].iter().copied());
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// Changes that turn `base` into `new`, with adjacent edits grouped into one hunk.
+fn hunks<'a>(base: &[&str], new: &'a [&'a str]) -> Vec<Hunk<'a>> {
+    let mut hunks: Vec<(usize, usize, usize, usize)> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, base, new) {
+        let (tag, old, new_range) = op.as_tag_tuple();
+        if tag == DiffTag::Equal {
+            continue;
+        }
+        match hunks.last_mut() {
+            Some(last) if last.1 == old.start && last.3 == new_range.start => {
+                last.1 = old.end;
+                last.3 = new_range.end;
+            }
+            _ => hunks.push((old.start, old.end, new_range.start, new_range.end)),
+        }
+    }
+    hunks
+        .into_iter()
+        .map(|(start, end, new_start, new_end)| Hunk {
+            start,
+            end,
+            lines: &new[new_start..new_end],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "fn a() {}\n\nfn b() {}\n\nfn c() {}\n";
+
+    /// Edits to different lines are both kept
+    #[test]
+    fn test_disjoint_edits_merge() {
+        let current = "fn a() { 1 }\n\nfn b() {}\n\nfn c() {}\n";
+        let incoming = "fn a() {}\n\nfn b() {}\n\nfn c() { 3 }\n";
+        assert_eq!(
+            merge(BASE, current, incoming).unwrap(),
+            "fn a() { 1 }\n\nfn b() {}\n\nfn c() { 3 }\n"
+        );
+    }
+
+    /// Two agents appending different functions at the end of the file
+    #[test]
+    fn test_both_append() {
+        let current = format!("{}\nfn from_a() {{}}\n", BASE);
+        let incoming = format!("{}\nfn from_b() {{}}\n", BASE);
+        assert_eq!(
+            merge(BASE, &current, &incoming).unwrap(),
+            format!("{}\nfn from_a() {{}}\n\nfn from_b() {{}}\n", BASE)
+        );
+    }
+
+    /// The same change on both sides is applied once
+    #[test]
+    fn test_identical_changes() {
+        let changed = "fn a() {}\n\nfn b() { 2 }\n\nfn c() {}\n";
+        assert_eq!(merge(BASE, changed, changed).unwrap(), changed);
+    }
+
+    /// Different changes to the same line are refused
+    #[test]
+    fn test_overlapping_edits_conflict() {
+        let current = "fn a() {}\n\nfn b() { 1 }\n\nfn c() {}\n";
+        let incoming = "fn a() {}\n\nfn b() { 2 }\n\nfn c() {}\n";
+        assert_eq!(merge(BASE, current, incoming), Err(MergeError::Overlap { line: 3 }));
+    }
+
+    /// An insertion inside a range the other side replaced is refused
+    #[test]
+    fn test_insertion_inside_change_conflicts() {
+        let current = "fn a() {}\nfn replaced() {}\nfn c() {}\n";
+        let incoming = "fn a() {}\n\nfn b() {}\nfn inserted() {}\n\nfn c() {}\n";
+        assert!(merge(BASE, current, incoming).is_err());
+    }
+
+    /// Deletion on one side, unrelated edit on the other
+    #[test]
+    fn test_delete_and_edit() {
+        let current = "fn a() {}\n\nfn c() {}\n";
+        let incoming = "fn a() {}\n\nfn b() {}\n\nfn c() { 3 }\n";
+        assert_eq!(merge(BASE, current, incoming).unwrap(), "fn a() {}\n\nfn c() { 3 }\n");
+    }
+
+    /// Overlapping changes end up between markers, the rest is merged
+    #[test]
+    fn test_markers_around_overlap() {
+        let current = "fn a() { 1 }\n\nfn b() { 1 }\n\nfn c() {}\n";
+        let incoming = "fn a() {}\n\nfn b() { 2 }\n\nfn c() { 3 }\n";
+        let merged = merge_with_markers(BASE, current, incoming);
+        assert_eq!(
+            merged,
+            "fn a() { 1 }\n\n<<<<<<< current\nfn b() { 1 }\n=======\nfn b() { 2 }\n>>>>>>> theirs\n\nfn c() { 3 }\n"
+        );
+        assert!(has_markers(&merged));
+        assert!(!has_markers(&merge_with_markers(BASE, current, current)));
+    }
+
+    /// Binary content is never merged
+    #[test]
+    fn test_binary_not_merged() {
+        assert_eq!(
+            crate::merge::merge(std::path::Path::new("f.bin"), b"a\0", b"b\0", b"c\0"),
+            Err(MergeError::NotText)
+        );
+    }
+}
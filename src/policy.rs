@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use globset::GlobMatcher;
+
+use crate::config::{PathPolicy, PolicyKind};
+
+/// Which policies apply to which paths: `--strict` for every path, plus the
+/// `--policy GLOB=POLICY` rules. A path can fall under several policies.
+pub struct Policies {
+    strict: bool,
+    rules: Vec<(GlobMatcher, PolicyKind)>,
+}
+
+impl Policies {
+    pub fn new(strict: bool, rules: &[PathPolicy]) -> Self {
+        Self {
+            strict,
+            rules: rules.iter().map(|rule| (rule.glob.compile_matcher(), rule.kind)).collect(),
+        }
+    }
+
+    /// Whether `kind` applies to `path`, relative to the backing directory.
+    pub fn applies(&self, kind: PolicyKind, path: &Path) -> bool {
+        if kind == PolicyKind::Strict && self.strict {
+            return true;
+        }
+        self.rules.iter().any(|(glob, k)| *k == kind && glob.is_match(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Cli, Command};
+    use clap::Parser;
+
+    fn policies(args: &[&str]) -> Policies {
+        let cli = Cli::try_parse_from(["dibs", "mount", "backing", "mnt"].iter().chain(args)).unwrap();
+        match cli.command {
+            Command::Mount { strict, policies, .. } => Policies::new(strict, &policies),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_glob_without_slash_matches_any_depth() {
+        let p = policies(&["--policy", "*.md=strict"]);
+        assert!(p.applies(PolicyKind::Strict, Path::new("README.md")));
+        assert!(p.applies(PolicyKind::Strict, Path::new("docs/guide/intro.md")));
+        assert!(!p.applies(PolicyKind::Strict, Path::new("src/main.rs")));
+    }
+
+    #[test]
+    fn test_glob_with_slash_matches_from_root() {
+        let p = policies(&["--policy", "src/*.rs=strict"]);
+        assert!(p.applies(PolicyKind::Strict, Path::new("src/main.rs")));
+        assert!(!p.applies(PolicyKind::Strict, Path::new("src/fs/mod.rs")));
+        assert!(!p.applies(PolicyKind::Strict, Path::new("vendor/src/lib.rs")));
+
+        let p = policies(&["--policy", "/src/**=strict"]);
+        assert!(p.applies(PolicyKind::Strict, Path::new("src/fs/mod.rs")));
+    }
+
+    #[test]
+    fn test_strict_everywhere() {
+        let p = policies(&["--strict"]);
+        assert!(p.applies(PolicyKind::Strict, Path::new("any/path")));
+        assert!(!policies(&[]).applies(PolicyKind::Strict, Path::new("any/path")));
+    }
+
+    #[test]
+    fn test_invalid_policy() {
+        for arg in ["*.md", "=strict", "*.md=bogus", "a[=strict"] {
+            let args = ["dibs", "mount", "backing", "mnt", "--policy", arg];
+            assert!(Cli::try_parse_from(args).is_err(), "{}", arg);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+/// `dibs resolve`: settle saved conflicts through a running mount.
+///
+/// The chosen content is written through the mount like any other edit, so
+/// it goes through the CAS check and every session's view stays consistent.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::config::Take;
+use crate::fs::virtual_dir::{DIBS_DIR_NAME, DIBS_OBJECTS_NAME, DIBS_STATUS_NAME};
+use crate::fs::CONFLICT_STORE_NAME;
+use crate::merge::{self, text, MergeError};
+use crate::state::conflicts::{ConflictMeta, ConflictStore, REJECTED_NAME};
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0} is not a dibs mount")]
+    NotMounted(PathBuf),
+
+    #[error("no saved conflict {0}")]
+    NotFound(String),
+
+    #[error("conflict {0} is already resolved")]
+    AlreadyResolved(String),
+
+    #[error("the version the rejected writer read is no longer held; use --edit to merge by hand")]
+    BaseUnavailable,
+
+    #[error("{0}; use --edit to merge by hand")]
+    Merge(#[from] MergeError),
+
+    #[error("editor failed: {0}")]
+    Editor(String),
+
+    #[error("conflict markers left in {0}; nothing was written")]
+    UnresolvedMarkers(PathBuf),
+
+    #[error("{0} changed while resolving; run dibs resolve again")]
+    Changed(String),
+}
+
+/// A running mount and its conflict registry.
+pub struct Resolver {
+    mountpoint: PathBuf,
+    store: Option<ConflictStore>,
+}
+
+impl Resolver {
+    /// Find the conflict registry behind the mount at `mountpoint`.
+    pub fn open(mountpoint: &Path) -> Result<Self, ResolveError> {
+        let status_path = mountpoint.join(DIBS_DIR_NAME).join(DIBS_STATUS_NAME);
+        let status: serde_json::Value = std::fs::read(&status_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .ok_or_else(|| ResolveError::NotMounted(mountpoint.to_path_buf()))?;
+        let backing = status["backing"]
+            .as_str()
+            .map(PathBuf::from)
+            .ok_or_else(|| ResolveError::NotMounted(mountpoint.to_path_buf()))?;
+
+        // Without --save-conflicts there is nothing to resolve. Opened without
+        // a retention policy: pruning is the daemon's job.
+        let root = backing.join(CONFLICT_STORE_NAME);
+        let store = if root.is_dir() {
+            Some(ConflictStore::open(root, 0, 0)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            mountpoint: mountpoint.to_path_buf(),
+            store,
+        })
+    }
+
+    /// Saved conflicts that haven't been resolved, oldest first.
+    pub fn pending(&self) -> Vec<ConflictMeta> {
+        let Some(store) = &self.store else {
+            return Vec::new();
+        };
+        store
+            .list()
+            .iter()
+            .filter_map(|id| store.meta(id))
+            .filter(|m| m.resolved.is_none())
+            .collect()
+    }
+
+    /// Settle conflict `id` by writing the `take` version of the file through
+    /// the mount, then mark it resolved.
+    pub fn resolve(&self, id: &str, take: Take, edit: bool) -> Result<ConflictMeta, ResolveError> {
+        let store = self.store.as_ref().ok_or_else(|| ResolveError::NotFound(id.to_string()))?;
+        let meta = store.meta(id).ok_or_else(|| ResolveError::NotFound(id.to_string()))?;
+        if meta.resolved.is_some() {
+            return Err(ResolveError::AlreadyResolved(id.to_string()));
+        }
+
+        // Reading through the mount records what this session saw, so the
+        // write below is rejected if the file changes in the meantime.
+        let mount_file = self.mountpoint.join(&meta.path);
+        let current = match std::fs::read(&mount_file) {
+            Ok(data) => Some(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let theirs = store.read_part(id, REJECTED_NAME)?;
+
+        let result = match take {
+            Take::Theirs => Some(theirs),
+            Take::Current => current.clone(),
+            Take::Merged => Some(self.merged(&meta, current.as_deref().unwrap_or_default(), &theirs, edit)?),
+        };
+        let result = match (result, edit) {
+            (Some(content), true) => Some(edit_file(id, &meta.path, &content)?),
+            (result, _) => result,
+        };
+
+        if let Some(content) = result.filter(|c| Some(c) != current.as_ref()) {
+            std::fs::write(&mount_file, content).map_err(|e| match e.raw_os_error() {
+                Some(libc::EIO) => ResolveError::Changed(meta.path.clone()),
+                _ => ResolveError::Io(e),
+            })?;
+        }
+        store.mark_resolved(id, take.as_str())?;
+        Ok(meta)
+    }
+
+    /// Three-way merge of the rejected write with the current file, against
+    /// the version the rejected writer had read. With `edit`, overlapping
+    /// changes are kept between conflict markers instead of failing.
+    fn merged(&self, meta: &ConflictMeta, current: &[u8], theirs: &[u8], edit: bool) -> Result<Vec<u8>, ResolveError> {
+        let base = meta.reader_hash.as_ref().and_then(|hash| {
+            std::fs::read(self.mountpoint.join(DIBS_DIR_NAME).join(DIBS_OBJECTS_NAME).join(hash)).ok()
+        });
+        let Some(base) = base else {
+            if !edit {
+                return Err(ResolveError::BaseUnavailable);
+            }
+            return Ok(whole_file_markers(current, theirs));
+        };
+        match merge::merge(Path::new(&meta.path), &base, current, theirs) {
+            Err(MergeError::Overlap { .. } | MergeError::KeyConflict { .. }) if edit => {
+                let as_str = |data| String::from_utf8_lossy(data).into_owned();
+                Ok(text::merge_with_markers(&as_str(&base), &as_str(current), &as_str(theirs)).into_bytes())
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Both versions in full between conflict markers, for when there's no
+/// common base to merge against.
+fn whole_file_markers(current: &[u8], theirs: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(current.len() + theirs.len() + 64);
+    let mut push_side = |marker: &str, side: &[u8]| {
+        out.extend_from_slice(marker.as_bytes());
+        out.extend_from_slice(side);
+        if !side.is_empty() && !side.ends_with(b"\n") {
+            out.push(b'\n');
+        }
+    };
+    push_side(text::MARKER_CURRENT, current);
+    push_side(text::MARKER_SEPARATOR, theirs);
+    out.extend_from_slice(text::MARKER_THEIRS.as_bytes());
+    out
+}
+
+/// Let the user edit `content` in $EDITOR (default vi) and return the result.
+/// Refuses content that still has conflict markers.
+fn edit_file(id: &str, path: &str, content: &[u8]) -> Result<Vec<u8>, ResolveError> {
+    let name = Path::new(path).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = std::env::temp_dir().join(format!("dibs-resolve-{}-{}", id, name));
+    std::fs::write(&tmp, content)?;
+
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or_else(|| ResolveError::Editor("EDITOR is empty".to_string()))?;
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(&tmp)
+        .status()
+        .map_err(|e| ResolveError::Editor(format!("{}: {}", program, e)))?;
+    if !status.success() {
+        return Err(ResolveError::Editor(format!("{} exited with {}", program, status)));
+    }
+
+    let edited = std::fs::read(&tmp)?;
+    if text::has_markers(&String::from_utf8_lossy(&edited)) {
+        return Err(ResolveError::UnresolvedMarkers(tmp));
+    }
+    let _ = std::fs::remove_file(&tmp);
+    Ok(edited)
+}
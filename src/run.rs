@@ -0,0 +1,357 @@
+/// `dibs run`: start an agent in a session of its own and name it.
+///
+/// The daemon tells agents apart by session, so every `dibs run` is a separate
+/// agent even when started from the same terminal. The name is registered
+/// through `.dibs/names` and only changes how the agent is shown.
+///
+/// With `--isolate` (Linux), the agent also gets its own user, mount and PID
+/// namespaces in which the mount is bound over the backing directory, so the
+/// project path leads through dibs and the backing files can't be reached.
+#[cfg(target_os = "linux")]
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::fs::virtual_dir::{DIBS_DIR_NAME, DIBS_NAMES_NAME, DIBS_STATUS_NAME};
+use crate::identity::session_alive;
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0} is not a dibs mount")]
+    NotMounted(PathBuf),
+
+    #[error("names can't contain line breaks")]
+    InvalidName,
+
+    #[error("no command to run")]
+    NoCommand,
+
+    #[error("failed to start {0}: {1}")]
+    Spawn(String, io::Error),
+
+    #[error("failed to isolate {0}: {1} (are unprivileged user namespaces enabled?)")]
+    Isolate(String, io::Error),
+
+    #[error("--isolate needs Linux namespaces; on macOS, use sandbash")]
+    IsolateUnsupported,
+}
+
+/// Process group of the running agent, for forwarding signals.
+static AGENT_PGID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    let pgid = AGENT_PGID.load(Ordering::Relaxed);
+    if pgid > 0 {
+        unsafe {
+            libc::kill(-pgid, sig);
+        }
+    }
+}
+
+/// Run `command` in a new session with its working directory inside the
+/// mount, registered as `name` until every process in the session has
+/// exited. With `isolate`, the mount takes the backing directory's place.
+/// Returns the command's exit code.
+pub fn run(mountpoint: &Path, name: &str, command: &[String], isolate: bool) -> Result<i32, RunError> {
+    if name.contains(['\n', '\r']) {
+        return Err(RunError::InvalidName);
+    }
+    let mountpoint = std::fs::canonicalize(mountpoint)?;
+    let backing = backing_dir(&mountpoint).ok_or_else(|| RunError::NotMounted(mountpoint.clone()))?;
+    let (program, args) = command.split_first().ok_or(RunError::NoCommand)?;
+    let cwd = working_dir(&mountpoint, &backing);
+    let isolation = if isolate {
+        Some(Isolation::new(&mountpoint, &backing, &cwd)?)
+    } else {
+        None
+    };
+
+    // The agent waits to exec until its name is registered, so that nothing
+    // it does is logged or reported under the bare session ID. The new
+    // session's ID is the child's PID, which `spawn` only returns after the
+    // exec, so the child sends it over and a thread registers the name.
+    let names = mountpoint.join(DIBS_DIR_NAME).join(DIBS_NAMES_NAME);
+    let (mut sid_reader, sid_writer) = io::pipe()?;
+    let (go_reader, mut go_writer) = io::pipe()?;
+    let registrar = {
+        let names = names.clone();
+        let name = name.to_string();
+        std::thread::spawn(move || {
+            let mut sid = [0u8; 4];
+            sid_reader.read_exact(&mut sid).ok()?;
+            let sid = u32::from_ne_bytes(sid);
+            let registered = send(&names, &format!("{} {}\n", sid, name));
+            let _ = go_writer.write_all(b"\n");
+            Some(registered)
+        })
+    };
+    let (sid_fd, go_fd) = (sid_writer.as_raw_fd(), go_reader.as_raw_fd());
+
+    let spawned = unsafe {
+        std::process::Command::new(program)
+            .args(args)
+            .current_dir(&cwd)
+            .pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let sid = libc::getpid().to_ne_bytes();
+                if libc::write(sid_fd, sid.as_ptr() as *const libc::c_void, sid.len()) == sid.len() as isize {
+                    let mut go = 0u8;
+                    while libc::read(go_fd, &mut go as *mut u8 as *mut libc::c_void, 1) < 0
+                        && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                    {}
+                }
+                match &isolation {
+                    Some(isolation) => isolation.enter(),
+                    None => Ok(()),
+                }
+            })
+            .spawn()
+    };
+    // A child that failed before sending its session ID never will
+    drop(sid_writer);
+    drop(go_reader);
+    let registered = registrar.join().ok().flatten();
+    let mut child = spawned.map_err(|e| {
+        if isolate && e.kind() != io::ErrorKind::NotFound {
+            RunError::Isolate(program.clone(), e)
+        } else {
+            RunError::Spawn(program.clone(), e)
+        }
+    })?;
+    if let Some(Err(e)) = registered {
+        eprintln!("dibs: couldn't register {}: {}", name, e);
+    }
+
+    if isolate {
+        // Don't leave a way into the backing directory open in this process
+        std::env::set_current_dir("/")?;
+    }
+
+    // Without a controlling terminal the agent no longer gets ctrl-C from
+    // the tty, so pass it on.
+    let sid = child.id();
+    AGENT_PGID.store(sid as i32, Ordering::Relaxed);
+    unsafe {
+        use nix::sys::signal::{signal, SigHandler, Signal};
+        for sig in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+            signal(sig, SigHandler::Handler(forward_signal)).ok();
+        }
+    }
+
+    let status = child.wait()?;
+    if session_alive(sid) {
+        eprintln!("dibs: waiting for processes left in {}'s session to exit...", name);
+        while session_alive(sid) {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    }
+    AGENT_PGID.store(0, Ordering::Relaxed);
+
+    if let Err(e) = send(&names, &format!("{}\n", sid)) {
+        eprintln!("dibs: couldn't unregister {}: {}", name, e);
+    }
+
+    Ok(status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
+
+/// The backing directory of the mount at `mountpoint`, from `.dibs/status`.
+fn backing_dir(mountpoint: &Path) -> Option<PathBuf> {
+    let data = std::fs::read(mountpoint.join(DIBS_DIR_NAME).join(DIBS_STATUS_NAME)).ok()?;
+    let status: serde_json::Value = serde_json::from_slice(&data).ok()?;
+    status["backing"].as_str().map(PathBuf::from)
+}
+
+/// Where the agent starts: the current directory if it's already in the
+/// mount, its counterpart in the mount if it's in the backing directory,
+/// otherwise the mount's root.
+fn working_dir(mountpoint: &Path, backing: &Path) -> PathBuf {
+    let Ok(cwd) = std::env::current_dir() else {
+        return mountpoint.to_path_buf();
+    };
+    if cwd.starts_with(mountpoint) {
+        return cwd;
+    }
+    match cwd.strip_prefix(backing) {
+        Ok(rel) => mountpoint.join(rel),
+        Err(_) => mountpoint.to_path_buf(),
+    }
+}
+
+/// What the child needs to isolate itself, prepared before the fork: nothing
+/// may be allocated between fork and exec.
+#[cfg(target_os = "linux")]
+struct Isolation {
+    mountpoint: CString,
+    backing: CString,
+    /// The working directory, as seen once the mount covers the backing path.
+    cwd: CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl Isolation {
+    fn new(mountpoint: &Path, backing: &Path, cwd: &Path) -> Result<Self, RunError> {
+        let cwd = match cwd.strip_prefix(mountpoint) {
+            Ok(rel) => backing.join(rel),
+            Err(_) => backing.to_path_buf(),
+        };
+        let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(io::Error::from);
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Self {
+            mountpoint: c_path(mountpoint)?,
+            backing: c_path(backing)?,
+            cwd: c_path(&cwd)?,
+            // The agent keeps its own IDs inside the namespace
+            uid_map: format!("{} {} 1\n", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1\n", gid, gid).into_bytes(),
+        })
+    }
+
+    /// Enter new user, mount and PID namespaces and bind the mount over the
+    /// backing directory. Runs in the child between fork and exec.
+    ///
+    /// The child forks twice more: it stays outside the PID namespace as the
+    /// process `dibs run` waits on, its child is the namespace's init, and
+    /// only the init's child returns here to exec the agent.
+    fn enter(&self) -> io::Result<()> {
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID))?;
+            self.map_ids()?;
+            // Keep the bind mount from propagating back to the host
+            check(libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            check(libc::mount(
+                self.mountpoint.as_ptr(),
+                self.backing.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                std::ptr::null(),
+            ))?;
+
+            let init = check(libc::fork())?;
+            if init > 0 {
+                // Forwarded signals are for the agent
+                for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+                    libc::signal(sig, libc::SIG_IGN);
+                }
+                reap(init);
+            }
+            // A /proc of our own, in which no process outside the namespace
+            // can be found: `dibs run`'s and the daemon's working directories
+            // and descriptors lead into the backing directory
+            check(libc::mount(
+                c"proc".as_ptr(),
+                c"/proc".as_ptr(),
+                c"proc".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                std::ptr::null(),
+            ))?;
+            // Mounts made in a more privileged user namespace are locked in
+            // place, so the agent can't unmount the bind mount or /proc
+            check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+            self.map_ids()?;
+            check(libc::chdir(self.cwd.as_ptr()))?;
+
+            let agent = check(libc::fork())?;
+            if agent > 0 {
+                reap(agent);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the agent's IDs to themselves in a namespace just entered.
+    unsafe fn map_ids(&self) -> io::Result<()> {
+        // Unprivileged processes may only map their own IDs, and only after
+        // giving up setgroups
+        write_proc_file(c"/proc/self/setgroups", b"deny")?;
+        write_proc_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_proc_file(c"/proc/self/gid_map", &self.gid_map)
+    }
+}
+
+/// Wait out every child until none is left, then exit as `main` did. As a
+/// PID namespace's init, this also reaps whatever the agent left running.
+#[cfg(target_os = "linux")]
+unsafe fn reap(main: libc::pid_t) -> ! {
+    // Let `spawn` in `dibs run` see only the agent's exec, not this process
+    libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+    let mut code = 0;
+    loop {
+        let mut status = 0;
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+        if pid == main {
+            code = if libc::WIFEXITED(status) {
+                libc::WEXITSTATUS(status)
+            } else {
+                128 + libc::WTERMSIG(status)
+            };
+        }
+    }
+    libc::_exit(code)
+}
+
+/// Write `data` to a /proc file with raw syscalls only.
+#[cfg(target_os = "linux")]
+unsafe fn write_proc_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY))?;
+    let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    libc::close(fd);
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Isolation;
+
+#[cfg(not(target_os = "linux"))]
+impl Isolation {
+    fn new(_mountpoint: &Path, _backing: &Path, _cwd: &Path) -> Result<Self, RunError> {
+        Err(RunError::IsolateUnsupported)
+    }
+
+    fn enter(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write one batch of commands to `.dibs/names`.
+fn send(names: &Path, commands: &str) -> io::Result<()> {
+    std::fs::OpenOptions::new().write(true).open(names)?.write_all(commands.as_bytes())
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::fs::backing::Dir;
+use crate::fs::cas;
+use crate::identity::AgentId;
+
+/// File names inside each conflict entry.
+pub const REJECTED_NAME: &str = "rejected";
+pub const CURRENT_NAME: &str = "current";
+pub const META_NAME: &str = "meta.json";
+pub const ENTRY_FILES: [&str; 3] = [REJECTED_NAME, CURRENT_NAME, META_NAME];
+
+/// Why a write was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The file changed since the agent last read it, or was written
+    /// since its receipt was evicted.
+    Stale,
+    /// Another handle holds the file's write ownership.
+    Owned,
+    /// The file was created since the agent saw the path missing.
+    Appeared,
+    /// The file's policy is strict and the agent never read it.
+    NeverRead,
+}
+
+/// A rejected write: why, and a description for logs and reports.
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub kind: Rejection,
+    pub reason: String,
+}
+
+impl Rejected {
+    pub fn new(kind: Rejection, reason: String) -> Self {
+        Self { kind, reason }
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+/// What dibs knew about a write at the moment it was rejected.
+#[derive(Debug, Clone)]
+pub struct ConflictInfo {
+    /// Path relative to backing root.
+    pub path: PathBuf,
+    /// Agent that attempted the write.
+    pub agent: AgentId,
+    /// Hash the agent last saw, if it had read the file.
+    pub reader_hash: Option<Vec<u8>>,
+    /// Hash of the backing file when the write was rejected.
+    pub actual_hash: Option<Vec<u8>>,
+    /// Why the write was rejected.
+    pub kind: Rejection,
+    /// Human-readable rejection reason.
+    pub reason: String,
+}
+
+/// Contents of `meta.json` for a saved conflict.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictMeta {
+    pub id: String,
+    pub path: String,
+    #[serde(alias = "session")]
+    pub agent: AgentId,
+    pub reader_hash: Option<String>,
+    pub actual_hash: Option<String>,
+    pub time: String,
+    pub reason: String,
+    /// Set once the conflict has been settled with `dibs resolve`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<Resolution>,
+}
+
+/// How and when a saved conflict was settled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resolution {
+    /// Which version was kept: theirs, current or merged.
+    pub take: String,
+    pub time: String,
+}
+
+/// On-disk registry of rejected writes, one directory per conflict:
+///
+/// ```text
+/// <root>/<id>/rejected    the data the rejected writer produced
+/// <root>/<id>/current     the backing file's content at rejection time
+/// <root>/<id>/meta.json   ConflictMeta
+/// ```
+///
+/// IDs start with a UTC timestamp, so sorting them sorts by age.
+pub struct ConflictStore {
+    root: Dir,
+    /// Keep at most this many conflicts (0 = unlimited).
+    max_entries: usize,
+    /// Drop conflicts older than this (None = keep forever).
+    max_age: Option<chrono::Duration>,
+    next_seq: AtomicU64,
+    /// Serializes saves against pruning.
+    lock: Mutex<()>,
+}
+
+impl ConflictStore {
+    /// Open (creating if needed) the conflict store at `root`.
+    pub fn open(root: PathBuf, max_entries: usize, max_age_hours: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        Self::with_root(Dir::open(&root)?, max_entries, max_age_hours)
+    }
+
+    /// Open (creating if needed) the conflict store at `rel` in `parent`.
+    pub fn open_in(parent: &Dir, rel: &Path, max_entries: usize, max_age_hours: u64) -> io::Result<Self> {
+        parent.create_dir_all(rel)?;
+        Self::with_root(parent.open_dir(rel)?, max_entries, max_age_hours)
+    }
+
+    fn with_root(root: Dir, max_entries: usize, max_age_hours: u64) -> io::Result<Self> {
+        let store = Self {
+            root,
+            max_entries,
+            max_age: (max_age_hours > 0).then(|| chrono::Duration::hours(max_age_hours as i64)),
+            next_seq: AtomicU64::new(1),
+            lock: Mutex::new(()),
+        };
+        store.prune();
+        Ok(store)
+    }
+
+    /// Save a rejected write. `current` is the backing file's content at
+    /// rejection time, if it could be read. Returns the new conflict ID.
+    pub fn save(&self, info: &ConflictInfo, rejected: &[u8], current: Option<&[u8]>) -> io::Result<String> {
+        let _guard = self.lock.lock();
+        let now = Utc::now();
+        let (id, dir) = self.create_entry_dir(&now)?;
+
+        let meta = ConflictMeta {
+            id: id.clone(),
+            path: info.path.display().to_string(),
+            agent: info.agent.clone(),
+            reader_hash: info.reader_hash.as_deref().map(cas::hash_hex),
+            actual_hash: info.actual_hash.as_deref().map(cas::hash_hex),
+            time: now.to_rfc3339(),
+            reason: info.reason.clone(),
+            resolved: None,
+        };
+
+        self.root.write(&dir.join(REJECTED_NAME), rejected)?;
+        self.root.write(&dir.join(CURRENT_NAME), current.unwrap_or_default())?;
+        let json = serde_json::to_string_pretty(&meta).map_err(io::Error::other)?;
+        self.root.write(&dir.join(META_NAME), json.as_bytes())?;
+
+        self.prune_locked();
+        Ok(id)
+    }
+
+    /// Allocate a fresh entry directory named `<timestamp>-<seq>`.
+    fn create_entry_dir(&self, now: &DateTime<Utc>) -> io::Result<(String, PathBuf)> {
+        let ts = now.format("%Y%m%d-%H%M%S-%3f");
+        loop {
+            let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+            let id = format!("{}-{:04}", ts, seq % 10000);
+            let dir = PathBuf::from(&id);
+            match self.root.mkdir(&dir, 0o777) {
+                Ok(()) => return Ok((id, dir)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// IDs of all saved conflicts, oldest first.
+    pub fn list(&self) -> Vec<String> {
+        let mut ids: Vec<String> = match self.root.read_dir(Path::new("")) {
+            Ok(names) => names
+                .into_iter()
+                .filter(|name| self.root.is_file(&Path::new(name).join(META_NAME)))
+                .map(|name| name.to_string_lossy().to_string())
+                .filter(|name| !name.starts_with('.'))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        ids.sort();
+        ids
+    }
+
+    /// Whether a conflict with this ID exists.
+    pub fn contains(&self, id: &str) -> bool {
+        Self::is_valid_id(id) && self.root.is_file(&Path::new(id).join(META_NAME))
+    }
+
+    /// Parsed metadata for a conflict.
+    pub fn meta(&self, id: &str) -> Option<ConflictMeta> {
+        let data = self.read_part(id, META_NAME).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Record that a conflict was settled by keeping `take`.
+    pub fn mark_resolved(&self, id: &str, take: &str) -> io::Result<()> {
+        let _guard = self.lock.lock();
+        let mut meta = self.meta(id).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        meta.resolved = Some(Resolution {
+            take: take.to_string(),
+            time: Utc::now().to_rfc3339(),
+        });
+        let json = serde_json::to_string_pretty(&meta).map_err(io::Error::other)?;
+        let dir = Path::new(id);
+        let tmp = dir.join(format!(".{}.tmp", META_NAME));
+        self.root.write(&tmp, json.as_bytes())?;
+        self.root.rename(&tmp, &dir.join(META_NAME))
+    }
+
+    /// Read one of the files of a conflict entry.
+    pub fn read_part(&self, id: &str, part: &str) -> io::Result<Vec<u8>> {
+        if !Self::is_valid_id(id) || !ENTRY_FILES.contains(&part) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.root.read(&Path::new(id).join(part))
+    }
+
+    /// IDs are single path components that don't start with a dot.
+    fn is_valid_id(id: &str) -> bool {
+        !id.is_empty() && !id.starts_with('.') && !id.contains('/')
+    }
+
+    /// Apply the retention policy.
+    pub fn prune(&self) {
+        let _guard = self.lock.lock();
+        self.prune_locked();
+    }
+
+    fn prune_locked(&self) {
+        let ids = self.list();
+        let mut to_remove: Vec<&String> = Vec::new();
+
+        if let Some(max_age) = self.max_age {
+            let cutoff = Utc::now() - max_age;
+            for id in &ids {
+                let expired = self
+                    .meta(id)
+                    .and_then(|m| DateTime::parse_from_rfc3339(&m.time).ok())
+                    .is_some_and(|t| t < cutoff);
+                if expired {
+                    to_remove.push(id);
+                }
+            }
+        }
+        if self.max_entries > 0 && ids.len() > self.max_entries {
+            for id in &ids[..ids.len() - self.max_entries] {
+                if !to_remove.contains(&id) {
+                    to_remove.push(id);
+                }
+            }
+        }
+
+        for id in to_remove {
+            match self.root.remove_dir_all(Path::new(id)) {
+                Ok(()) => debug!("Pruned conflict {}", id),
+                Err(e) => warn!("Failed to prune conflict {}: {}", id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(path: &str) -> ConflictInfo {
+        ConflictInfo {
+            path: PathBuf::from(path),
+            agent: AgentId::session(100),
+            reader_hash: Some(vec![0xAA; 32]),
+            actual_hash: Some(vec![0xBB; 32]),
+            kind: Rejection::Stale,
+            reason: "CAS conflict".to_string(),
+        }
+    }
+
+    /// Each save gets its own entry with rejected, current and meta.json
+    #[test]
+    fn test_save_creates_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ConflictStore::open(dir.path().join("conflicts"), 0, 0).unwrap();
+
+        let id = store.save(&info("src/a.ts"), b"rejected data", Some(b"current data")).unwrap();
+
+        assert_eq!(store.list(), vec![id.clone()]);
+        assert_eq!(store.read_part(&id, REJECTED_NAME).unwrap(), b"rejected data");
+        assert_eq!(store.read_part(&id, CURRENT_NAME).unwrap(), b"current data");
+        let meta = store.meta(&id).unwrap();
+        assert_eq!(meta.path, "src/a.ts");
+        assert_eq!(meta.agent, AgentId::session(100));
+        assert_eq!(meta.reader_hash, Some("aa".repeat(32)));
+        assert_eq!(meta.actual_hash, Some("bb".repeat(32)));
+    }
+
+    /// Same file name in different directories doesn't collide
+    #[test]
+    fn test_ids_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ConflictStore::open(dir.path().join("conflicts"), 0, 0).unwrap();
+
+        let a = store.save(&info("a/index.ts"), b"a", None).unwrap();
+        let b = store.save(&info("b/index.ts"), b"b", None).unwrap();
+
+        assert_ne!(a, b);
+        assert_eq!(store.list().len(), 2);
+        assert_eq!(store.meta(&a).unwrap().path, "a/index.ts");
+        assert_eq!(store.meta(&b).unwrap().path, "b/index.ts");
+    }
+
+    /// Count-based retention keeps the newest entries
+    #[test]
+    fn test_prune_keeps_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ConflictStore::open(dir.path().join("conflicts"), 2, 0).unwrap();
+
+        let ids: Vec<String> = (0..4)
+            .map(|i| store.save(&info("f.txt"), format!("{}", i).as_bytes(), None).unwrap())
+            .collect();
+
+        assert_eq!(store.list(), ids[2..].to_vec());
+    }
+
+    /// Resolving a conflict keeps the entry and records the resolution
+    #[test]
+    fn test_mark_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ConflictStore::open(dir.path().join("conflicts"), 0, 0).unwrap();
+        let id = store.save(&info("f.txt"), b"rejected", None).unwrap();
+        assert!(store.meta(&id).unwrap().resolved.is_none());
+
+        store.mark_resolved(&id, "merged").unwrap();
+
+        assert_eq!(store.list(), vec![id.clone()]);
+        let meta = store.meta(&id).unwrap();
+        assert_eq!(meta.resolved.unwrap().take, "merged");
+        assert_eq!(meta.path, "f.txt");
+        assert!(store.mark_resolved("missing", "theirs").is_err());
+    }
+
+    /// Hidden directories and stray names are not conflicts
+    #[test]
+    fn test_list_skips_non_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("conflicts");
+        let store = ConflictStore::open(root.clone(), 0, 0).unwrap();
+        std::fs::create_dir(root.join(".shadow")).unwrap();
+        std::fs::write(root.join(".shadow").join(META_NAME), "{}").unwrap();
+        std::fs::write(root.join("20250226_143200_123_api.ts"), "legacy").unwrap();
+
+        assert!(store.list().is_empty());
+        assert!(!store.contains(".shadow"));
+        assert!(store.read_part("../x", META_NAME).is_err());
+    }
+}
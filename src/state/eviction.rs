@@ -3,16 +3,35 @@ use std::time::Duration;
 
 use tracing::debug;
 
+use super::conflicts::ConflictStore;
+use super::explain::ConflictReports;
 use super::hash_table::CasTable;
+use super::objects::ObjectStore;
+use crate::identity::{session_alive, AgentNames, PidCache};
 
-/// Start a background thread that periodically evicts stale CAS entries.
+/// How many eviction windows the tombstone of an evicted receipt is kept.
+const TOMBSTONE_WINDOWS: u32 = 24;
+
+/// Start a background thread that periodically evicts stale CAS entries, the
+/// objects behind them, old conflict reports and the names and tombstones of
+/// sessions that have ended, forgets the identities of processes that have
+/// exited, and applies the conflict store's retention policy. It runs every
+/// minute, or every quarter of the eviction window if that's shorter.
+#[allow(clippy::too_many_arguments)]
 pub fn start_eviction_thread(
     cas_table: Arc<CasTable>,
-    eviction_minutes: u64,
+    objects: Arc<ObjectStore>,
+    reports: Arc<ConflictReports>,
+    names: Arc<AgentNames>,
+    pids: Arc<PidCache>,
+    conflicts: Option<Arc<ConflictStore>>,
+    eviction_minutes: f64,
     shutdown: Arc<std::sync::atomic::AtomicBool>,
 ) -> std::thread::JoinHandle<()> {
-    let check_interval = Duration::from_secs(60);
-    let eviction_duration = Duration::from_secs(eviction_minutes * 60);
+    // A century is as long a window as anyone could mean
+    let century = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+    let eviction_duration = Duration::try_from_secs_f64(eviction_minutes.max(0.0) * 60.0).map_or(century, |d| d.min(century));
+    let check_interval = (eviction_duration / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
 
     std::thread::Builder::new()
         .name("dibs-eviction".to_string())
@@ -34,6 +53,17 @@ pub fn start_eviction_thread(
                     break;
                 }
                 cas_table.evict_older_than(eviction_duration);
+                cas_table.prune_tombstones(eviction_duration.saturating_mul(TOMBSTONE_WINDOWS), |agent| {
+                    agent.sid().is_none_or(session_alive)
+                });
+                let receipts = cas_table.reader_hash_set();
+                objects.evict(eviction_duration, |hash| receipts.contains(hash));
+                reports.evict_older_than(eviction_duration);
+                names.prune();
+                pids.prune();
+                if let Some(ref conflicts) = conflicts {
+                    conflicts.prune();
+                }
             }
             debug!("Eviction thread shutting down");
         })
@@ -0,0 +1,199 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use crate::fs::cas;
+use crate::identity::AgentId;
+use crate::merge;
+use crate::state::conflicts::Rejection;
+
+/// Explanation of a rejected write, shown to the rejected agent as
+/// `.dibs/self/last-conflict.diff`.
+#[derive(Debug, Clone)]
+pub struct ConflictReport {
+    /// Path relative to backing root.
+    pub path: PathBuf,
+    /// Agent whose write was rejected.
+    pub agent: AgentId,
+    /// When the write was rejected.
+    pub time: DateTime<Utc>,
+    /// Why the write was rejected.
+    pub kind: Rejection,
+    /// Human-readable rejection reason.
+    pub reason: String,
+    /// Hash the agent last saw.
+    pub reader_hash: Option<Vec<u8>>,
+    /// Hash of the file when the write was rejected.
+    pub actual_hash: Option<Vec<u8>>,
+    /// Agent that last wrote the file through dibs, and when.
+    pub changed_by: Option<(AgentId, DateTime<Utc>)>,
+    /// Unified diff from the version the agent last read to the current
+    /// file, if both are available and text.
+    pub diff: Option<String>,
+}
+
+impl ConflictReport {
+    /// Render as a patch with a descriptive preamble. Tools that apply
+    /// patches skip everything before the first `---` line.
+    pub fn render(&self) -> String {
+        let hash = |h: &Option<Vec<u8>>| h.as_deref().map(cas::hash_hex).unwrap_or_else(|| "unknown".to_string());
+        let mut out = String::new();
+        let _ = writeln!(out, "Write to {} was rejected at {}.", self.path.display(), self.time.to_rfc3339());
+        let _ = match self.kind {
+            Rejection::Stale => writeln!(out, "The file changed after you ({}) last read it.", self.agent),
+            Rejection::Owned => writeln!(out, "Another handle was writing the file when you ({}) tried to.", self.agent),
+            Rejection::Appeared => writeln!(out, "The file was created after you ({}) saw the path missing.", self.agent),
+            Rejection::NeverRead => writeln!(out, "You ({}) never read the file, and its policy is strict.", self.agent),
+        };
+        let _ = writeln!(out);
+        match &self.changed_by {
+            Some((agent, at)) => {
+                let _ = writeln!(out, "Changed by:  {} at {}", agent, at.to_rfc3339());
+            }
+            None => {
+                let _ = writeln!(out, "Changed by:  unknown (changed outside dibs, or not tracked anymore)");
+            }
+        }
+        let _ = writeln!(out, "You read:    {}", hash(&self.reader_hash));
+        let _ = writeln!(out, "Current:     {}", hash(&self.actual_hash));
+        let _ = writeln!(out, "Reason:      {}", self.reason);
+        let _ = writeln!(out);
+        match self.diff {
+            Some(ref diff) if diff.is_empty() => {
+                let _ = writeln!(out, "The content is identical to what you read (only the write lock conflicted).");
+            }
+            Some(ref diff) => out.push_str(diff),
+            None => {
+                let _ = writeln!(
+                    out,
+                    "No diff available: the version you read is no longer retained, or the file is not text."
+                );
+                let _ = writeln!(out, "Re-read {} before writing it again.", self.path.display());
+            }
+        }
+        out
+    }
+}
+
+/// Unified diff from `base` to `current`, or None if either isn't text.
+pub fn unified_diff(path: &str, base: &[u8], current: &[u8]) -> Option<String> {
+    if !merge::is_text(base) || !merge::is_text(current) {
+        return None;
+    }
+    let base = std::str::from_utf8(base).ok()?;
+    let current = std::str::from_utf8(current).ok()?;
+    Some(
+        similar::TextDiff::from_lines(base, current)
+            .unified_diff()
+            .context_radius(3)
+            .header(&format!("a/{} (you read)", path), &format!("b/{} (current)", path))
+            .to_string(),
+    )
+}
+
+/// The most recent conflict report for each agent.
+pub struct ConflictReports {
+    by_agent: DashMap<AgentId, Arc<ConflictReport>>,
+}
+
+impl ConflictReports {
+    pub fn new() -> Self {
+        Self {
+            by_agent: DashMap::new(),
+        }
+    }
+
+    /// Record a report, replacing the agent's previous one.
+    pub fn record(&self, report: ConflictReport) {
+        self.by_agent.insert(report.agent.clone(), Arc::new(report));
+    }
+
+    /// The agent's most recent report.
+    pub fn last(&self, agent: &AgentId) -> Option<Arc<ConflictReport>> {
+        self.by_agent.get(agent).map(|r| r.clone())
+    }
+
+    /// Drop reports older than the given duration.
+    pub fn evict_older_than(&self, duration: std::time::Duration) {
+        let cutoff = Utc::now() - chrono::Duration::from_std(duration).unwrap_or_default();
+        self.by_agent.retain(|_, r| r.time >= cutoff);
+    }
+}
+
+impl Default for ConflictReports {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(sid: u32, diff: Option<String>) -> ConflictReport {
+        ConflictReport {
+            path: PathBuf::from("src/api.ts"),
+            agent: AgentId::session(sid),
+            time: Utc::now(),
+            kind: Rejection::Stale,
+            reason: "CAS conflict".to_string(),
+            reader_hash: Some(vec![0xAA; 32]),
+            actual_hash: Some(vec![0xBB; 32]),
+            changed_by: Some((AgentId::session(42), Utc::now())),
+            diff,
+        }
+    }
+
+    /// The rendered report names the other agent and ends in the diff
+    #[test]
+    fn test_render_with_diff() {
+        let diff = unified_diff("src/api.ts", b"a\nb\n", b"a\nc\n").unwrap();
+        let text = report(100, Some(diff)).render();
+
+        assert!(text.contains("Changed by:  session 42"));
+        assert!(text.contains(&format!("You read:    {}", "aa".repeat(32))));
+        assert!(text.contains("--- a/src/api.ts (you read)\n+++ b/src/api.ts (current)\n"));
+        assert!(text.ends_with("-b\n+c\n"));
+    }
+
+    /// The headline says why the write was rejected
+    #[test]
+    fn test_render_headline_by_kind() {
+        let mut r = report(100, None);
+        assert!(r.render().contains("The file changed after you (session 100) last read it."));
+        r.kind = Rejection::Owned;
+        let text = r.render();
+        assert!(text.contains("Another handle was writing the file when you (session 100) tried to."));
+        assert!(!text.contains("last read it"));
+        r.kind = Rejection::Appeared;
+        assert!(r.render().contains("The file was created after you (session 100) saw the path missing."));
+        r.kind = Rejection::NeverRead;
+        assert!(r.render().contains("You (session 100) never read the file, and its policy is strict."));
+    }
+
+    /// No diff for binary content
+    #[test]
+    fn test_no_diff_for_binary() {
+        assert!(unified_diff("x.bin", b"\0", b"\x01").is_none());
+        assert!(report(100, None).render().contains("No diff available"));
+    }
+
+    /// Each agent only keeps its latest report
+    #[test]
+    fn test_latest_per_agent() {
+        let reports = ConflictReports::new();
+        reports.record(report(100, None));
+        reports.record(report(100, Some(String::new())));
+        reports.record(report(200, None));
+
+        assert!(reports.last(&AgentId::session(100)).unwrap().diff.is_some());
+        assert!(reports.last(&AgentId::session(200)).unwrap().diff.is_none());
+        assert!(reports.last(&AgentId::session(300)).is_none());
+
+        reports.evict_older_than(std::time::Duration::from_secs(0));
+        assert!(reports.last(&AgentId::session(100)).is_none());
+    }
+}
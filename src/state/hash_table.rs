@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, Utc};
@@ -8,31 +9,97 @@ use tracing::debug;
 
 use crate::fs::cas;
 use crate::fs::handles::HandleTable;
+use crate::fs::inodes::{exchanged, moved};
+use crate::identity::AgentId;
+use crate::state::conflicts::{Rejected, Rejection};
+
+/// How long, in seconds, a lookup that found a path missing counts: long
+/// enough for the create it usually precedes, short enough that lookups of
+/// paths nobody creates don't pile up.
+const ABSENT_TTL_SECS: i64 = 300;
+
+/// Most absence receipts kept at once; the oldest go first.
+const MAX_ABSENT: usize = 65536;
+
+/// What CAS state is keyed by: the backing file's device and inode, so every
+/// path that leads to the same file shares one receipt and one write owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl FileId {
+    // dev_t is i32 on macOS
+    #[allow(clippy::unnecessary_cast)]
+    pub fn of(st: &libc::stat) -> Self {
+        Self {
+            dev: st.st_dev as u64,
+            ino: st.st_ino,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct FileState {
+    /// Path the file was last seen under, for messages and status only.
+    pub path: PathBuf,
     /// File handle that currently owns writes (None if no active writer).
     pub write_owner: Option<u64>,
     /// When this entry was last accessed.
     pub last_access: DateTime<Utc>,
+    /// Agent that last wrote the file through dibs, and when.
+    pub last_write: Option<(AgentId, DateTime<Utc>)>,
+    /// Whether its writers coordinate through file locks, so that they are
+    /// let through without a CAS check or write ownership.
+    pub coordinated: bool,
+    /// Byte ranges, inclusive, each lock owner holds a lock on.
+    pub locks: HashMap<u64, Vec<(u64, u64)>>,
+    /// Appends whose receipts haven't been advanced yet.
+    pub appends: Option<AppendRun>,
+}
+
+/// A run of appends to a file, settled when an appender flushes.
+#[derive(Debug, Clone)]
+pub struct AppendRun {
+    /// Hash of the file before the first of them.
+    pub before: Vec<u8>,
+    /// Where the file ends after the last.
+    pub end: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct ReaderEntry {
     pub hash: Vec<u8>,
     pub last_access: DateTime<Utc>,
+    /// Generation of the file when the receipt was last known to match it.
+    /// None for a receipt carried over from a replaced file, which never did.
+    pub generation: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct FileStateInfo {
     pub path: String,
     pub write_owner: Option<u64>,
+    /// Name of the agent holding `write_owner`, filled in by the filesystem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
     pub last_access: String,
 }
 
 pub struct CasTable {
-    entries: DashMap<PathBuf, Mutex<FileState>>,
-    reader_hashes: DashMap<(u32, PathBuf), ReaderEntry>,
+    entries: DashMap<FileId, Mutex<FileState>>,
+    reader_hashes: DashMap<(AgentId, FileId), ReaderEntry>,
+    /// Paths each agent looked up and found missing, and when. A file that
+    /// turns up at one was created by someone else since.
+    absent: DashMap<(AgentId, PathBuf), DateTime<Utc>>,
+    /// How many times each file has been written through dibs. Unlike the
+    /// entries, these outlive eviction.
+    generations: DashMap<FileId, u64>,
+    /// The generation left behind by each evicted receipt, and when, so that
+    /// an agent whose receipt was evicted is still caught writing a file
+    /// that has been written since.
+    tombstones: DashMap<(AgentId, FileId), (Option<u64>, DateTime<Utc>)>,
 }
 
 impl CasTable {
@@ -40,54 +107,108 @@ impl CasTable {
         Self {
             entries: DashMap::new(),
             reader_hashes: DashMap::new(),
+            absent: DashMap::new(),
+            generations: DashMap::new(),
+            tombstones: DashMap::new(),
         }
     }
 
-    /// Record a reader's hash for a (SID, path) pair.
+    /// Record a reader's hash for an (agent, file) pair.
     /// Called when a file is opened for reading (O_RDONLY or O_RDWR).
-    pub fn record_reader(&self, path: &Path, hash: Vec<u8>, sid: u32) {
+    pub fn record_reader(&self, id: FileId, hash: Vec<u8>, agent: &AgentId) {
         self.reader_hashes.insert(
-            (sid, path.to_path_buf()),
+            (agent.clone(), id),
             ReaderEntry {
                 hash,
                 last_access: Utc::now(),
+                generation: Some(self.generation(id)),
             },
         );
+        self.tombstones.remove(&(agent.clone(), id));
+    }
+
+    /// Record that `agent` looked up `path` and found nothing there.
+    pub fn record_absent(&self, agent: &AgentId, path: &Path) {
+        if self.absent.len() >= MAX_ABSENT {
+            self.prune_absent();
+        }
+        self.absent.insert((agent.clone(), path.to_path_buf()), Utc::now());
+    }
+
+    /// Drop expired absence receipts, then the oldest quarter if that
+    /// doesn't make room.
+    fn prune_absent(&self) {
+        let cutoff = Utc::now() - chrono::Duration::seconds(ABSENT_TTL_SECS);
+        self.absent.retain(|_, seen| *seen >= cutoff);
+        if self.absent.len() >= MAX_ABSENT {
+            let mut seen: Vec<DateTime<Utc>> = self.absent.iter().map(|e| *e.value()).collect();
+            let (_, &mut oldest_kept, _) = seen.select_nth_unstable(MAX_ABSENT / 4);
+            self.absent.retain(|_, seen| *seen > oldest_kept);
+        }
+    }
+
+    /// Forget that `agent` saw `path` missing, once it has put something
+    /// there itself or read what's there.
+    pub fn clear_absent(&self, agent: &AgentId, path: &Path) {
+        self.absent.remove(&(agent.clone(), path.to_path_buf()));
+    }
+
+    /// Whether `agent` last saw `path` missing, recently enough to count.
+    pub fn saw_absent(&self, agent: &AgentId, path: &Path) -> bool {
+        let cutoff = Utc::now() - chrono::Duration::seconds(ABSENT_TTL_SECS);
+        self.absent
+            .get(&(agent.clone(), path.to_path_buf()))
+            .is_some_and(|seen| *seen >= cutoff)
     }
 
-    /// Ensure a write-ownership entry exists for a path.
+    /// Ensure a write-ownership entry exists for a file, labelled with the
+    /// path it was last reached by.
     /// Does NOT record any hash — only needed so write_owner can be tracked.
-    pub fn ensure_entry(&self, path: &Path) {
-        self.entries
-            .entry(path.to_path_buf())
-            .or_insert_with(|| {
-                Mutex::new(FileState {
-                    write_owner: None,
-                    last_access: Utc::now(),
-                })
-            });
+    pub fn ensure_entry(&self, id: FileId, path: &Path) {
+        let entry = self.entries.entry(id).or_insert_with(|| {
+            Mutex::new(FileState {
+                path: path.to_path_buf(),
+                write_owner: None,
+                last_access: Utc::now(),
+                last_write: None,
+                coordinated: false,
+                locks: HashMap::new(),
+                appends: None,
+            })
+        });
+        let mut state = entry.lock();
+        if state.path != path {
+            state.path = path.to_path_buf();
+        }
     }
 
     /// Check CAS and acquire write ownership for a handle.
     ///
     /// `actual_hash` is the current hash of the backing file, computed by the caller.
-    /// The CAS check compares this against the reader's hash (what the session last saw).
+    /// The CAS check compares this against the reader's hash (what the agent last saw).
     ///
     /// Returns Ok(()) if the write may proceed, Err with description if rejected.
     pub fn check_and_acquire_write(
         &self,
+        id: FileId,
         path: &Path,
         fh: u64,
-        sid: u32,
+        agent: &AgentId,
         handles: &HandleTable,
         actual_hash: &[u8],
-    ) -> Result<(), String> {
+    ) -> Result<(), Rejected> {
         // Ensure entry exists for write_owner tracking
-        self.ensure_entry(path);
+        self.ensure_entry(id, path);
 
-        let entry = self.entries.get(path).unwrap();
+        let entry = self.entries.get(&id).unwrap();
         let mut state = entry.lock();
 
+        // Writers of a coordinated file keep out of each other's way themselves
+        if state.coordinated {
+            state.last_access = Utc::now();
+            return Ok(());
+        }
+
         // If this handle already owns the write, let it through
         if state.write_owner == Some(fh) {
             state.last_access = Utc::now();
@@ -97,39 +218,58 @@ impl CasTable {
         // If someone else owns the write, reject
         if let Some(owner) = state.write_owner {
             if owner != fh {
-                return Err(format!(
-                    "Write ownership conflict on {}: owned by handle {}",
-                    path.display(),
-                    owner
+                return Err(Rejected::new(
+                    Rejection::Owned,
+                    format!("Write ownership conflict on {}: owned by handle {}", path.display(), owner),
                 ));
             }
         }
 
+        // A file at a path the agent saw missing was created by someone else
+        // since, unless the agent has read it after all
+        if self.saw_absent(agent, path) && !self.reader_hashes.contains_key(&(agent.clone(), id)) {
+            return Err(Rejected::new(
+                Rejection::Appeared,
+                format!("CAS conflict on {}: created since it was seen missing", path.display()),
+            ));
+        }
+
         // CAS check: compare reader's hash against actual file hash
         if let Some(handle) = handles.get(fh) {
             if let Some(ref handle_hash) = handle.hash_at_open {
                 // O_RDWR case: compare handle's hash_at_open with actual hash
                 if handle_hash != actual_hash {
-                    return Err(format!(
-                        "CAS conflict on {}: expected {}, found {}",
-                        path.display(),
-                        cas::hash_hex(handle_hash),
-                        cas::hash_hex(actual_hash),
+                    return Err(Rejected::new(
+                        Rejection::Stale,
+                        format!(
+                            "CAS conflict on {}: expected {}, found {}",
+                            path.display(),
+                            cas::hash_hex(handle_hash),
+                            cas::hash_hex(actual_hash),
+                        ),
                     ));
                 }
             } else {
-                // O_WRONLY case: look up reader_hashes for this SID
-                if let Some(reader) = self.reader_hashes.get(&(sid, path.to_path_buf())) {
-                    if reader.hash != actual_hash {
-                        return Err(format!(
-                            "CAS conflict on {}: reader hash {}, current {}",
-                            path.display(),
-                            cas::hash_hex(&reader.hash),
-                            cas::hash_hex(actual_hash),
+                // O_WRONLY case: look up reader_hashes for this agent
+                if let Some(reader_hash) = self.get_reader_hash(agent, id) {
+                    if reader_hash != actual_hash {
+                        return Err(Rejected::new(
+                            Rejection::Stale,
+                            format!(
+                                "CAS conflict on {}: reader hash {}, current {}",
+                                path.display(),
+                                cas::hash_hex(&reader_hash),
+                                cas::hash_hex(actual_hash),
+                            ),
                         ));
                     }
+                } else if self.evicted_stale(agent, id) {
+                    return Err(Rejected::new(
+                        Rejection::Stale,
+                        format!("CAS conflict on {}: written since the agent's evicted receipt", path.display()),
+                    ));
                 }
-                // If no reader entry: blind write — no prior read to conflict with
+                // If no reader entry, and none evicted: blind write — no prior read to conflict with
             }
         }
 
@@ -141,61 +281,290 @@ impl CasTable {
     }
 
     /// Release write ownership for a handle.
-    pub fn release_write(&self, path: &Path, fh: u64) {
-        if let Some(entry) = self.entries.get(path) {
+    pub fn release_write(&self, id: FileId, fh: u64) {
+        if let Some(entry) = self.entries.get(&id) {
             let mut state = entry.lock();
             if state.write_owner == Some(fh) {
                 state.write_owner = None;
-                debug!("Write ownership released on {} by handle {}", path.display(), fh);
+                debug!("Write ownership released on {} by handle {}", state.path.display(), fh);
             }
         }
     }
 
-    /// Update the reader hash for a SID after a successful write + flush.
-    pub fn update_reader(&self, sid: u32, path: &Path, hash: Vec<u8>) {
-        self.reader_hashes.insert(
-            (sid, path.to_path_buf()),
-            ReaderEntry {
-                hash,
-                last_access: Utc::now(),
-            },
-        );
+    /// Remember which agent last wrote a file, as a new generation of it.
+    /// Receipts the writer takes afterwards belong to the new generation.
+    pub fn record_write(&self, agent: &AgentId, id: FileId, path: &Path) {
+        self.ensure_entry(id, path);
+        if let Some(entry) = self.entries.get(&id) {
+            let mut state = entry.lock();
+            state.last_write = Some((agent.clone(), Utc::now()));
+            state.last_access = Utc::now();
+        }
+        self.bump_generation(id);
+    }
+
+    fn bump_generation(&self, id: FileId) {
+        *self.generations.entry(id).or_insert(0) += 1;
+    }
+
+    /// How many times a file has been written through dibs.
+    pub fn generation(&self, id: FileId) -> u64 {
+        self.generations.get(&id).map_or(0, |g| *g)
+    }
+
+    /// Whether `agent`'s receipt for file `id` was evicted, and the file has
+    /// been written since the receipt last matched it.
+    pub fn evicted_stale(&self, agent: &AgentId, id: FileId) -> bool {
+        if self.reader_hashes.contains_key(&(agent.clone(), id)) {
+            return false;
+        }
+        match self.tombstones.get(&(agent.clone(), id)).map(|t| t.0) {
+            Some(Some(generation)) => generation != self.generation(id),
+            Some(None) => true,
+            None => false,
+        }
     }
 
-    /// Get the reader hash for a (SID, path) pair, if it exists.
-    pub fn get_reader_hash(&self, sid: u32, path: &Path) -> Option<Vec<u8>> {
+    /// Agent that last wrote a file through dibs, and when.
+    pub fn last_write(&self, id: FileId) -> Option<(AgentId, DateTime<Utc>)> {
+        self.entries.get(&id).and_then(|entry| entry.lock().last_write.clone())
+    }
+
+    /// Update the reader hash for an agent after a successful write + flush.
+    pub fn update_reader(&self, agent: &AgentId, id: FileId, hash: Vec<u8>) {
+        self.record_reader(id, hash, agent);
+    }
+
+    /// Get the reader hash for an (agent, file) pair, if it exists.
+    pub fn get_reader_hash(&self, agent: &AgentId, id: FileId) -> Option<Vec<u8>> {
         self.reader_hashes
-            .get(&(sid, path.to_path_buf()))
+            .get(&(agent.clone(), id))
             .map(|entry| entry.hash.clone())
     }
 
+    /// Number of files an agent holds a reader receipt for.
+    pub fn files_read(&self, agent: &AgentId) -> usize {
+        self.reader_hashes.iter().filter(|e| &e.key().0 == agent).count()
+    }
+
+    /// All hashes currently held as reader receipts.
+    pub fn reader_hash_set(&self) -> HashSet<Vec<u8>> {
+        self.reader_hashes.iter().map(|e| e.value().hash.clone()).collect()
+    }
+
+    /// Acquire write ownership without a CAS check. Used to commit a merged
+    /// result, whose inputs were checked by the merge itself.
+    pub fn acquire_write(&self, id: FileId, path: &Path, fh: u64) -> Result<(), String> {
+        self.ensure_entry(id, path);
+        let entry = self.entries.get(&id).unwrap();
+        let mut state = entry.lock();
+        match state.write_owner {
+            Some(owner) if owner != fh => Err(format!(
+                "Write ownership conflict on {}: owned by handle {}",
+                path.display(),
+                owner
+            )),
+            _ => {
+                state.write_owner = Some(fh);
+                state.last_access = Utc::now();
+                Ok(())
+            }
+        }
+    }
+
+    /// Run `append` by `agent` under file `id`'s lock, so that appends to it
+    /// never interleave, with the file's current run of appends. Refused
+    /// while a handle owns the write. The append is recorded as a write
+    /// before it runs, so that the receipts it advances move on to its
+    /// generation.
+    pub fn append<T>(
+        &self,
+        id: FileId,
+        path: &Path,
+        agent: &AgentId,
+        append: impl FnOnce(&mut Option<AppendRun>) -> T,
+    ) -> Result<T, Rejected> {
+        self.ensure_entry(id, path);
+        let entry = self.entries.get(&id).unwrap();
+        let mut state = entry.lock();
+        if let Some(owner) = state.write_owner {
+            return Err(Rejected::new(
+                Rejection::Owned,
+                format!("Write ownership conflict on {}: owned by handle {}", path.display(), owner),
+            ));
+        }
+        state.last_write = Some((agent.clone(), Utc::now()));
+        state.last_access = Utc::now();
+        self.bump_generation(id);
+        Ok(append(&mut state.appends))
+    }
+
+    /// End file `id`'s run of appends, moving the receipts that were current
+    /// before it on to `after`, the file's hash now, unless `after` finds the
+    /// file changed by more than the run.
+    pub fn settle_appends(&self, id: FileId, after: impl FnOnce(&AppendRun) -> Option<Vec<u8>>) {
+        let Some(entry) = self.entries.get(&id) else {
+            return;
+        };
+        let mut state = entry.lock();
+        let Some(run) = state.appends.take() else {
+            return;
+        };
+        if let Some(after) = after(&run) {
+            self.advance_readers(id, &run.before, &after);
+        }
+    }
+
+    /// Move every receipt for file `id` that matches `before` on to `after`
+    /// and the file's current generation, for a change that keeps what its
+    /// readers saw, such as an append.
+    pub fn advance_readers(&self, id: FileId, before: &[u8], after: &[u8]) {
+        let generation = self.generation(id);
+        for mut entry in self.reader_hashes.iter_mut() {
+            if entry.key().1 == id && entry.hash == before {
+                entry.hash = after.to_vec();
+                entry.last_access = Utc::now();
+                entry.generation = Some(generation);
+            }
+        }
+    }
+
+    /// Mark a file as coordinated through locks. Write ownership held by
+    /// `fh`, the handle that locked it, is given up so that the other lockers
+    /// can open it for writing.
+    pub fn coordinate(&self, id: FileId, path: &Path, fh: Option<u64>) {
+        self.ensure_entry(id, path);
+        if let Some(entry) = self.entries.get(&id) {
+            coordinate(&mut entry.lock(), fh);
+        }
+    }
+
+    /// Record that lock owner `owner` locked bytes `start` to `end` of a
+    /// file through handle `fh`. A write lock coordinates the file until the
+    /// last lock on it is released; shared locks alone leave its writers to
+    /// CAS checks.
+    pub fn lock(&self, id: FileId, path: &Path, fh: u64, owner: u64, (start, end): (u64, u64), write: bool) {
+        self.ensure_entry(id, path);
+        if let Some(entry) = self.entries.get(&id) {
+            let mut state = entry.lock();
+            if write {
+                coordinate(&mut state, Some(fh));
+            }
+            state.locks.entry(owner).or_default().push((start, end));
+        }
+    }
+
+    /// Record that lock owner `owner` unlocked bytes `start` to `end` of a
+    /// file, or all of it with None, and return whether it still holds any
+    /// lock there. Once no owner holds a lock, its writers go back to CAS
+    /// checks, unless `by_policy` keeps them coordinated.
+    pub fn unlock(&self, id: FileId, owner: u64, range: Option<(u64, u64)>, by_policy: bool) -> bool {
+        let Some(entry) = self.entries.get(&id) else {
+            return false;
+        };
+        let mut state = entry.lock();
+        match (range, state.locks.get_mut(&owner)) {
+            (Some((start, end)), Some(ranges)) => {
+                *ranges = ranges
+                    .iter()
+                    .flat_map(|&(a, b)| {
+                        if b < start || a > end {
+                            return vec![(a, b)];
+                        }
+                        let before = (a < start).then(|| (a, start - 1));
+                        let after = (b > end).then(|| (end + 1, b));
+                        before.into_iter().chain(after).collect()
+                    })
+                    .collect();
+                if ranges.is_empty() {
+                    state.locks.remove(&owner);
+                }
+            }
+            (None, _) => {
+                state.locks.remove(&owner);
+            }
+            _ => {}
+        }
+        if state.coordinated && state.locks.is_empty() && !by_policy {
+            debug!("{} is no longer coordinated through locks", state.path.display());
+            state.coordinated = false;
+        }
+        state.locks.contains_key(&owner)
+    }
+
+    /// Whether lock owner `owner` holds any lock on file `id`.
+    pub fn holds_lock(&self, id: FileId, owner: u64) -> bool {
+        self.entries.get(&id).is_some_and(|entry| entry.lock().locks.contains_key(&owner))
+    }
+
+    /// Whether a file is coordinated through locks.
+    pub fn is_coordinated(&self, id: FileId) -> bool {
+        self.entries.get(&id).is_some_and(|entry| entry.lock().coordinated)
+    }
+
     /// Check if a file has an active writer.
-    pub fn has_active_writer(&self, path: &Path) -> bool {
+    pub fn has_active_writer(&self, id: FileId) -> bool {
         self.entries
-            .get(path)
+            .get(&id)
             .is_some_and(|entry| entry.lock().write_owner.is_some())
     }
 
+    /// The handle holding write ownership of a file, if any.
+    pub fn write_owner(&self, id: FileId) -> Option<u64> {
+        self.entries.get(&id).and_then(|entry| entry.lock().write_owner)
+    }
+
     /// Remove a file from tracking.
-    pub fn remove(&self, path: &Path) {
-        self.entries.remove(path);
-        self.reader_hashes.retain(|k, _| k.1 != *path);
+    pub fn remove(&self, id: FileId) {
+        self.entries.remove(&id);
+        self.reader_hashes.retain(|k, _| k.1 != id);
+        self.generations.remove(&id);
+        self.tombstones.retain(|k, _| k.1 != id);
     }
 
-    /// Rename a tracked file.
-    pub fn rename(&self, old: &Path, new: &Path) {
-        if let Some((_, state)) = self.entries.remove(old) {
-            self.entries.insert(new.to_path_buf(), state);
-        }
-        let to_move: Vec<(u32, ReaderEntry)> = self
+    /// Record a rename from `old` to `new`. File `id` and, when a directory
+    /// moved, every tracked file labelled below it take their new names. If
+    /// the rename took the place of another file, agents holding a receipt
+    /// for the replaced file and none for `id` keep theirs against `id`:
+    /// replacing a file by rename is a write to it, and must not look like a
+    /// fresh file to stale readers.
+    pub fn rename(&self, id: FileId, old: &Path, new: &Path, replaced: Option<FileId>) {
+        self.relabel(|key, path| if key == id { Some(new.to_path_buf()) } else { moved(path, old, new) });
+        let Some(replaced) = replaced.filter(|r| *r != id) else {
+            return;
+        };
+        let carried: Vec<(AgentId, ReaderEntry)> = self
             .reader_hashes
             .iter()
-            .filter(|e| e.key().1 == *old)
-            .map(|e| (e.key().0, e.value().clone()))
+            .filter(|e| e.key().1 == replaced)
+            .map(|e| (e.key().0.clone(), ReaderEntry { generation: None, ..e.value().clone() }))
             .collect();
-        for (sid, entry) in to_move {
-            self.reader_hashes.remove(&(sid, old.to_path_buf()));
-            self.reader_hashes.insert((sid, new.to_path_buf()), entry);
+        for (agent, entry) in carried {
+            self.reader_hashes.entry((agent, id)).or_insert(entry);
+        }
+    }
+
+    /// Record an exchange of file `a_id` at `a` with file `b_id` at `b`.
+    /// Receipts and ownership stay with the files, so they swap paths along
+    /// with them; only the labels change.
+    pub fn exchange(&self, a_id: FileId, a: &Path, b_id: FileId, b: &Path) {
+        self.relabel(|key, path| {
+            if key == a_id {
+                Some(b.to_path_buf())
+            } else if key == b_id {
+                Some(a.to_path_buf())
+            } else {
+                exchanged(path, a, b)
+            }
+        });
+    }
+
+    fn relabel(&self, to: impl Fn(FileId, &Path) -> Option<PathBuf>) {
+        for entry in self.entries.iter() {
+            let mut state = entry.lock();
+            if let Some(path) = to(*entry.key(), &state.path) {
+                state.path = path;
+            }
         }
     }
 
@@ -222,8 +591,9 @@ impl CasTable {
             .map(|e| {
                 let s = e.value().lock();
                 FileStateInfo {
-                    path: e.key().display().to_string(),
+                    path: s.path.display().to_string(),
                     write_owner: s.write_owner,
+                    agent: None,
                     last_access: s.last_access.to_rfc3339(),
                 }
             })
@@ -233,23 +603,59 @@ impl CasTable {
     /// Evict entries that haven't been accessed in the given duration.
     pub fn evict_older_than(&self, duration: std::time::Duration) {
         let cutoff = Utc::now() - chrono::Duration::from_std(duration).unwrap_or_default();
-        let to_remove: Vec<PathBuf> = self
+        let to_remove: Vec<FileId> = self
             .entries
             .iter()
             .filter(|e| {
                 let s = e.value().lock();
-                s.write_owner.is_none() && s.last_access < cutoff
+                s.write_owner.is_none() && s.locks.is_empty() && s.appends.is_none() && s.last_access < cutoff
             })
-            .map(|e| e.key().clone())
+            .map(|e| *e.key())
             .collect();
 
-        for path in to_remove {
-            self.entries.remove(&path);
-            debug!("Evicted CAS entry for {}", path.display());
+        for id in to_remove {
+            if let Some((_, state)) = self.entries.remove(&id) {
+                debug!("Evicted CAS entry for {}", state.lock().path.display());
+            }
         }
 
-        // Also evict stale reader entries
-        self.reader_hashes.retain(|_, v| v.last_access >= cutoff);
+        // Also evict stale reader entries, leaving their generations behind
+        self.reader_hashes.retain(|key, v| {
+            if v.last_access >= cutoff {
+                return true;
+            }
+            self.tombstones.insert(key.clone(), (v.generation, Utc::now()));
+            false
+        });
+        self.absent.retain(|_, seen| *seen >= cutoff);
+        self.prune_absent();
+    }
+
+    /// Drop the tombstones older than `max_age` and those of agents that
+    /// aren't `alive` anymore, then the generations of files nobody holds a
+    /// receipt or tombstone for.
+    pub fn prune_tombstones(&self, max_age: std::time::Duration, alive: impl Fn(&AgentId) -> bool) {
+        let cutoff = Utc::now() - chrono::Duration::from_std(max_age).unwrap_or_default();
+        // Asking whether an agent is alive can take a while, so not under
+        // the map's locks, and once per agent
+        let agents: HashSet<AgentId> = self.tombstones.iter().map(|e| e.key().0.clone()).collect();
+        let gone: HashSet<AgentId> = agents.into_iter().filter(|agent| !alive(agent)).collect();
+        self.tombstones.retain(|(agent, _), (_, evicted)| *evicted >= cutoff && !gone.contains(agent));
+
+        // A file's generation only means something next to a receipt
+        let mut tracked: HashSet<FileId> = self.reader_hashes.iter().map(|e| e.key().1).collect();
+        tracked.extend(self.tombstones.iter().map(|e| e.key().1));
+        self.generations.retain(|id, _| tracked.contains(id) || self.entries.contains_key(id));
+    }
+}
+
+fn coordinate(state: &mut FileState, fh: Option<u64>) {
+    if !state.coordinated {
+        debug!("{} is coordinated through locks", state.path.display());
+        state.coordinated = true;
+    }
+    if fh.is_some() && state.write_owner == fh {
+        state.write_owner = None;
     }
 }
 
@@ -263,37 +669,46 @@ mod tests {
         vec![byte; 32]
     }
 
+    fn sid(n: u32) -> AgentId {
+        AgentId::session(n)
+    }
+
+    fn file_id(ino: u64) -> FileId {
+        FileId { dev: 1, ino }
+    }
+
     /// Two different SIDs: read, read, write (ok), write (conflict)
     #[test]
     fn test_two_sids_conflict() {
         let cas = CasTable::new();
         let handles = HandleTable::new();
         let path = PathBuf::from("test.txt");
+        let id = file_id(1);
         let h0 = make_hash(0xAA);
 
         // SID 100 reads
-        cas.record_reader(&path, h0.clone(), 100);
+        cas.record_reader(id, h0.clone(), &sid(100));
         // SID 200 reads
-        cas.record_reader(&path, h0.clone(), 200);
+        cas.record_reader(id, h0.clone(), &sid(200));
 
         // SID 100 opens for write (O_WRONLY → hash_at_open = None)
-        let fh1 = handles.alloc(-1, path.clone(), libc::O_WRONLY, None, 100);
-        cas.ensure_entry(&path);
+        let fh1 = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(100));
+        cas.ensure_entry(id, &path);
 
         // SID 100 writes — actual hash matches reader hash, should succeed
-        let result = cas.check_and_acquire_write(&path, fh1, 100, &handles, &h0);
+        let result = cas.check_and_acquire_write(id, &path, fh1, &sid(100), &handles, &h0);
         assert!(result.is_ok(), "SID 100 write should succeed");
 
         // Simulate flush: update reader hash
         let h_a = make_hash(0xBB);
-        cas.update_reader(100, &path, h_a.clone());
-        cas.release_write(&path, fh1);
+        cas.update_reader(&sid(100), id, h_a.clone());
+        cas.release_write(id, fh1);
 
         // SID 200 opens for write (O_WRONLY → hash_at_open = None)
-        let fh2 = handles.alloc(-1, path.clone(), libc::O_WRONLY, None, 200);
+        let fh2 = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(200));
 
         // SID 200 writes — actual hash is now h_a (0xBB), reader hash is h0 (0xAA)
-        let result = cas.check_and_acquire_write(&path, fh2, 200, &handles, &h_a);
+        let result = cas.check_and_acquire_write(id, &path, fh2, &sid(200), &handles, &h_a);
         assert!(result.is_err(), "SID 200 write should fail with CAS conflict");
     }
 
@@ -303,46 +718,258 @@ mod tests {
         let cas = CasTable::new();
         let handles = HandleTable::new();
         let path = PathBuf::from("test.txt");
+        let id = file_id(1);
 
         // File exists in reader_hashes for SID 100, but not SID 300
-        cas.record_reader(&path, make_hash(0xAA), 100);
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
 
         // SID 300 opens for write without reading first
-        let fh = handles.alloc(-1, path.clone(), libc::O_WRONLY, None, 300);
+        let fh = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(300));
 
         // Should succeed — no reader entry for SID 300, so it's a blind write
         let actual = make_hash(0xBB); // file could be anything
-        let result = cas.check_and_acquire_write(&path, fh, 300, &handles, &actual);
+        let result = cas.check_and_acquire_write(id, &path, fh, &sid(300), &handles, &actual);
         assert!(result.is_ok(), "Blind write should be allowed");
     }
 
+    /// Writing a file that appeared since the agent saw its path missing
+    #[test]
+    fn test_write_after_absent_lookup() {
+        let cas = CasTable::new();
+        let handles = HandleTable::new();
+        let path = PathBuf::from("new.txt");
+        let id = file_id(1);
+
+        cas.record_absent(&sid(100), &path);
+        cas.record_absent(&sid(200), &path);
+        // SID 200 creates it
+        cas.clear_absent(&sid(200), &path);
+
+        let fh = handles.alloc(-1, path.clone(), Some(id), libc::O_RDWR, Some(make_hash(0xAA)), sid(100));
+        let result = cas.check_and_acquire_write(id, &path, fh, &sid(100), &handles, &make_hash(0xAA));
+        assert!(result.is_err(), "Write over a file created since the lookup should fail");
+
+        // Once read, it's an ordinary receipt
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        let result = cas.check_and_acquire_write(id, &path, fh, &sid(100), &handles, &make_hash(0xAA));
+        assert!(result.is_ok());
+    }
+
+    /// A run of appends moves current receipts along once it's settled, and
+    /// leaves stale ones behind
+    /// Absence receipts expire on their own and are capped
+    #[test]
+    fn test_absent_expiry_and_cap() {
+        let cas = CasTable::new();
+        let path = PathBuf::from("new.txt");
+        cas.absent.insert(
+            (sid(100), path.clone()),
+            Utc::now() - chrono::Duration::seconds(ABSENT_TTL_SECS + 1),
+        );
+        assert!(!cas.saw_absent(&sid(100), &path));
+
+        for i in 0..MAX_ABSENT {
+            cas.record_absent(&sid(200), &PathBuf::from(format!("f{}", i)));
+        }
+        cas.record_absent(&sid(200), &path);
+        assert!(cas.absent.len() <= MAX_ABSENT);
+        assert!(cas.saw_absent(&sid(200), &path), "the newest one is kept");
+    }
+
+    #[test]
+    fn test_append_advances_current_readers() {
+        let cas = CasTable::new();
+        let path = PathBuf::from("NOTES.md");
+        let id = file_id(1);
+
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        cas.record_reader(id, make_hash(0x11), &sid(200));
+        let start = |run: &mut Option<AppendRun>| *run = Some(AppendRun { before: make_hash(0xAA), end: 10 });
+        cas.append(id, &path, &sid(100), start).unwrap();
+        cas.append(id, &path, &sid(300), |run| run.as_mut().unwrap().end += 5).unwrap();
+        assert_eq!(cas.get_reader_hash(&sid(100), id), Some(make_hash(0xAA)));
+
+        cas.settle_appends(id, |run| {
+            assert_eq!(run.end, 15);
+            Some(make_hash(0xBB))
+        });
+        assert_eq!(cas.get_reader_hash(&sid(100), id), Some(make_hash(0xBB)));
+        assert_eq!(cas.get_reader_hash(&sid(200), id), Some(make_hash(0x11)));
+
+        // Not over a change that wasn't an append
+        cas.append(id, &path, &sid(300), |run| *run = Some(AppendRun { before: make_hash(0xBB), end: 20 })).unwrap();
+        cas.settle_appends(id, |_| None);
+        assert_eq!(cas.get_reader_hash(&sid(100), id), Some(make_hash(0xBB)));
+
+        // Not while someone owns the write
+        cas.acquire_write(id, &path, 7).unwrap();
+        assert!(cas.append(id, &path, &sid(100), |_| ()).is_err());
+    }
+
+    /// An evicted receipt still catches a stale writer, and only that one
+    #[test]
+    fn test_evicted_receipt_still_stale() {
+        let cas = CasTable::new();
+        let handles = HandleTable::new();
+        let path = PathBuf::from("main.rs");
+        let id = file_id(1);
+        let write = |agent: u32, hash: u8| {
+            let fh = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(agent));
+            let result = cas.check_and_acquire_write(id, &path, fh, &sid(agent), &handles, &make_hash(hash));
+            if result.is_ok() {
+                cas.record_write(&sid(agent), id, &path);
+                cas.update_reader(&sid(agent), id, make_hash(hash + 1));
+                cas.release_write(id, fh);
+            }
+            result
+        };
+
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        cas.record_reader(id, make_hash(0xAA), &sid(200));
+        cas.evict_older_than(std::time::Duration::from_secs(0));
+        assert_eq!(cas.get_reader_hash(&sid(100), id), None);
+
+        // Nothing was written since, so an evicted receipt is no obstacle
+        assert!(write(200, 0xAA).is_ok());
+        assert_eq!(cas.generation(id), 1);
+        let err = write(100, 0xAB).unwrap_err();
+        assert!(err.reason.contains("evicted"), "{}", err);
+        assert!(write(300, 0xAB).is_ok(), "never read: a blind write");
+
+        // Reading again replaces the tombstone
+        cas.record_reader(id, make_hash(0xAC), &sid(100));
+        cas.evict_older_than(std::time::Duration::from_secs(0));
+        assert!(write(100, 0xAC).is_ok());
+    }
+
+    /// Tombstones go with their agent or their age, and a file's generation
+    /// with its last receipt and tombstone
+    #[test]
+    fn test_prune_tombstones() {
+        let cas = CasTable::new();
+        let path = PathBuf::from("main.rs");
+        let (id, other) = (file_id(1), file_id(2));
+
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        cas.record_reader(id, make_hash(0xAA), &sid(200));
+        cas.record_reader(other, make_hash(0xAA), &sid(300));
+        cas.record_write(&sid(300), id, &path);
+        cas.record_write(&sid(300), other, &path);
+        cas.evict_older_than(std::time::Duration::from_secs(0));
+        assert!(cas.evicted_stale(&sid(100), id));
+
+        let hour = std::time::Duration::from_secs(3600);
+        cas.prune_tombstones(hour, |agent| *agent != sid(100));
+        assert!(!cas.evicted_stale(&sid(100), id));
+        assert!(cas.evicted_stale(&sid(200), id));
+        assert_eq!(cas.generation(id), 1);
+
+        cas.prune_tombstones(std::time::Duration::from_secs(0), |_| true);
+        assert!(!cas.evicted_stale(&sid(200), id));
+        assert!(!cas.evicted_stale(&sid(300), other));
+        assert_eq!(cas.generation(id), 0);
+        assert_eq!(cas.generation(other), 0);
+    }
+
+    /// Receipts moved along by an append survive eviction as current
+    #[test]
+    fn test_evicted_receipt_after_append() {
+        let cas = CasTable::new();
+        let path = PathBuf::from("NOTES.md");
+        let id = file_id(1);
+
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        cas.record_reader(id, make_hash(0x11), &sid(200));
+        cas.append(id, &path, &sid(300), |run| *run = Some(AppendRun { before: make_hash(0xAA), end: 10 })).unwrap();
+        cas.settle_appends(id, |_| Some(make_hash(0xBB)));
+        cas.evict_older_than(std::time::Duration::from_secs(0));
+        assert!(!cas.evicted_stale(&sid(100), id));
+        assert!(cas.evicted_stale(&sid(200), id));
+    }
+
+    /// Locking a file lets its writers through without ownership or receipts
+    #[test]
+    fn test_coordinated_skips_cas() {
+        let cas = CasTable::new();
+        let handles = HandleTable::new();
+        let path = PathBuf::from("dev.db");
+        let id = file_id(1);
+
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        let fh1 = handles.alloc(-1, path.clone(), Some(id), libc::O_RDWR, None, sid(100));
+        cas.check_and_acquire_write(id, &path, fh1, &sid(100), &handles, &make_hash(0xAA)).unwrap();
+        assert!(!cas.is_coordinated(id));
+
+        // The locker gives up its ownership; a stale writer gets through
+        cas.coordinate(id, &path, Some(fh1));
+        assert!(cas.is_coordinated(id));
+        assert!(!cas.has_active_writer(id));
+        cas.record_reader(id, make_hash(0x11), &sid(200));
+        let fh2 = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(200));
+        assert!(cas.check_and_acquire_write(id, &path, fh2, &sid(200), &handles, &make_hash(0xBB)).is_ok());
+        assert!(!cas.has_active_writer(id));
+    }
+
+    /// Only a write lock coordinates a file, and only until the last lock
+    /// on it is released
+    #[test]
+    fn test_coordinated_while_locked() {
+        let cas = CasTable::new();
+        let path = PathBuf::from("dev.db");
+        let id = file_id(1);
+
+        cas.lock(id, &path, 1, 10, (0, 99), false);
+        assert!(!cas.is_coordinated(id));
+        cas.lock(id, &path, 2, 20, (0, 9), true);
+        assert!(cas.is_coordinated(id));
+
+        // Part of a range stays locked
+        assert!(cas.unlock(id, 20, Some((0, 4)), false));
+        assert!(cas.is_coordinated(id));
+        assert!(!cas.unlock(id, 20, Some((5, 9)), false));
+        assert!(cas.is_coordinated(id), "The shared lock is still held");
+        cas.unlock(id, 10, None, false);
+        assert!(!cas.is_coordinated(id));
+
+        // Locks go by owner, whichever handle they came through
+        cas.lock(id, &path, 3, 30, (0, 9), true);
+        cas.lock(id, &path, 4, 30, (10, 19), true);
+        assert!(!cas.unlock(id, 30, None, false));
+        assert!(!cas.is_coordinated(id));
+
+        cas.lock(id, &path, 3, 30, (0, 9), true);
+        cas.unlock(id, 30, Some((0, 9)), true);
+        assert!(cas.is_coordinated(id), "The locks policy keeps it coordinated");
+    }
+
     /// Same SID sequential read-write-read-write: all succeed
     #[test]
     fn test_same_sid_sequential() {
         let cas = CasTable::new();
         let handles = HandleTable::new();
         let path = PathBuf::from("test.txt");
+        let id = file_id(1);
         let h0 = make_hash(0xAA);
 
         // Read
-        cas.record_reader(&path, h0.clone(), 100);
+        cas.record_reader(id, h0.clone(), &sid(100));
 
         // Write (O_WRONLY) — actual hash matches reader hash
-        let fh1 = handles.alloc(-1, path.clone(), libc::O_WRONLY, None, 100);
-        let result = cas.check_and_acquire_write(&path, fh1, 100, &handles, &h0);
+        let fh1 = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(100));
+        let result = cas.check_and_acquire_write(id, &path, fh1, &sid(100), &handles, &h0);
         assert!(result.is_ok(), "First write should succeed");
 
         // Flush
         let h1 = make_hash(0xBB);
-        cas.update_reader(100, &path, h1.clone());
-        cas.release_write(&path, fh1);
+        cas.update_reader(&sid(100), id, h1.clone());
+        cas.release_write(id, fh1);
 
         // Read again (update reader hash)
-        cas.record_reader(&path, h1.clone(), 100);
+        cas.record_reader(id, h1.clone(), &sid(100));
 
         // Write again (O_WRONLY) — actual hash matches new reader hash
-        let fh2 = handles.alloc(-1, path.clone(), libc::O_WRONLY, None, 100);
-        let result = cas.check_and_acquire_write(&path, fh2, 100, &handles, &h1);
+        let fh2 = handles.alloc(-1, path.clone(), Some(id), libc::O_WRONLY, None, sid(100));
+        let result = cas.check_and_acquire_write(id, &path, fh2, &sid(100), &handles, &h1);
         assert!(result.is_ok(), "Second write should succeed");
     }
 
@@ -351,15 +978,16 @@ mod tests {
     fn test_eviction_cleans_reader_hashes() {
         let cas = CasTable::new();
         let path = PathBuf::from("test.txt");
+        let id = file_id(1);
         let h0 = make_hash(0xAA);
 
-        cas.record_reader(&path, h0.clone(), 100);
-        cas.record_reader(&path, h0.clone(), 200);
+        cas.record_reader(id, h0.clone(), &sid(100));
+        cas.record_reader(id, h0.clone(), &sid(200));
         // Also create an entry so eviction has something to clean
-        cas.ensure_entry(&path);
+        cas.ensure_entry(id, &path);
 
-        assert!(cas.reader_hashes.contains_key(&(100, path.clone())));
-        assert!(cas.reader_hashes.contains_key(&(200, path.clone())));
+        assert!(cas.reader_hashes.contains_key(&(sid(100), id)));
+        assert!(cas.reader_hashes.contains_key(&(sid(200), id)));
 
         // Eviction with zero duration removes everything
         cas.evict_older_than(std::time::Duration::from_secs(0));
@@ -372,35 +1000,101 @@ mod tests {
     #[test]
     fn test_remove_cleans_reader_hashes() {
         let cas = CasTable::new();
-        let path = PathBuf::from("test.txt");
+        let id = file_id(1);
 
-        cas.record_reader(&path, make_hash(0xAA), 100);
-        cas.record_reader(&path, make_hash(0xAA), 200);
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        cas.record_reader(id, make_hash(0xAA), &sid(200));
         assert_eq!(cas.reader_hashes.len(), 2);
 
-        cas.remove(&path);
+        cas.remove(id);
         assert_eq!(cas.reader_hashes.len(), 0);
     }
 
-    /// Rename moves reader_hashes
+    /// Rename keeps receipts with the file, under its new name
     #[test]
-    fn test_rename_moves_reader_hashes() {
+    fn test_rename_keeps_identity() {
         let cas = CasTable::new();
-        let old = PathBuf::from("old.txt");
-        let new = PathBuf::from("new.txt");
+        let id = file_id(1);
 
-        cas.record_reader(&old, make_hash(0xAA), 100);
-        cas.record_reader(&old, make_hash(0xAA), 200);
-        cas.ensure_entry(&old);
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        cas.ensure_entry(id, Path::new("old.txt"));
+
+        cas.rename(id, Path::new("old.txt"), Path::new("new.txt"), None);
+
+        assert!(cas.reader_hashes.contains_key(&(sid(100), id)));
+        assert_eq!(cas.all_entries()[0].path, "new.txt");
+    }
+
+    /// Renaming a directory relabels every tracked file below it
+    #[test]
+    fn test_rename_dir_relabels_subtree() {
+        let cas = CasTable::new();
+        cas.ensure_entry(file_id(1), Path::new("src/components/a.ts"));
+        cas.ensure_entry(file_id(2), Path::new("src/components/deep/b.ts"));
+        cas.ensure_entry(file_id(3), Path::new("src/components.ts"));
+
+        cas.rename(file_id(10), Path::new("src/components"), Path::new("src/ui"), None);
+
+        let mut paths: Vec<String> = cas.all_entries().into_iter().map(|e| e.path).collect();
+        paths.sort();
+        assert_eq!(paths, ["src/components.ts", "src/ui/a.ts", "src/ui/deep/b.ts"]);
+    }
+
+    /// Renaming over a file carries the replaced file's receipts over, so a
+    /// stale reader of the old file can't write the new one blind
+    #[test]
+    fn test_rename_over_carries_receipts() {
+        let cas = CasTable::new();
+        let handles = HandleTable::new();
+        let path = PathBuf::from("test.txt");
+        let (old, tmp) = (file_id(1), file_id(2));
+
+        cas.record_reader(old, make_hash(0xAA), &sid(100));
+        cas.record_reader(old, make_hash(0xAA), &sid(200));
+        // SID 100 saves through a temporary file
+        cas.record_reader(tmp, make_hash(0xBB), &sid(100));
+
+        cas.rename(tmp, Path::new("test.txt.tmp"), &path, Some(old));
+        cas.remove(old);
+
+        assert_eq!(cas.get_reader_hash(&sid(100), tmp), Some(make_hash(0xBB)));
+        assert_eq!(cas.get_reader_hash(&sid(200), tmp), Some(make_hash(0xAA)));
+        let fh = handles.alloc(-1, path.clone(), Some(tmp), libc::O_WRONLY, None, sid(200));
+        let result = cas.check_and_acquire_write(tmp, &path, fh, &sid(200), &handles, &make_hash(0xBB));
+        assert!(result.is_err(), "Stale write after rename-over should fail");
+    }
+
+    /// Two paths to the same file share one receipt
+    #[test]
+    fn test_aliased_paths_share_receipts() {
+        let cas = CasTable::new();
+        let handles = HandleTable::new();
+        let id = file_id(1);
+        let alias = PathBuf::from("alias/test.txt");
+
+        // SID 100 reads through one path, the file changes, and it writes
+        // through another
+        cas.record_reader(id, make_hash(0xAA), &sid(100));
+        let fh = handles.alloc(-1, alias.clone(), Some(id), libc::O_WRONLY, None, sid(100));
+        let result = cas.check_and_acquire_write(id, &alias, fh, &sid(100), &handles, &make_hash(0xBB));
+        assert!(result.is_err(), "Stale write through an alias should fail");
+    }
+
+    /// Exchange swaps labels and leaves receipts with their files
+    #[test]
+    fn test_exchange_swaps_labels() {
+        let cas = CasTable::new();
+        let (a, b) = (file_id(1), file_id(2));
+        cas.record_reader(a, make_hash(0xAA), &sid(100));
+        cas.ensure_entry(a, Path::new("a.txt"));
+        cas.ensure_entry(b, Path::new("b.txt"));
 
-        cas.rename(&old, &new);
+        cas.exchange(a, Path::new("a.txt"), b, Path::new("b.txt"));
 
-        assert!(!cas.reader_hashes.contains_key(&(100, old.clone())));
-        assert!(!cas.reader_hashes.contains_key(&(200, old.clone())));
-        assert!(cas.reader_hashes.contains_key(&(100, new.clone())));
-        assert!(cas.reader_hashes.contains_key(&(200, new.clone())));
-        assert!(cas.entries.contains_key(&new));
-        assert!(!cas.entries.contains_key(&old));
+        assert_eq!(cas.entries.get(&a).unwrap().lock().path, Path::new("b.txt"));
+        assert_eq!(cas.entries.get(&b).unwrap().lock().path, Path::new("a.txt"));
+        assert_eq!(cas.get_reader_hash(&sid(100), a), Some(make_hash(0xAA)));
+        assert_eq!(cas.get_reader_hash(&sid(100), b), None);
     }
 
     /// O_RDWR handle uses hash_at_open for CAS check
@@ -409,18 +1103,19 @@ mod tests {
         let cas = CasTable::new();
         let handles = HandleTable::new();
         let path = PathBuf::from("test.txt");
+        let id = file_id(1);
         let h0 = make_hash(0xAA);
 
-        cas.record_reader(&path, h0.clone(), 100);
+        cas.record_reader(id, h0.clone(), &sid(100));
 
         // O_RDWR handle has hash_at_open set
-        let fh = handles.alloc(-1, path.clone(), libc::O_RDWR, Some(h0.clone()), 100);
+        let fh = handles.alloc(-1, path.clone(), Some(id), libc::O_RDWR, Some(h0.clone()), sid(100));
 
         // Actual file hash has changed (another agent wrote)
         let h1 = make_hash(0xBB);
 
         // Write should fail — hash_at_open (0xAA) != actual hash (0xBB)
-        let result = cas.check_and_acquire_write(&path, fh, 100, &handles, &h1);
+        let result = cas.check_and_acquire_write(id, &path, fh, &sid(100), &handles, &h1);
         assert!(result.is_err(), "O_RDWR write should fail when file hash changed");
     }
 }
@@ -1,2 +1,5 @@
+pub mod conflicts;
 pub mod eviction;
+pub mod explain;
 pub mod hash_table;
+pub mod objects;
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::fs::cas;
+
+struct StoredObject {
+    data: Arc<[u8]>,
+    /// Tick of the last insert or read, from `ObjectStore::clock`.
+    tick: AtomicU64,
+    /// Time of the last insert or read, from `now`.
+    last_access: AtomicI64,
+}
+
+impl StoredObject {
+    fn touch(&self, tick: u64) {
+        self.tick.store(tick, Ordering::Relaxed);
+        self.last_access.store(now(), Ordering::Relaxed);
+    }
+}
+
+/// In-memory, content-addressed store of file versions, keyed by the hashes
+/// from `cas::hash_file`. Holds the content behind reader receipts so that
+/// what a session saw can be recovered, diffed and merged against.
+///
+/// Bounded by total size: inserting past the limit drops the least recently
+/// used objects first.
+pub struct ObjectStore {
+    objects: DashMap<Vec<u8>, StoredObject>,
+    /// Hashes by the tick they were queued at, oldest first. Reads only move
+    /// an object's own tick, so that they don't have to take this lock; an
+    /// object read since it was queued is queued again at its new tick when
+    /// it comes up for dropping.
+    queue: Mutex<BTreeMap<u64, Vec<u8>>>,
+    /// Source of access ticks.
+    clock: AtomicU64,
+    /// Total bytes held.
+    size: AtomicU64,
+    /// Size limit in bytes (0 = store nothing).
+    max_bytes: u64,
+}
+
+impl ObjectStore {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            objects: DashMap::new(),
+            queue: Mutex::new(BTreeMap::new()),
+            clock: AtomicU64::new(0),
+            size: AtomicU64::new(0),
+            max_bytes,
+        }
+    }
+
+    /// Largest single object the store accepts.
+    pub fn max_object_size(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Store `data`, returning its hash. The data isn't kept if it's larger
+    /// than the store, but the hash is still returned.
+    pub fn insert(&self, data: Vec<u8>) -> Vec<u8> {
+        let hash = cas::hash_bytes(&data);
+        let len = data.len() as u64;
+        if len > self.max_bytes {
+            return hash;
+        }
+        let tick = self.tick();
+        match self.objects.entry(hash.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(e) => {
+                e.get().touch(tick);
+                return hash;
+            }
+            dashmap::mapref::entry::Entry::Vacant(e) => {
+                e.insert(StoredObject {
+                    data: Arc::from(data),
+                    tick: AtomicU64::new(tick),
+                    last_access: AtomicI64::new(now()),
+                });
+            }
+        }
+        self.queue.lock().insert(tick, hash.clone());
+        self.size.fetch_add(len, Ordering::Relaxed);
+        self.shrink_to(self.max_bytes, Some(&hash));
+        hash
+    }
+
+    /// Content for a hash, if it is still held.
+    pub fn get(&self, hash: &[u8]) -> Option<Arc<[u8]>> {
+        self.objects.get(hash).map(|obj| {
+            obj.touch(self.tick());
+            obj.data.clone()
+        })
+    }
+
+    /// Hex hashes of all held objects, sorted.
+    pub fn list(&self) -> Vec<String> {
+        let mut hashes: Vec<String> = self.objects.iter().map(|e| cas::hash_hex(e.key())).collect();
+        hashes.sort();
+        hashes
+    }
+
+    /// Content for a hex hash, as named under .dibs/objects/.
+    pub fn get_hex(&self, hex: &str) -> Option<Arc<[u8]>> {
+        self.get(&parse_hex(hex)?)
+    }
+
+    /// Total bytes held.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Number of objects held.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Whether no objects are held.
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Drop objects that no reader receipt refers to anymore, and objects
+    /// that haven't been accessed in the given duration.
+    pub fn evict(&self, duration: std::time::Duration, is_referenced: impl Fn(&[u8]) -> bool) {
+        let cutoff = now() - duration.as_nanos().min(i64::MAX as u128) as i64;
+        let to_remove: Vec<Vec<u8>> = self
+            .objects
+            .iter()
+            .filter(|e| e.value().last_access.load(Ordering::Relaxed) < cutoff || !is_referenced(e.key()))
+            .map(|e| e.key().clone())
+            .collect();
+        for hash in to_remove {
+            self.remove(&hash);
+        }
+        self.queue.lock().retain(|_, hash| self.objects.contains_key(hash));
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Drop least recently used objects until at most `limit` bytes are
+    /// held, never dropping `keep`.
+    fn shrink_to(&self, limit: u64, keep: Option<&[u8]>) {
+        let mut queue = self.queue.lock();
+        let mut kept = None;
+        while self.size() > limit {
+            let Some((tick, hash)) = queue.pop_first() else {
+                break;
+            };
+            let Some(current) = self.objects.get(&hash).map(|obj| obj.tick.load(Ordering::Relaxed)) else {
+                continue;
+            };
+            if Some(hash.as_slice()) == keep {
+                kept = Some((current, hash));
+            } else if current != tick {
+                queue.insert(current, hash);
+            } else {
+                self.remove(&hash);
+            }
+        }
+        if let Some((tick, hash)) = kept {
+            queue.insert(tick, hash);
+        }
+    }
+
+    fn remove(&self, hash: &[u8]) {
+        if let Some((_, obj)) = self.objects.remove(hash) {
+            self.size.fetch_sub(obj.data.len() as u64, Ordering::Relaxed);
+            debug!("Evicted object {}", cas::hash_hex(hash));
+        }
+    }
+}
+
+/// The time in nanoseconds since the epoch.
+fn now() -> i64 {
+    Utc::now().timestamp_nanos_opt().unwrap_or(i64::MAX)
+}
+
+/// Parse a lowercase hex hash as produced by `cas::hash_hex`.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Objects are keyed by the same hash hash_file would produce
+    #[test]
+    fn test_insert_and_get_by_hash() {
+        let store = ObjectStore::new(1024);
+        let hash = store.insert(b"hello".to_vec());
+
+        assert_eq!(hash, cas::hash_bytes(b"hello"));
+        assert_eq!(&*store.get(&hash).unwrap(), b"hello");
+        assert_eq!(&*store.get_hex(&cas::hash_hex(&hash)).unwrap(), b"hello");
+        assert_eq!(store.list(), vec![cas::hash_hex(&hash)]);
+        assert!(store.get_hex("zz").is_none());
+    }
+
+    /// Identical content is stored once
+    #[test]
+    fn test_dedup() {
+        let store = ObjectStore::new(1024);
+        store.insert(b"same".to_vec());
+        store.insert(b"same".to_vec());
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.size(), 4);
+    }
+
+    /// Going over the size limit drops the least recently used object
+    #[test]
+    fn test_size_limit_drops_lru() {
+        let store = ObjectStore::new(10);
+        let a = store.insert(b"aaaa".to_vec());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let b = store.insert(b"bbbb".to_vec());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        store.get(&a);
+        let c = store.insert(b"cccc".to_vec());
+
+        assert!(store.get(&a).is_some());
+        assert!(store.get(&b).is_none(), "Least recently used object should be dropped");
+        assert!(store.get(&c).is_some());
+        assert_eq!(store.size(), 8);
+
+        // Too large to ever fit: hash is returned, nothing is stored
+        let big = store.insert(vec![0u8; 11]);
+        assert!(store.get(&big).is_none());
+    }
+
+    /// One insert can drop many objects, oldest first
+    #[test]
+    fn test_size_limit_drops_many() {
+        let store = ObjectStore::new(100);
+        let small: Vec<_> = (0..50u8).map(|i| store.insert(vec![i; 2])).collect();
+        store.get(&small[0]);
+        let big = store.insert(vec![0xff; 80]);
+
+        assert!(store.get(&big).is_some());
+        assert!(store.get(&small[0]).is_some(), "Recently read object should be kept");
+        let kept: Vec<_> = small.iter().filter(|h| store.get(h).is_some()).collect();
+        assert_eq!(kept.len(), 10);
+        assert_eq!(kept[1..], small[41..].iter().collect::<Vec<_>>()[..]);
+        assert_eq!(store.size(), 100);
+    }
+
+    /// Eviction drops unreferenced and idle objects
+    #[test]
+    fn test_evict() {
+        let store = ObjectStore::new(1024);
+        let kept = store.insert(b"kept".to_vec());
+        let orphan = store.insert(b"orphan".to_vec());
+
+        store.evict(std::time::Duration::from_secs(60), |h| h == kept.as_slice());
+        assert!(store.get(&kept).is_some());
+        assert!(store.get(&orphan).is_none());
+
+        store.evict(std::time::Duration::from_secs(0), |_| true);
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.size(), 0);
+    }
+}
@@ -6,14 +6,42 @@ use std::time::Duration;
 pub struct TestMount {
     pub backing: tempfile::TempDir,
     pub mount_dir: tempfile::TempDir,
+    /// Per-agent mount points from `--agent NAME=MOUNTPOINT`.
+    pub agent_dirs: Vec<(String, tempfile::TempDir)>,
     pub process: Option<Child>,
 }
 
 impl TestMount {
     /// Create a new test mount. Starts dibs in the background.
     pub fn new() -> Self {
+        Self::with_args(&[])
+    }
+
+    /// Create a new test mount, passing extra options to `dibs mount`.
+    pub fn with_args(extra_args: &[&str]) -> Self {
+        Self::with_agents(&[], extra_args)
+    }
+
+    /// Create a new test mount that also gives each of `agents` its own
+    /// mount point.
+    pub fn with_agents(agents: &[&str], extra_args: &[&str]) -> Self {
         let backing = tempfile::tempdir().expect("failed to create backing dir");
         let mount_dir = tempfile::tempdir().expect("failed to create mount dir");
+        let agent_dirs: Vec<(String, tempfile::TempDir)> = agents
+            .iter()
+            .map(|name| (name.to_string(), tempfile::tempdir().expect("failed to create agent mount dir")))
+            .collect();
+        let agent_args: Vec<String> = agent_dirs
+            .iter()
+            .flat_map(|(name, dir)| ["--agent".to_string(), format!("{}={}", name, dir.path().display())])
+            .collect();
+
+        // Tests that need eviction set their own window
+        let eviction_args: &[&str] = if extra_args.contains(&"--eviction-minutes") {
+            &[]
+        } else {
+            &["--eviction-minutes", "60"]
+        };
 
         let dibs_bin = dibs_binary();
         let child = Command::new(&dibs_bin)
@@ -24,19 +52,24 @@ impl TestMount {
                 "-f",
                 "--log-file",
                 "/tmp/dibs-test.log",
-                "--eviction-minutes",
-                "60",
                 "--save-conflicts",
             ])
+            .args(eviction_args)
+            .args(&agent_args)
+            .args(extra_args)
             .spawn()
             .expect("failed to start dibs");
 
         // Wait for mount to be ready
         wait_for_mount(mount_dir.path());
+        for (_, dir) in &agent_dirs {
+            wait_for_mount(dir.path());
+        }
 
         TestMount {
             backing,
             mount_dir,
+            agent_dirs,
             process: Some(child),
         }
     }
@@ -48,12 +81,21 @@ impl TestMount {
     pub fn mount_path(&self) -> &Path {
         self.mount_dir.path()
     }
+
+    /// Mount point of an agent passed to `with_agents`.
+    pub fn agent_path(&self, name: &str) -> &Path {
+        let (_, dir) = self.agent_dirs.iter().find(|(n, _)| n == name).expect("unknown agent");
+        dir.path()
+    }
 }
 
 impl Drop for TestMount {
     fn drop(&mut self) {
         if let Some(mut child) = self.process.take() {
             // Unmount
+            for (_, dir) in &self.agent_dirs {
+                let _ = Command::new("umount").arg(dir.path()).status();
+            }
             let mp = self.mount_dir.path().to_str().unwrap();
             let _ = Command::new("umount").arg(mp).status();
             // Give it a moment
@@ -133,3 +175,41 @@ fn wait_for_mount(mount_path: &Path) {
     // Give it one more second
     std::thread::sleep(Duration::from_secs(1));
 }
+
+/// Spawn a `dibs-test-agent` that reads `file`, signals `<name>.ready`, and
+/// writes `content` once `<name>.go` appears in `sync_dir`.
+pub fn spawn_agent(file: &Path, sync_dir: &Path, name: &str, content: &str) -> Child {
+    spawn_agent_with_env(file, sync_dir, name, content, &[])
+}
+
+/// Like `spawn_agent`, with extra environment variables for the agent.
+pub fn spawn_agent_with_env(file: &Path, sync_dir: &Path, name: &str, content: &str, env: &[(&str, &str)]) -> Child {
+    let child = Command::new(test_agent_binary())
+        .args([
+            file.to_str().unwrap(),
+            sync_dir.to_str().unwrap(),
+            name,
+            content,
+        ])
+        .envs(env.iter().copied())
+        .spawn()
+        .expect("failed to spawn test agent");
+    assert!(
+        wait_for_file(&sync_dir.join(format!("{}.ready", name)), Duration::from_secs(10)),
+        "Agent {} did not become ready",
+        name
+    );
+    child
+}
+
+/// Tell a spawned agent to write, and return its result line.
+pub fn agent_write(sync_dir: &Path, name: &str) -> String {
+    std::fs::write(sync_dir.join(format!("{}.go", name)), "").unwrap();
+    let result = sync_dir.join(format!("{}.result", name));
+    assert!(
+        wait_for_file(&result, Duration::from_secs(10)),
+        "Agent {} did not produce a result",
+        name
+    );
+    std::fs::read_to_string(result).unwrap()
+}
@@ -65,7 +65,7 @@ fn main() {
     }
 
     // Write to the file (goes through FUSE, triggers CAS check)
-    match fs::write(file_path, content) {
+    match write_and_close(file_path, content) {
         Ok(_) => {
             fs::write(
                 sync_dir.join(format!("{}.result", agent_name)),
@@ -82,3 +82,18 @@ fn main() {
         }
     }
 }
+
+/// Like `fs::write`, but reports errors from `close()` too — dibs reports
+/// deferred rejections at flush time, which `File`'s drop would swallow.
+fn write_and_close(path: &str, content: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+
+    let mut file = fs::File::create(path)?;
+    file.write_all(content.as_bytes())?;
+    let fd = file.into_raw_fd();
+    if unsafe { libc::close(fd) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
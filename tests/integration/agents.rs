@@ -0,0 +1,48 @@
+use std::fs;
+
+use crate::helpers::TestMount;
+
+/// Each --agent mount point is its own agent over the shared state: a stale
+/// write through one mount is rejected after a write through another
+#[test]
+fn test_agent_mounts_share_cas_state() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("shared.txt"), "original").unwrap();
+    let alpha_file = mount.agent_path("alpha").join("shared.txt");
+    let beta_file = mount.agent_path("beta").join("shared.txt");
+
+    assert_eq!(fs::read_to_string(&alpha_file).unwrap(), "original");
+    assert_eq!(fs::read_to_string(&beta_file).unwrap(), "original");
+
+    fs::write(&alpha_file, "from alpha").unwrap();
+    assert!(fs::write(&beta_file, "from beta").is_err(), "Stale write through beta's mount should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("shared.txt")).unwrap(), "from alpha");
+
+    // Everyone sees the same file through their own mount
+    assert_eq!(fs::read_to_string(mount.mount_path().join("shared.txt")).unwrap(), "from alpha");
+}
+
+/// .dibs/status names the mount's agent and lists every agent's view
+#[test]
+fn test_agent_mounts_status() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "a").unwrap();
+    fs::read(mount.agent_path("alpha").join("a.txt")).unwrap();
+
+    let status_path = mount.agent_path("beta").join(".dibs/status");
+    let status: serde_json::Value = serde_json::from_slice(&fs::read(status_path).unwrap()).unwrap();
+    assert_eq!(status["agent"], "beta");
+
+    let agents = status["agents"].as_array().unwrap();
+    assert_eq!(agents.len(), 2);
+    let alpha = agents.iter().find(|a| a["name"] == "alpha").unwrap();
+    assert_eq!(alpha["files_read"], 1);
+    assert!(alpha["last_conflict"].is_null());
+    let beta = agents.iter().find(|a| a["name"] == "beta").unwrap();
+    assert_eq!(beta["files_read"], 0);
+
+    // The shared mount point has no agent of its own
+    let status: serde_json::Value =
+        serde_json::from_slice(&fs::read(mount.mount_path().join(".dibs/status")).unwrap()).unwrap();
+    assert!(status["agent"].is_null());
+}
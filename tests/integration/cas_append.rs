@@ -0,0 +1,96 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::helpers::TestMount;
+
+fn append(path: &Path, data: &str) -> std::io::Result<()> {
+    fs::OpenOptions::new().append(true).open(path)?.write_all(data.as_bytes())
+}
+
+#[test]
+fn test_append_policy() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &["--policy", "NOTES.md=append"]);
+    fs::write(mount.backing_path().join("NOTES.md"), "# Notes\n").unwrap();
+    fs::write(mount.backing_path().join("TODO.md"), "# Todo\n").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    for agent in [&alpha, &beta] {
+        assert_eq!(fs::read_to_string(agent.join("NOTES.md")).unwrap(), "# Notes\n");
+        assert_eq!(fs::read_to_string(agent.join("TODO.md")).unwrap(), "# Todo\n");
+    }
+    append(&alpha.join("NOTES.md"), "alpha\n").unwrap();
+    append(&beta.join("NOTES.md"), "beta\n").unwrap();
+    assert_eq!(
+        fs::read_to_string(mount.backing_path().join("NOTES.md")).unwrap(),
+        "# Notes\nalpha\nbeta\n"
+    );
+
+    // Without the policy, an append is a write like any other
+    append(&alpha.join("TODO.md"), "alpha\n").unwrap();
+    let err = append(&beta.join("TODO.md"), "beta\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+    // Appends left both agents current; a rewrite does not
+    fs::write(alpha.join("NOTES.md"), "# Notes\nrewritten\n").unwrap();
+    let err = fs::write(beta.join("NOTES.md"), "# Notes\nbeta\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    append(&beta.join("NOTES.md"), "still appending\n").unwrap();
+}
+
+#[test]
+fn test_concurrent_appends() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &["--policy", "*.log=append"]);
+    fs::write(mount.backing_path().join("progress.log"), "").unwrap();
+
+    let writers: Vec<_> = ["alpha", "beta"]
+        .into_iter()
+        .map(|name| {
+            let path = mount.agent_path(name).join("progress.log");
+            std::thread::spawn(move || {
+                for i in 0..50 {
+                    append(&path, &format!("{} {}\n", name, i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let log = fs::read_to_string(mount.backing_path().join("progress.log")).unwrap();
+    for name in ["alpha", "beta"] {
+        let lines: Vec<_> = log.lines().filter(|l| l.starts_with(name)).collect();
+        let expected: Vec<_> = (0..50).map(|i| format!("{} {}", name, i)).collect();
+        assert_eq!(lines, expected, "{}", log);
+    }
+    assert_eq!(log.lines().count(), 100, "{}", log);
+}
+
+#[test]
+fn test_append_run_settles_at_close() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &["--policy", "*.log=append"]);
+    fs::write(mount.backing_path().join("build.log"), "start\n").unwrap();
+    let alpha = mount.agent_path("alpha").join("build.log");
+    let beta = mount.agent_path("beta").join("build.log");
+    assert_eq!(fs::read_to_string(&alpha).unwrap(), "start\n");
+
+    // Many appends through one handle leave alpha current once it's closed
+    let mut log = fs::OpenOptions::new().append(true).open(&beta).unwrap();
+    for i in 0..100 {
+        writeln!(log, "step {}", i).unwrap();
+    }
+    drop(log);
+    fs::write(&alpha, "restart\n").unwrap();
+
+    // A change in between that isn't an append leaves alpha behind
+    assert_eq!(fs::read_to_string(&alpha).unwrap(), "restart\n");
+    let mut log = fs::OpenOptions::new().append(true).open(&beta).unwrap();
+    writeln!(log, "step 0").unwrap();
+    fs::write(mount.backing_path().join("build.log"), "edited\n").unwrap();
+    writeln!(log, "step 1").unwrap();
+    drop(log);
+    let err = fs::write(&alpha, "restart\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}
@@ -0,0 +1,322 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::helpers::{agent_write, spawn_agent, TestMount};
+
+/// Deferred rejection: the stale writer's open succeeds, the conflict is
+/// reported at close, and its complete content lands in the conflict store.
+#[test]
+fn test_deferred_rejection_saves_full_content() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("deferred.txt"), "original").unwrap();
+    let mount_file = mount.mount_path().join("deferred.txt");
+
+    let mut agent_a = spawn_agent(&mount_file, sync_dir.path(), "a", "A wrote this");
+    let mut agent_b = spawn_agent(&mount_file, sync_dir.path(), "b", "B's complete edit");
+
+    let a_result = agent_write(sync_dir.path(), "a");
+    let b_result = agent_write(sync_dir.path(), "b");
+    let _ = agent_a.wait();
+    let _ = agent_b.wait();
+
+    assert_eq!(a_result, "ok", "A's write should succeed");
+    assert!(
+        b_result.starts_with("error"),
+        "B's write should be rejected at close, got: {}",
+        b_result
+    );
+
+    // A's content is intact, B's is kept in full.
+    assert_eq!(
+        fs::read_to_string(mount.backing_path().join("deferred.txt")).unwrap(),
+        "A wrote this"
+    );
+    let ids = conflict_ids(mount.mount_path());
+    assert_eq!(ids.len(), 1, "Expected one saved conflict, got: {:?}", ids);
+    let entry = mount.mount_path().join(".dibs/conflicts").join(&ids[0]);
+    assert_eq!(fs::read_to_string(entry.join("rejected")).unwrap(), "B's complete edit");
+    assert_eq!(fs::read_to_string(entry.join("current")).unwrap(), "A wrote this");
+}
+
+/// Conflicts on files with the same name in different directories get
+/// separate entries in .dibs/conflicts/, each with its own metadata.
+#[test]
+fn test_conflict_registry_browsable_through_mount() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+
+    for dir in ["a", "b"] {
+        fs::create_dir(mount.backing_path().join(dir)).unwrap();
+        fs::write(mount.backing_path().join(dir).join("index.ts"), "original").unwrap();
+
+        let sync_dir = tempfile::tempdir().unwrap();
+        let mount_file = mount.mount_path().join(dir).join("index.ts");
+        let mut first = spawn_agent(&mount_file, sync_dir.path(), "first", "first edit");
+        let mut second = spawn_agent(&mount_file, sync_dir.path(), "second", "second edit");
+        assert_eq!(agent_write(sync_dir.path(), "first"), "ok");
+        assert!(agent_write(sync_dir.path(), "second").starts_with("error"));
+        let _ = first.wait();
+        let _ = second.wait();
+    }
+
+    let ids = conflict_ids(mount.mount_path());
+    assert_eq!(ids.len(), 2, "Expected two saved conflicts, got: {:?}", ids);
+
+    let mut paths = Vec::new();
+    for id in &ids {
+        let entry = mount.mount_path().join(".dibs/conflicts").join(id);
+        let meta: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(entry.join("meta.json")).unwrap()).unwrap();
+        assert_eq!(meta["id"], id.as_str());
+        assert!(meta["agent"].as_str().unwrap().starts_with("session "));
+        assert!(meta["reader_hash"].is_string());
+        assert!(meta["actual_hash"].is_string());
+        assert!(meta["time"].is_string());
+        assert_eq!(fs::read_to_string(entry.join("rejected")).unwrap(), "second edit");
+        assert_eq!(fs::read_to_string(entry.join("current")).unwrap(), "first edit");
+        paths.push(meta["path"].as_str().unwrap().to_string());
+    }
+    paths.sort();
+    assert_eq!(paths, vec!["a/index.ts", "b/index.ts"]);
+}
+
+/// Auto-merge: two agents adding different functions to the same file both
+/// succeed, and the file ends up with both functions.
+#[test]
+fn test_auto_merge_combines_independent_edits() {
+    let mount = TestMount::with_args(&["--auto-merge"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    let original = "fn main() {}\n\nfn helper() {}\n";
+    fs::write(mount.backing_path().join("lib.rs"), original).unwrap();
+    let mount_file = mount.mount_path().join("lib.rs");
+
+    let a_content = "fn from_a() {}\n\nfn main() {}\n\nfn helper() {}\n";
+    let b_content = "fn main() {}\n\nfn helper() {}\n\nfn from_b() {}\n";
+    let mut agent_a = spawn_agent(&mount_file, sync_dir.path(), "a", a_content);
+    let mut agent_b = spawn_agent(&mount_file, sync_dir.path(), "b", b_content);
+
+    let a_result = agent_write(sync_dir.path(), "a");
+    let b_result = agent_write(sync_dir.path(), "b");
+    let _ = agent_a.wait();
+    let _ = agent_b.wait();
+
+    assert_eq!(a_result, "ok", "A's write should succeed");
+    assert_eq!(b_result, "ok", "B's stale write should be merged");
+    assert_eq!(
+        fs::read_to_string(mount.backing_path().join("lib.rs")).unwrap(),
+        "fn from_a() {}\n\nfn main() {}\n\nfn helper() {}\n\nfn from_b() {}\n"
+    );
+    assert!(conflict_ids(mount.mount_path()).is_empty());
+}
+
+/// Auto-merge: after a merge, the writer is only current with what it
+/// wrote, so rewriting its own copy doesn't undo the other agent's edit.
+#[test]
+fn test_auto_merge_rewrite_keeps_other_edit() {
+    let mount = TestMount::with_args(&["--auto-merge"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("lib.rs"), "fn main() {}\n").unwrap();
+    let mount_file = mount.mount_path().join("lib.rs");
+
+    // This process reads, then another session adds a function before main
+    assert_eq!(fs::read_to_string(&mount_file).unwrap(), "fn main() {}\n");
+    let mut agent = spawn_agent(&mount_file, sync_dir.path(), "a", "fn from_a() {}\n\nfn main() {}\n");
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    let _ = agent.wait();
+
+    let b_content = "fn main() {}\n\nfn from_b() {}\n";
+    fs::write(&mount_file, b_content).unwrap();
+    let merged = "fn from_a() {}\n\nfn main() {}\n\nfn from_b() {}\n";
+    assert_eq!(fs::read_to_string(mount.backing_path().join("lib.rs")).unwrap(), merged);
+
+    fs::write(&mount_file, b_content).unwrap();
+    assert_eq!(fs::read_to_string(mount.backing_path().join("lib.rs")).unwrap(), merged);
+}
+
+/// Auto-merge: JSON files are merged by key, so two agents adding
+/// dependencies on neighbouring lines don't conflict.
+#[test]
+fn test_auto_merge_json_keys() {
+    let mount = TestMount::with_args(&["--auto-merge"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    let original = "{\n  \"dependencies\": {\n    \"react\": \"^18\"\n  }\n}\n";
+    fs::write(mount.backing_path().join("package.json"), original).unwrap();
+    let mount_file = mount.mount_path().join("package.json");
+
+    let a_content = "{\n  \"dependencies\": {\n    \"react\": \"^18\",\n    \"zod\": \"^3\"\n  }\n}\n";
+    let b_content = "{\n  \"dependencies\": {\n    \"react\": \"^18\",\n    \"axios\": \"^1\"\n  }\n}\n";
+    let mut agent_a = spawn_agent(&mount_file, sync_dir.path(), "a", a_content);
+    let mut agent_b = spawn_agent(&mount_file, sync_dir.path(), "b", b_content);
+
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    assert_eq!(agent_write(sync_dir.path(), "b"), "ok", "B's dependency should be merged");
+    let _ = agent_a.wait();
+    let _ = agent_b.wait();
+
+    assert_eq!(
+        fs::read_to_string(mount.backing_path().join("package.json")).unwrap(),
+        "{\n  \"dependencies\": {\n    \"react\": \"^18\",\n    \"zod\": \"^3\",\n    \"axios\": \"^1\"\n  }\n}\n"
+    );
+}
+
+/// Auto-merge: edits to the same lines are still rejected and saved.
+#[test]
+fn test_auto_merge_rejects_overlapping_edits() {
+    let mount = TestMount::with_args(&["--auto-merge"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("config.txt"), "port = 80\nhost = a\n").unwrap();
+    let mount_file = mount.mount_path().join("config.txt");
+
+    let mut agent_a = spawn_agent(&mount_file, sync_dir.path(), "a", "port = 8080\nhost = a\n");
+    let mut agent_b = spawn_agent(&mount_file, sync_dir.path(), "b", "port = 9090\nhost = a\n");
+
+    let a_result = agent_write(sync_dir.path(), "a");
+    let b_result = agent_write(sync_dir.path(), "b");
+    let _ = agent_a.wait();
+    let _ = agent_b.wait();
+
+    assert_eq!(a_result, "ok", "A's write should succeed");
+    assert!(b_result.starts_with("error"), "B's write should be rejected, got: {}", b_result);
+    assert_eq!(
+        fs::read_to_string(mount.backing_path().join("config.txt")).unwrap(),
+        "port = 8080\nhost = a\n"
+    );
+
+    let ids = conflict_ids(mount.mount_path());
+    assert_eq!(ids.len(), 1, "Expected one saved conflict, got: {:?}", ids);
+    let entry = mount.mount_path().join(".dibs/conflicts").join(&ids[0]);
+    assert_eq!(fs::read_to_string(entry.join("rejected")).unwrap(), "port = 9090\nhost = a\n");
+    let meta: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(entry.join("meta.json")).unwrap()).unwrap();
+    assert!(meta["reason"].as_str().unwrap().contains("auto-merge failed"));
+}
+
+/// The version a rejected writer last read can be looked up in
+/// .dibs/objects/ by the reader hash recorded with the conflict.
+#[test]
+fn test_rejected_writer_view_in_objects() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("notes.md"), "what B saw").unwrap();
+    let mount_file = mount.mount_path().join("notes.md");
+
+    let mut agent_a = spawn_agent(&mount_file, sync_dir.path(), "a", "A's version");
+    let mut agent_b = spawn_agent(&mount_file, sync_dir.path(), "b", "B's version");
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    assert!(agent_write(sync_dir.path(), "b").starts_with("error"));
+    let _ = agent_a.wait();
+    let _ = agent_b.wait();
+
+    let ids = conflict_ids(mount.mount_path());
+    assert_eq!(ids.len(), 1, "Expected one saved conflict, got: {:?}", ids);
+    let entry = mount.mount_path().join(".dibs/conflicts").join(&ids[0]);
+    let meta: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(entry.join("meta.json")).unwrap()).unwrap();
+    let objects = mount.mount_path().join(".dibs/objects");
+    let seen = objects.join(meta["reader_hash"].as_str().unwrap());
+    let current = objects.join(meta["actual_hash"].as_str().unwrap());
+    assert_eq!(fs::read_to_string(seen).unwrap(), "what B saw");
+    assert_eq!(fs::read_to_string(current).unwrap(), "A's version");
+}
+
+/// A rejected session finds a diff of what changed in .dibs/self/
+#[test]
+fn test_last_conflict_diff_for_rejected_session() {
+    let mount = TestMount::new();
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("api.ts"), "one\ntwo\nthree\n").unwrap();
+    let mount_file = mount.mount_path().join("api.ts");
+    let self_dir = mount.mount_path().join(".dibs/self");
+
+    // This process reads, then another session changes the file
+    assert_eq!(fs::read_to_string(&mount_file).unwrap(), "one\ntwo\nthree\n");
+    let mut agent = spawn_agent(&mount_file, sync_dir.path(), "a", "one\n2\nthree\n");
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    let _ = agent.wait();
+    assert_eq!(fs::read_dir(&self_dir).unwrap().count(), 0, "No conflict yet");
+
+    let err = fs::write(&mount_file, "one\ntwo\nthree\nfour\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+    let report = fs::read_to_string(self_dir.join("last-conflict.diff")).unwrap();
+    assert!(report.contains("Write to api.ts was rejected"), "{}", report);
+    assert!(report.contains("Changed by:  session "), "{}", report);
+    assert!(
+        report.contains("--- a/api.ts (you read)\n+++ b/api.ts (current)\n"),
+        "{}",
+        report
+    );
+    assert!(report.contains("-two\n+2\n"), "{}", report);
+}
+
+/// A file read under one name and written under another is still checked
+/// against the read: CAS state belongs to the backing file, not the path.
+#[test]
+fn test_stale_write_through_alias() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "original").unwrap();
+    fs::hard_link(mount.backing_path().join("a.txt"), mount.backing_path().join("alias.txt")).unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("alias.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "original");
+    fs::write(alpha.join("a.txt"), "from alpha").unwrap();
+
+    assert!(fs::write(beta.join("a.txt"), "from beta").is_err(), "Stale write under another name should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("a.txt")).unwrap(), "from alpha");
+}
+
+fn conflict_ids(mount: &Path) -> Vec<String> {
+    let mut ids: Vec<String> = fs::read_dir(mount.join(".dibs/conflicts"))
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    ids.sort();
+    ids
+}
+
+/// A stale writer's deferred data stays in its shadow file: other handles
+/// never read it, the file's size doesn't follow it, and the shadow file
+/// can't be reached through the mount.
+#[test]
+fn test_deferred_writes_stay_private() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    // Whole pages, which the kernel would keep as written
+    let page = |c: char| c.to_string().repeat(4096);
+    fs::write(mount.backing_path().join("shared.txt"), page('o')).unwrap();
+    let mount_file = mount.mount_path().join("shared.txt");
+
+    // This process reads, then another session changes the file
+    let mut reader = fs::File::open(&mount_file).unwrap();
+    let mut content = String::new();
+    reader.read_to_string(&mut content).unwrap();
+    let mut agent = spawn_agent(&mount_file, sync_dir.path(), "a", &page('a'));
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    let _ = agent.wait();
+
+    let mut stale = fs::OpenOptions::new().write(true).open(&mount_file).unwrap();
+    stale.write_all(page('x').as_bytes()).unwrap();
+    content.clear();
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    reader.read_to_string(&mut content).unwrap();
+    assert_eq!(content, page('a'));
+
+    let store = mount.mount_path().join(".dibs-conflicts");
+    assert!(fs::read_dir(&store).unwrap().all(|e| e.unwrap().file_name() != ".shadow"));
+    assert_eq!(fs::metadata(store.join(".shadow")).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    assert_eq!(fs::create_dir(store.join(".shadow")).unwrap_err().raw_os_error(), Some(libc::EACCES));
+    drop(stale);
+    assert_eq!(fs::read_to_string(&mount_file).unwrap(), page('a'));
+}
@@ -0,0 +1,53 @@
+use std::fs;
+
+use crate::helpers::TestMount;
+
+#[test]
+fn test_create_race() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert!(!alpha.join("new.ts").exists());
+    assert!(!beta.join("new.ts").exists());
+    fs::write(beta.join("new.ts"), "from beta").unwrap();
+
+    let err = fs::write(alpha.join("new.ts"), "from alpha").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("new.ts")).unwrap(), "from beta");
+    let report = fs::read_to_string(alpha.join(".dibs/self/last-conflict.diff")).unwrap();
+    assert!(report.contains("saw the path missing."), "{}", report);
+    assert!(report.contains("created since it was seen missing"), "{}", report);
+
+    // Having read what's there, alpha may overwrite it
+    assert_eq!(fs::read_to_string(alpha.join("new.ts")).unwrap(), "from beta");
+    fs::write(alpha.join("new.ts"), "from alpha").unwrap();
+}
+
+#[test]
+fn test_create_own_file() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    let alpha = mount.agent_path("alpha");
+
+    assert!(!alpha.join("new.ts").exists());
+    fs::write(alpha.join("new.ts"), "one").unwrap();
+    fs::write(alpha.join("new.ts"), "two").unwrap();
+    fs::create_dir(alpha.join("dir")).unwrap();
+    fs::write(alpha.join("dir/f.ts"), "three").unwrap();
+}
+
+#[test]
+fn test_rename_onto_created_path() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert!(!alpha.join("config.json").exists());
+    fs::write(beta.join("config.json"), "from beta").unwrap();
+
+    fs::write(alpha.join("config.json.tmp"), "from alpha").unwrap();
+    let err = fs::rename(alpha.join("config.json.tmp"), alpha.join("config.json")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("config.json")).unwrap(), "from beta");
+}
+
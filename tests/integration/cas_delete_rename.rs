@@ -1,4 +1,7 @@
+use std::ffi::CString;
 use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::time::Duration;
 
 use crate::helpers::TestMount;
@@ -29,3 +32,171 @@ fn test_delete_tracked_file_with_external_change() {
         "Delete should fail when file was externally modified"
     );
 }
+
+/// Saving by renaming a new file over the old one is a write: an agent that
+/// read the old file can't then overwrite the new one blind.
+#[test]
+fn test_stale_write_after_rename_over() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("config.json"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("config.json")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(alpha.join("config.json")).unwrap(), "original");
+    fs::write(alpha.join("config.json.tmp"), "from alpha").unwrap();
+    fs::rename(alpha.join("config.json.tmp"), alpha.join("config.json")).unwrap();
+
+    assert!(fs::write(beta.join("config.json"), "from beta").is_err(), "Stale write after rename-over should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("config.json")).unwrap(), "from alpha");
+
+    // The saving agent keeps writing freely
+    fs::write(alpha.join("config.json"), "alpha again").unwrap();
+}
+
+/// Receipts follow files when their directory is renamed
+#[test]
+fn test_rename_dir_keeps_receipts() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::create_dir_all(mount.backing_path().join("src/components")).unwrap();
+    fs::write(mount.backing_path().join("src/components/a.ts"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("src/components/a.ts")).unwrap(), "original");
+    fs::rename(alpha.join("src/components"), alpha.join("src/ui")).unwrap();
+    assert_eq!(fs::read_to_string(alpha.join("src/ui/a.ts")).unwrap(), "original");
+    fs::write(alpha.join("src/ui/a.ts"), "from alpha").unwrap();
+
+    assert!(fs::write(beta.join("src/ui/a.ts"), "from beta").is_err(), "Stale write after a directory rename should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("src/ui/a.ts")).unwrap(), "from alpha");
+}
+
+/// Receipts follow a file moved to another directory
+#[test]
+fn test_move_file_to_new_parent() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::create_dir(mount.backing_path().join("a")).unwrap();
+    fs::create_dir(mount.backing_path().join("b")).unwrap();
+    fs::write(mount.backing_path().join("a/x.txt"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("a/x.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(alpha.join("a/x.txt")).unwrap(), "original");
+    fs::rename(alpha.join("a/x.txt"), alpha.join("b/x.txt")).unwrap();
+    fs::write(alpha.join("b/x.txt"), "from alpha").unwrap();
+
+    assert!(fs::write(beta.join("b/x.txt"), "from beta").is_err(), "Stale write after a move should fail");
+    fs::write(alpha.join("b/x.txt"), "alpha again").unwrap();
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b/x.txt")).unwrap(), "alpha again");
+}
+
+/// A file open for writing while its directory is renamed is flushed under
+/// its new name, so the writer's receipt stays current
+#[test]
+fn test_open_handle_follows_dir_rename() {
+    use std::io::Write;
+
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    fs::create_dir(mount.backing_path().join("dir")).unwrap();
+    fs::write(mount.backing_path().join("dir/f.txt"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    assert_eq!(fs::read_to_string(alpha.join("dir/f.txt")).unwrap(), "original");
+    let mut file = fs::File::create(alpha.join("dir/f.txt")).unwrap();
+    fs::rename(alpha.join("dir"), alpha.join("moved")).unwrap();
+    file.write_all(b"written across the rename").unwrap();
+    drop(file);
+
+    fs::write(alpha.join("moved/f.txt"), "and again").unwrap();
+    let locks = fs::read_to_string(alpha.join(".dibs/locks")).unwrap();
+    assert!(locks.contains("moved/f.txt"), "{}", locks);
+}
+
+#[cfg(target_os = "linux")]
+const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE;
+#[cfg(target_os = "linux")]
+const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+#[cfg(not(target_os = "linux"))]
+const RENAME_NOREPLACE: u32 = libc::RENAME_EXCL;
+#[cfg(not(target_os = "linux"))]
+const RENAME_EXCHANGE: u32 = libc::RENAME_SWAP;
+
+/// `renameat2`, or `renameatx_np` on macOS.
+fn rename_with(old: &Path, new: &Path, flags: u32) -> std::io::Result<()> {
+    let old = CString::new(old.as_os_str().as_bytes()).unwrap();
+    let new = CString::new(new.as_os_str().as_bytes()).unwrap();
+    #[cfg(target_os = "linux")]
+    let ret = unsafe { libc::renameat2(libc::AT_FDCWD, old.as_ptr(), libc::AT_FDCWD, new.as_ptr(), flags) };
+    #[cfg(not(target_os = "linux"))]
+    let ret = unsafe { libc::renameatx_np(libc::AT_FDCWD, old.as_ptr(), libc::AT_FDCWD, new.as_ptr(), flags) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rename_noreplace_leaves_table() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "a").unwrap();
+    fs::write(mount.backing_path().join("b.txt"), "b").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(alpha.join("b.txt")).unwrap(), "b");
+    let err = rename_with(&alpha.join("a.txt"), &alpha.join("b.txt"), RENAME_NOREPLACE).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("a.txt")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "b");
+
+    // Both receipts are where they were
+    fs::write(alpha.join("a.txt"), "a2").unwrap();
+    fs::write(alpha.join("b.txt"), "b2").unwrap();
+
+    rename_with(&alpha.join("a.txt"), &alpha.join("c.txt"), RENAME_NOREPLACE).unwrap();
+    fs::write(alpha.join("c.txt"), "c").unwrap();
+    assert!(!mount.backing_path().join("a.txt").exists());
+}
+
+#[test]
+fn test_rename_exchange_swaps_receipts() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "a").unwrap();
+    fs::write(mount.backing_path().join("b.txt"), "b").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(alpha.join("b.txt")).unwrap(), "b");
+    assert_eq!(fs::read_to_string(beta.join("b.txt")).unwrap(), "b");
+    rename_with(&alpha.join("a.txt"), &alpha.join("b.txt"), RENAME_EXCHANGE).unwrap();
+    assert_eq!(fs::read_to_string(mount.backing_path().join("a.txt")).unwrap(), "b");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "a");
+
+    // Beta's receipt went along with the file it read, now at a.txt
+    fs::write(alpha.join("a.txt"), "from alpha").unwrap();
+    assert!(fs::write(beta.join("a.txt"), "from beta").is_err(), "Stale write after exchange should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("a.txt")).unwrap(), "from alpha");
+    fs::write(alpha.join("b.txt"), "alpha again").unwrap();
+}
+
+#[test]
+fn test_rename_exchange_with_changed_file() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "a").unwrap();
+    fs::write(mount.backing_path().join("b.txt"), "b").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(alpha.join("b.txt")).unwrap(), "b");
+    assert_eq!(fs::read_to_string(beta.join("b.txt")).unwrap(), "b");
+    fs::write(beta.join("b.txt"), "from beta").unwrap();
+
+    let err = rename_with(&alpha.join("a.txt"), &alpha.join("b.txt"), RENAME_EXCHANGE).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("a.txt")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "from beta");
+}
@@ -0,0 +1,187 @@
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::helpers::TestMount;
+
+fn open_rw(path: &std::path::Path) -> File {
+    fs::OpenOptions::new().read(true).write(true).open(path).unwrap()
+}
+
+/// Lock the first 100 bytes of `file` with a lock of type `typ`.
+fn lock(file: &File, typ: i32, cmd: i32) -> std::io::Result<()> {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = typ as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_len = 100;
+    if unsafe { libc::fcntl(file.as_raw_fd(), cmd, &mut fl) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn unlock(file: &File) {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = libc::F_UNLCK as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    assert_eq!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &mut fl) }, 0);
+}
+
+/// On Linux locks are passed through to the backing file, so agents lock
+/// each other out; elsewhere the kernel keeps them to each mount
+#[test]
+fn test_locks_between_agents() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("dev.db"), "").unwrap();
+    let alpha = open_rw(&mount.agent_path("alpha").join("dev.db"));
+    let beta = File::open(mount.agent_path("beta").join("dev.db")).unwrap();
+
+    lock(&alpha, libc::F_WRLCK, libc::F_SETLK).unwrap();
+    let result = lock(&beta, libc::F_RDLCK, libc::F_SETLK);
+    if !cfg!(target_os = "linux") {
+        result.unwrap();
+        unlock(&beta);
+        unlock(&alpha);
+        return;
+    }
+    let err = result.unwrap_err();
+    assert!(matches!(err.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)), "{}", err);
+
+    // fcntl() locks are the process's: it can unlock through another handle
+    let again = File::open(mount.agent_path("alpha").join("dev.db")).unwrap();
+    unlock(&again);
+    lock(&beta, libc::F_RDLCK, libc::F_SETLK).unwrap();
+    unlock(&beta);
+    drop(again);
+    lock(&alpha, libc::F_WRLCK, libc::F_SETLK).unwrap();
+
+    // A waiting lock is granted once the holder lets go
+    let waiter = std::thread::spawn(move || lock(&beta, libc::F_RDLCK, libc::F_SETLKW).map(|()| beta));
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!waiter.is_finished());
+    unlock(&alpha);
+    let beta = waiter.join().unwrap().unwrap();
+    let err = lock(&alpha, libc::F_WRLCK, libc::F_SETLK).unwrap_err();
+    assert!(matches!(err.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)), "{}", err);
+    drop(beta);
+
+    // flock() locks are passed through too
+    drop(alpha);
+    let alpha = File::open(mount.agent_path("alpha").join("dev.db")).unwrap();
+    let beta = File::open(mount.agent_path("beta").join("dev.db")).unwrap();
+    assert_eq!(unsafe { libc::flock(alpha.as_raw_fd(), libc::LOCK_EX) }, 0);
+    assert_eq!(unsafe { libc::flock(beta.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }, -1);
+
+    // The kernel releases the handle, and with it the lock, after close returns
+    drop(alpha);
+    let locked = (0..50).any(|_| {
+        std::thread::sleep(Duration::from_millis(20));
+        unsafe { libc::flock(beta.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+    });
+    assert!(locked);
+}
+
+/// A waiting lock request gives up when its caller is killed
+#[cfg(target_os = "linux")]
+#[test]
+fn test_abandoned_lock_wait() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("dev.db"), "").unwrap();
+    let alpha = open_rw(&mount.agent_path("alpha").join("dev.db"));
+    lock(&alpha, libc::F_WRLCK, libc::F_SETLK).unwrap();
+
+    let mut waiter = std::process::Command::new("flock")
+        .arg("-s")
+        .arg(mount.agent_path("beta").join("dev.db"))
+        .arg("true")
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(waiter.try_wait().unwrap().is_none(), "flock should be waiting");
+    waiter.kill().unwrap();
+    waiter.wait().unwrap();
+
+    // Nobody is left to take the lock, so nobody holds it after alpha
+    unlock(&alpha);
+    std::thread::sleep(Duration::from_millis(200));
+    lock(&alpha, libc::F_WRLCK, libc::F_SETLK).unwrap();
+    unlock(&alpha);
+}
+
+/// Closing any of a process's handles on a file drops its fcntl() locks
+/// there, even while another handle stays open
+#[cfg(target_os = "linux")]
+#[test]
+fn test_close_drops_process_locks() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("dev.db"), "").unwrap();
+    let alpha = open_rw(&mount.agent_path("alpha").join("dev.db"));
+    let beta = File::open(mount.agent_path("beta").join("dev.db")).unwrap();
+    lock(&alpha, libc::F_WRLCK, libc::F_SETLK).unwrap();
+
+    drop(File::open(mount.agent_path("alpha").join("dev.db")).unwrap());
+    lock(&beta, libc::F_RDLCK, libc::F_SETLK).unwrap();
+    drop(alpha);
+}
+
+#[test]
+fn test_locks_policy() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &["--policy", "dev.db*=locks"]);
+    fs::write(mount.backing_path().join("dev.db"), vec![b'.'; 8]).unwrap();
+    let alpha = open_rw(&mount.agent_path("alpha").join("dev.db"));
+    let beta = open_rw(&mount.agent_path("beta").join("dev.db"));
+
+    for i in 0..4 {
+        alpha.write_all_at(b"a", i * 2).unwrap();
+        beta.write_all_at(b"b", i * 2 + 1).unwrap();
+    }
+    let mut buf = [0; 8];
+    beta.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(&buf, b"abababab");
+    assert_eq!(fs::read(mount.backing_path().join("dev.db")).unwrap(), b"abababab");
+
+    // Companion files are created by whichever agent gets there first
+    fs::write(mount.agent_path("alpha").join("dev.db-journal"), "alpha").unwrap();
+    fs::write(mount.agent_path("beta").join("dev.db-journal"), "beta").unwrap();
+}
+
+/// Only on Linux do locks reach dibs
+#[cfg(target_os = "linux")]
+#[test]
+fn test_lock_coordinates_file() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("state.bin"), "v0").unwrap();
+    let alpha = mount.agent_path("alpha").join("state.bin");
+    let beta = mount.agent_path("beta").join("state.bin");
+    assert_eq!(fs::read_to_string(&alpha).unwrap(), "v0");
+    assert_eq!(fs::read_to_string(&beta).unwrap(), "v0");
+
+    // A shared lock alone leaves the file to CAS checks
+    let reader = File::open(&beta).unwrap();
+    lock(&reader, libc::F_RDLCK, libc::F_SETLK).unwrap();
+    let file = open_rw(&alpha);
+    file.write_all_at(b"v1", 0).unwrap();
+    drop(file);
+    assert!(fs::OpenOptions::new().write(true).open(&beta).is_err());
+    unlock(&reader);
+    drop(reader);
+
+    let file = open_rw(&alpha);
+    lock(&file, libc::F_WRLCK, libc::F_SETLK).unwrap();
+    file.write_all_at(b"v2", 0).unwrap();
+
+    // Beta's receipt is stale, but the file's writers go by its locks while
+    // it's locked
+    let other = open_rw(&beta);
+    unlock(&file);
+    drop(file);
+    lock(&other, libc::F_WRLCK, libc::F_SETLK).unwrap();
+    other.write_all_at(b"v3", 0).unwrap();
+    unlock(&other);
+    assert_eq!(fs::read_to_string(mount.backing_path().join("state.bin")).unwrap(), "v3");
+
+    // With the last lock gone, so is the exemption: alpha never saw v3
+    assert!(fs::OpenOptions::new().write(true).open(&alpha).is_err());
+    drop(other);
+}
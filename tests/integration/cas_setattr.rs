@@ -0,0 +1,87 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::helpers::TestMount;
+
+/// `truncate(2)`, which std only offers through an open file.
+fn truncate(path: &Path, size: libc::off_t) -> std::io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    if unsafe { libc::truncate(path.as_ptr(), size) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stale_truncate_rejected() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("notes.txt"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("notes.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(alpha.join("notes.txt")).unwrap(), "original");
+    fs::write(alpha.join("notes.txt"), "from alpha").unwrap();
+
+    let err = truncate(&beta.join("notes.txt"), 0).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("notes.txt")).unwrap(), "from alpha");
+}
+
+#[test]
+fn test_truncate_refreshes_receipt() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    fs::write(mount.backing_path().join("notes.txt"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    assert_eq!(fs::read_to_string(alpha.join("notes.txt")).unwrap(), "original");
+    truncate(&alpha.join("notes.txt"), 4).unwrap();
+    assert_eq!(fs::read_to_string(mount.backing_path().join("notes.txt")).unwrap(), "orig");
+    fs::write(alpha.join("notes.txt"), "after truncate").unwrap();
+
+    // Through a handle, the receipt catches up at close
+    let file = fs::OpenOptions::new().write(true).open(alpha.join("notes.txt")).unwrap();
+    file.set_len(5).unwrap();
+    drop(file);
+    fs::write(alpha.join("notes.txt"), "after ftruncate").unwrap();
+}
+
+#[test]
+fn test_stale_chmod_rejected() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &["--attr-policy", "check"]);
+    fs::write(mount.backing_path().join("run.sh"), "echo one").unwrap();
+    fs::set_permissions(mount.backing_path().join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("run.sh")).unwrap(), "echo one");
+    assert_eq!(fs::read_to_string(alpha.join("run.sh")).unwrap(), "echo one");
+    fs::write(alpha.join("run.sh"), "echo two").unwrap();
+
+    let err = fs::set_permissions(beta.join("run.sh"), fs::Permissions::from_mode(0o644)).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    let mode = fs::metadata(mount.backing_path().join("run.sh")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o755);
+
+    // The agent that made the change is current
+    fs::set_permissions(alpha.join("run.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+}
+
+#[test]
+fn test_attr_policy_allow() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("run.sh"), "echo one").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    assert_eq!(fs::read_to_string(beta.join("run.sh")).unwrap(), "echo one");
+    fs::write(alpha.join("run.sh"), "echo two").unwrap();
+
+    fs::set_permissions(beta.join("run.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+    // Truncation is checked regardless
+    let err = truncate(&beta.join("run.sh"), 0).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}
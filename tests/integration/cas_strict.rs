@@ -0,0 +1,71 @@
+use std::fs;
+use std::io::Write;
+
+use crate::helpers::TestMount;
+
+#[test]
+fn test_strict_rejects_unread_overwrite() {
+    let mount = TestMount::with_agents(&["alpha"], &["--strict"]);
+    fs::write(mount.backing_path().join("main.rs"), "fn main() {}").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    let err = fs::write(alpha.join("main.rs"), "overwritten").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("main.rs")).unwrap(), "fn main() {}");
+    let report = fs::read_to_string(alpha.join(".dibs/self/last-conflict.diff")).unwrap();
+    assert!(report.contains("never read the file, and its policy is strict."), "{}", report);
+    assert!(report.contains("strict: write of a file the agent never read"), "{}", report);
+
+    assert_eq!(fs::read_to_string(alpha.join("main.rs")).unwrap(), "fn main() {}");
+    fs::write(alpha.join("main.rs"), "fn main() { run() }").unwrap();
+}
+
+#[test]
+fn test_strict_allows_new_empty_and_append() {
+    let mount = TestMount::with_agents(&["alpha"], &["--strict"]);
+    fs::write(mount.backing_path().join("empty.txt"), "").unwrap();
+    fs::write(mount.backing_path().join("log.txt"), "one\n").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    fs::write(alpha.join("new.txt"), "new").unwrap();
+    fs::write(alpha.join("empty.txt"), "filled").unwrap();
+    let mut log = fs::OpenOptions::new().append(true).open(alpha.join("log.txt")).unwrap();
+    log.write_all(b"two\n").unwrap();
+    drop(log);
+    assert_eq!(fs::read_to_string(mount.backing_path().join("log.txt")).unwrap(), "one\ntwo\n");
+}
+
+#[test]
+fn test_strict_unlink_and_rename_over() {
+    let mount = TestMount::with_agents(&["alpha"], &["--strict"]);
+    fs::write(mount.backing_path().join("a.txt"), "a").unwrap();
+    fs::write(mount.backing_path().join("b.txt"), "b").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    let err = fs::remove_file(alpha.join("a.txt")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    fs::write(alpha.join("b.txt.tmp"), "new b").unwrap();
+    let err = fs::rename(alpha.join("b.txt.tmp"), alpha.join("b.txt")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "b");
+
+    // Once read, both go through
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(alpha.join("b.txt")).unwrap(), "b");
+    fs::remove_file(alpha.join("a.txt")).unwrap();
+    fs::rename(alpha.join("b.txt.tmp"), alpha.join("b.txt")).unwrap();
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "new b");
+}
+
+#[test]
+fn test_strict_policy_by_glob() {
+    let mount = TestMount::with_agents(&["alpha"], &["--policy", "*.md=strict"]);
+    fs::create_dir(mount.backing_path().join("docs")).unwrap();
+    fs::write(mount.backing_path().join("docs/guide.md"), "guide").unwrap();
+    fs::write(mount.backing_path().join("main.rs"), "fn main() {}").unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    let err = fs::write(alpha.join("docs/guide.md"), "overwritten").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    fs::write(alpha.join("main.rs"), "overwritten").unwrap();
+}
@@ -0,0 +1,41 @@
+use std::fs;
+
+use crate::helpers::{agent_write, spawn_agent_with_env, TestMount};
+
+/// With --identity env, processes in different sessions that share a
+/// DIBS_AGENT name are one agent: the second sees the first's write as its own
+#[test]
+fn test_env_identity_spans_sessions() {
+    let mount = TestMount::with_args(&["--identity", "env"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("shared.txt"), "original").unwrap();
+    let mount_file = mount.mount_path().join("shared.txt");
+
+    let env = [("DIBS_AGENT", "alpha")];
+    let mut first = spawn_agent_with_env(&mount_file, sync_dir.path(), "a", "first", &env);
+    let mut second = spawn_agent_with_env(&mount_file, sync_dir.path(), "b", "second", &env);
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    assert_eq!(agent_write(sync_dir.path(), "b"), "ok", "Same agent should not conflict with itself");
+    let _ = first.wait();
+    let _ = second.wait();
+    assert_eq!(fs::read_to_string(&mount_file).unwrap(), "second");
+}
+
+/// Different DIBS_AGENT names still conflict
+#[test]
+fn test_env_identity_separates_agents() {
+    let mount = TestMount::with_args(&["--identity", "env"]);
+    let sync_dir = tempfile::tempdir().unwrap();
+
+    fs::write(mount.backing_path().join("shared.txt"), "original").unwrap();
+    let mount_file = mount.mount_path().join("shared.txt");
+
+    let mut a = spawn_agent_with_env(&mount_file, sync_dir.path(), "a", "A", &[("DIBS_AGENT", "alpha")]);
+    let mut b = spawn_agent_with_env(&mount_file, sync_dir.path(), "b", "B", &[("DIBS_AGENT", "beta")]);
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    assert!(agent_write(sync_dir.path(), "b").starts_with("error"));
+    let _ = a.wait();
+    let _ = b.wait();
+    assert_eq!(fs::read_to_string(&mount_file).unwrap(), "A");
+}
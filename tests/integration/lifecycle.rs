@@ -1,7 +1,10 @@
 use std::fs;
-use std::process::Command;
+use std::path::Path;
+use std::process::{Child, Command};
 use std::time::Duration;
 
+use crate::helpers::{agent_write, spawn_agent, wait_for_file};
+
 /// Test 10: Clean shutdown — no stale mounts.
 #[test]
 fn test_clean_shutdown() {
@@ -51,3 +54,72 @@ fn test_clean_shutdown() {
     // (reading it should work normally as empty dir or fail gracefully)
     std::thread::sleep(Duration::from_millis(500));
 }
+
+/// Unmounts and stops a dibs started by hand, even if the test fails.
+struct Daemon<'a>(&'a Path, Child);
+
+impl Drop for Daemon<'_> {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(self.0).status();
+        std::thread::sleep(Duration::from_millis(500));
+        let _ = self.1.kill();
+        let _ = self.1.wait();
+    }
+}
+
+/// --in-place mounts over the backing directory: the project's own path
+/// leads through dibs, and the daemon keeps reaching the files underneath.
+#[test]
+fn test_in_place_mount() {
+    let project = tempfile::tempdir().unwrap();
+    let file = project.path().join("shared.txt");
+    fs::write(&file, "original").unwrap();
+
+    let child = Command::new(crate::helpers::dibs_binary())
+        .args([
+            "mount",
+            project.path().to_str().unwrap(),
+            "--in-place",
+            "-f",
+            "--log-file",
+            "/tmp/dibs-test-in-place.log",
+            "--save-conflicts",
+            "--defer-rejection",
+        ])
+        .spawn()
+        .expect("failed to start dibs");
+    let daemon = Daemon(project.path(), child);
+    assert!(
+        wait_for_file(&project.path().join(".dibs/status"), Duration::from_secs(10)),
+        "In-place mount did not come up"
+    );
+
+    let sync_dir = tempfile::tempdir().unwrap();
+    let mut a = spawn_agent(&file, sync_dir.path(), "a", "A");
+    let mut b = spawn_agent(&file, sync_dir.path(), "b", "B");
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    assert!(agent_write(sync_dir.path(), "b").starts_with("error"));
+    let _ = a.wait();
+    let _ = b.wait();
+
+    // The stale write was saved to the covered conflict store, and
+    // dibs resolve still finds it through the mount
+    let out = Command::new(crate::helpers::dibs_binary())
+        .arg("resolve")
+        .arg(project.path())
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout).lines().count(), 1);
+
+    drop(daemon);
+
+    // Once unmounted, the project directory holds the accepted write
+    assert!(!project.path().join(".dibs").exists());
+    assert_eq!(fs::read_to_string(&file).unwrap(), "A");
+    let saved = fs::read_dir(project.path().join(".dibs-conflicts"))
+        .unwrap()
+        .filter(|e| !e.as_ref().unwrap().file_name().to_string_lossy().starts_with('.'))
+        .count();
+    assert_eq!(saved, 1);
+}
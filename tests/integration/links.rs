@@ -0,0 +1,44 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use crate::helpers::TestMount;
+
+/// Links made through the mount share one file's CAS state: a stale write
+/// under one name is rejected after a write under another
+#[test]
+fn test_links_share_cas_state() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    fs::hard_link(alpha.join("a.txt"), alpha.join("b.txt")).unwrap();
+    assert_eq!(fs::metadata(mount.backing_path().join("a.txt")).unwrap().nlink(), 2);
+
+    assert_eq!(fs::read_to_string(beta.join("b.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "original");
+    fs::write(alpha.join("a.txt"), "from alpha").unwrap();
+
+    assert!(fs::write(beta.join("b.txt"), "from beta").is_err(), "Stale write through a link should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "from alpha");
+}
+
+/// Removing one name leaves the file reachable, and its receipts in force,
+/// under the others
+#[test]
+fn test_unlink_one_link() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("a.txt"), "original").unwrap();
+    let alpha = mount.agent_path("alpha");
+    let beta = mount.agent_path("beta");
+
+    fs::hard_link(alpha.join("a.txt"), alpha.join("b.txt")).unwrap();
+    assert_eq!(fs::read_to_string(beta.join("b.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(alpha.join("a.txt")).unwrap(), "original");
+    fs::write(alpha.join("a.txt"), "from alpha").unwrap();
+    fs::remove_file(alpha.join("a.txt")).unwrap();
+
+    assert_eq!(fs::read_to_string(alpha.join("b.txt")).unwrap(), "from alpha");
+    assert!(fs::write(beta.join("b.txt"), "from beta").is_err(), "Stale write after unlinking the other name should fail");
+    assert_eq!(fs::read_to_string(mount.backing_path().join("b.txt")).unwrap(), "from alpha");
+}
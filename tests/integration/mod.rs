@@ -1,5 +1,19 @@
+mod agents;
+mod cas_append;
 mod cas_basic;
+mod cas_conflicts;
+mod cas_create;
 mod cas_delete_rename;
 mod cas_external;
+mod cas_locks;
+mod cas_setattr;
+mod cas_strict;
+mod identity;
 mod lifecycle;
+mod links;
+mod passthrough;
 mod performance;
+mod resolve;
+mod run;
+mod symlinks;
+mod xattr;
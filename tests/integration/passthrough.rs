@@ -0,0 +1,87 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::AsRawFd;
+
+use crate::helpers::TestMount;
+
+/// Open `path` read-write, write through it and flush with a duplicate
+/// descriptor, so the handle gives up its write ownership but stays open.
+#[cfg(target_os = "linux")]
+fn written_and_flushed(path: &std::path::Path) -> File {
+    let file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+    file.write_all_at(b"x", 0).unwrap();
+    drop(file.try_clone().unwrap());
+    file
+}
+
+#[test]
+fn test_fsync_and_mknod() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    let alpha = mount.agent_path("alpha");
+
+    let mut file = File::create(alpha.join("data.bin")).unwrap();
+    file.write_all(b"durable").unwrap();
+    file.sync_data().unwrap();
+    file.sync_all().unwrap();
+    File::open(alpha).unwrap().sync_all().unwrap();
+
+    let fifo = std::ffi::CString::new(alpha.join("pipe").into_os_string().into_encoded_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    assert!(fs::metadata(mount.backing_path().join("pipe")).unwrap().file_type().is_fifo());
+}
+
+#[test]
+fn test_seek_data_and_hole() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    let sparse = File::create(mount.backing_path().join("sparse.bin")).unwrap();
+    sparse.write_all_at(b"end", 1 << 20).unwrap();
+
+    let file = File::open(mount.agent_path("alpha").join("sparse.bin")).unwrap();
+    let data = unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_DATA) };
+    assert!(data > 0 && data <= 1 << 20, "{}", data);
+    let hole = unsafe { libc::lseek(file.as_raw_fd(), data, libc::SEEK_HOLE) };
+    assert_eq!(hole, (1 << 20) + 3);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_fallocate_checks_like_write() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("db.bin"), vec![b'.'; 8192]).unwrap();
+    let alpha = written_and_flushed(&mount.agent_path("alpha").join("db.bin"));
+
+    // Reserving space changes nothing
+    assert_eq!(unsafe { libc::fallocate(alpha.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, 16384) }, 0);
+
+    fs::read(mount.agent_path("beta").join("db.bin")).unwrap();
+    fs::write(mount.agent_path("beta").join("db.bin"), "from beta").unwrap();
+
+    let punch = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    assert_eq!(unsafe { libc::fallocate(alpha.as_raw_fd(), punch, 0, 4096) }, -1);
+    assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("db.bin")).unwrap(), "from beta");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_copy_file_range_checks_like_write() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("src.txt"), "copied").unwrap();
+    fs::write(mount.backing_path().join("dst.txt"), "original").unwrap();
+    let src = File::open(mount.agent_path("alpha").join("src.txt")).unwrap();
+    let dst = written_and_flushed(&mount.agent_path("alpha").join("dst.txt"));
+
+    let copy = |dst: &File| unsafe {
+        libc::copy_file_range(src.as_raw_fd(), &mut 0, dst.as_raw_fd(), &mut 0, 6, 0)
+    };
+    assert_eq!(copy(&dst), 6);
+    drop(dst.try_clone().unwrap());
+    assert_eq!(fs::read_to_string(mount.backing_path().join("dst.txt")).unwrap(), "copiedal");
+
+    fs::read(mount.agent_path("beta").join("dst.txt")).unwrap();
+    fs::write(mount.agent_path("beta").join("dst.txt"), "from beta").unwrap();
+    assert_eq!(copy(&dst), -1);
+    assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("dst.txt")).unwrap(), "from beta");
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use crate::helpers::{agent_write, dibs_binary, spawn_agent, TestMount};
+
+/// Have agent A change `name` while agent B's stale write to it is deferred
+/// and saved as a conflict.
+fn make_conflict(mount: &TestMount, name: &str, original: &str, a: &str, b: &str) {
+    let sync_dir = tempfile::tempdir().unwrap();
+    fs::write(mount.backing_path().join(name), original).unwrap();
+    let mount_file = mount.mount_path().join(name);
+
+    let mut agent_a = spawn_agent(&mount_file, sync_dir.path(), "a", a);
+    let mut agent_b = spawn_agent(&mount_file, sync_dir.path(), "b", b);
+    assert_eq!(agent_write(sync_dir.path(), "a"), "ok");
+    assert!(agent_write(sync_dir.path(), "b").starts_with("error"));
+    let _ = agent_a.wait();
+    let _ = agent_b.wait();
+}
+
+fn dibs_resolve(mount: &Path, args: &[&str], editor: Option<&str>) -> Output {
+    let mut cmd = Command::new(dibs_binary());
+    cmd.arg("resolve").arg(mount).args(args);
+    if let Some(editor) = editor {
+        cmd.env("EDITOR", editor);
+    }
+    cmd.output().expect("failed to run dibs resolve")
+}
+
+/// Pending conflict IDs as listed by `dibs resolve`.
+fn pending(mount: &Path) -> Vec<String> {
+    let out = dibs_resolve(mount, &[], None);
+    assert!(out.status.success());
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|l| l.split_whitespace().next().unwrap().to_string())
+        .collect()
+}
+
+/// A saved conflict is listed, merged through the mount, and marked resolved
+#[test]
+fn test_resolve_merged() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+    make_conflict(&mount, "list.txt", "one\ntwo\nthree\n", "ONE\ntwo\nthree\n", "one\ntwo\nTHREE\n");
+
+    let ids = pending(mount.mount_path());
+    assert_eq!(ids.len(), 1, "Expected one pending conflict, got: {:?}", ids);
+
+    let out = dibs_resolve(mount.mount_path(), &[&ids[0], "--take", "merged"], None);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(
+        fs::read_to_string(mount.mount_path().join("list.txt")).unwrap(),
+        "ONE\ntwo\nTHREE\n"
+    );
+
+    assert!(pending(mount.mount_path()).is_empty());
+    let meta: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(mount.mount_path().join(".dibs/conflicts").join(&ids[0]).join("meta.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(meta["resolved"]["take"], "merged");
+
+    // Resolving twice is refused
+    let out = dibs_resolve(mount.mount_path(), &[&ids[0], "--take", "theirs"], None);
+    assert!(!out.status.success());
+}
+
+/// Overlapping edits are refused without --edit, and settled by hand with it
+#[test]
+fn test_resolve_overlap_in_editor() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+    make_conflict(&mount, "cfg.txt", "a\nb\nc\n", "a\nB from A\nc\n", "a\nB from B\nc\n");
+    let ids = pending(mount.mount_path());
+    assert_eq!(ids.len(), 1);
+
+    let out = dibs_resolve(mount.mount_path(), &[&ids[0], "--take", "merged"], None);
+    assert!(!out.status.success());
+    assert_eq!(fs::read_to_string(mount.mount_path().join("cfg.txt")).unwrap(), "a\nB from A\nc\n");
+
+    // An "editor" that keeps both sides by dropping the marker lines
+    let out = dibs_resolve(
+        mount.mount_path(),
+        &[&ids[0], "--take", "merged", "--edit"],
+        Some("sed -i -e /^[<=>]\\{7\\}/d"),
+    );
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(
+        fs::read_to_string(mount.mount_path().join("cfg.txt")).unwrap(),
+        "a\nB from A\nB from B\nc\n"
+    );
+    assert!(pending(mount.mount_path()).is_empty());
+}
+
+/// Taking theirs writes the rejected content back through the mount
+#[test]
+fn test_resolve_take_theirs() {
+    let mount = TestMount::with_args(&["--defer-rejection"]);
+    make_conflict(&mount, "notes.md", "original", "A's version", "B's version");
+    let ids = pending(mount.mount_path());
+
+    let out = dibs_resolve(mount.mount_path(), &[&ids[0], "--take", "theirs"], None);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(fs::read_to_string(mount.mount_path().join("notes.md")).unwrap(), "B's version");
+}
@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use crate::helpers::{dibs_binary, TestMount};
+
+fn dibs_run(mount: &Path, cwd: &Path, name: &str, script: &str) -> Output {
+    dibs_run_with(mount, cwd, name, script, &[])
+}
+
+fn dibs_run_with(mount: &Path, cwd: &Path, name: &str, script: &str, extra_args: &[&str]) -> Output {
+    Command::new(dibs_binary())
+        .args(["run", "--name", name, "--mount"])
+        .arg(mount)
+        .args(extra_args)
+        .args(["--", "sh", "-c", script])
+        .current_dir(cwd)
+        .output()
+        .expect("failed to run dibs run")
+}
+
+fn status(mount: &Path) -> serde_json::Value {
+    serde_json::from_slice(&fs::read(mount.join(".dibs/status")).unwrap()).unwrap()
+}
+
+/// The agent starts in the mount, is listed by name while it runs and is
+/// forgotten once it exits
+#[test]
+fn test_run_names_agent_in_status() {
+    let mount = TestMount::new();
+    fs::create_dir(mount.backing_path().join("src")).unwrap();
+
+    let out = dibs_run(mount.mount_path(), &mount.backing_path().join("src"), "tester", "pwd; cat ../.dibs/status");
+    assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let (cwd, status_json) = stdout.split_once('\n').unwrap();
+    let mount_path = fs::canonicalize(mount.mount_path()).unwrap();
+    assert_eq!(Path::new(cwd), mount_path.join("src"), "Should start in the mount's copy of the cwd");
+
+    let running: serde_json::Value = serde_json::from_str(status_json).unwrap();
+    let agent = running["agents"].as_array().unwrap().iter().find(|a| a["name"] == "tester").unwrap();
+    assert!(agent["agent"].as_str().unwrap().starts_with("session "));
+
+    assert!(status(mount.mount_path())["agents"].as_array().unwrap().is_empty());
+    assert_eq!(fs::read_to_string(mount.mount_path().join(".dibs/names")).unwrap(), "");
+}
+
+/// .dibs/locks names the agent holding a write, and the exit code passes through
+#[test]
+fn test_run_names_lock_owner() {
+    let mount = TestMount::new();
+    fs::write(mount.backing_path().join("shared.txt"), "original").unwrap();
+    let out = dibs_run(
+        mount.mount_path(),
+        mount.mount_path(),
+        "writer",
+        "exec 3>shared.txt; cat .dibs/locks; exit 3",
+    );
+    assert_eq!(out.status.code(), Some(3));
+
+    let locks: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let entry = locks.as_array().unwrap().iter().find(|e| e["path"] == "shared.txt").unwrap();
+    assert_eq!(entry["agent"], "writer");
+}
+
+/// With --isolate the agent finds the mount at the project's own path, and
+/// its writes there go through dibs
+#[cfg(target_os = "linux")]
+#[test]
+fn test_run_isolate_covers_backing() {
+    let userns = Command::new("unshare").args(["-Urm", "true"]).status();
+    if !matches!(userns, Ok(s) if s.success()) {
+        eprintln!("skipping: unprivileged user namespaces are not available");
+        return;
+    }
+
+    let mount = TestMount::new();
+    let backing = fs::canonicalize(mount.backing_path()).unwrap();
+    fs::write(backing.join("shared.txt"), "original").unwrap();
+
+    let out = dibs_run_with(
+        mount.mount_path(),
+        &backing,
+        "isolated",
+        "pwd; ls -a; echo changed > shared.txt",
+        &["--isolate"],
+    );
+    assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(Path::new(lines.next().unwrap()), backing, "Should start at the project path");
+    assert!(lines.any(|l| l == ".dibs"), "The project path should show the mount");
+    assert_eq!(fs::read_to_string(backing.join("shared.txt")).unwrap(), "changed\n");
+    assert!(!backing.join(".dibs").exists(), "The host still sees the plain backing directory");
+}
+
+/// With --isolate no process outside the agent's namespaces shows up in
+/// /proc, so no other working directory or descriptor leads past the mount,
+/// and the agent can't take the mounts down
+#[cfg(target_os = "linux")]
+#[test]
+fn test_run_isolate_hides_outside_processes() {
+    let pidns = Command::new("unshare").args(["-Urmpf", "--mount-proc", "true"]).status();
+    if !matches!(pidns, Ok(s) if s.success()) {
+        eprintln!("skipping: unprivileged PID namespaces are not available");
+        return;
+    }
+
+    let mount = TestMount::new();
+    let backing = fs::canonicalize(mount.backing_path()).unwrap();
+
+    // dibs run itself was started from the backing directory
+    let out = dibs_run_with(
+        mount.mount_path(),
+        &backing,
+        "isolated",
+        "echo $PPID; \
+         for p in /proc/[0-9]*; do ls -a $p/cwd/ | grep -qx .dibs || echo \"escape $p\"; done; \
+         umount -l /proc 2>/dev/null && echo 'unmounted /proc'; \
+         umount -l \"$PWD\" 2>/dev/null && echo 'unmounted the mount'; \
+         true",
+        &["--isolate"],
+    );
+    assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("1"), "The agent's parent should be the namespace's init");
+    let escapes: Vec<_> = lines.collect();
+    assert!(escapes.is_empty(), "Should find no way past the mount: {:?}", escapes);
+}
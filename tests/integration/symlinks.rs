@@ -0,0 +1,42 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use crate::helpers::TestMount;
+
+/// With --contain-symlinks, links that stay in the mount work as usual and
+/// links leading out of it can be neither created nor followed
+#[test]
+fn test_contain_symlinks() {
+    let mount = TestMount::with_args(&["--contain-symlinks"]);
+    let mp = mount.mount_path();
+    fs::create_dir(mount.backing_path().join("sub")).unwrap();
+    fs::write(mount.backing_path().join("sub/f.txt"), "inside").unwrap();
+
+    symlink("sub/f.txt", mp.join("relative")).unwrap();
+    assert_eq!(fs::read_to_string(mp.join("relative")).unwrap(), "inside");
+    symlink("../sub/f.txt", mp.join("sub/sibling")).unwrap();
+    assert_eq!(fs::read_to_string(mp.join("sub/sibling")).unwrap(), "inside");
+    let mp_canonical = fs::canonicalize(mp).unwrap();
+    symlink(mp_canonical.join("sub/f.txt"), mp.join("absolute")).unwrap();
+    assert_eq!(fs::read_to_string(mp.join("absolute")).unwrap(), "inside");
+
+    let err = symlink("/etc/hostname", mp.join("etc")).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert!(symlink("../outside", mp.join("up")).is_err());
+    assert!(!mount.backing_path().join("etc").exists());
+
+    // A link into the backing directory itself would bypass dibs
+    symlink(mount.backing_path().join("sub/f.txt"), mount.backing_path().join("sneaky")).unwrap();
+    assert!(fs::read_link(mp.join("sneaky")).is_err());
+    assert!(fs::read_to_string(mp.join("sneaky")).is_err());
+}
+
+/// Without the option, symlinks are passed through untouched
+#[test]
+fn test_symlinks_pass_through() {
+    let mount = TestMount::new();
+    let mp = mount.mount_path();
+    symlink("/etc/hostname", mp.join("etc")).unwrap();
+    assert_eq!(fs::read_link(mp.join("etc")).unwrap(), std::path::Path::new("/etc/hostname"));
+    assert_eq!(fs::read_link(mount.backing_path().join("etc")).unwrap(), std::path::Path::new("/etc/hostname"));
+}
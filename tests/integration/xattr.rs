@@ -0,0 +1,171 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::helpers::TestMount;
+
+#[cfg(target_os = "linux")]
+const NO_XATTR: i32 = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+const NO_XATTR: i32 = libc::ENOATTR;
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn get(path: &Path, name: &str) -> std::io::Result<String> {
+    let (path, name) = (c_path(path), CString::new(name).unwrap());
+    let mut buf = vec![0u8; 256];
+    let ptr = buf.as_mut_ptr() as *mut libc::c_void;
+    #[cfg(target_os = "linux")]
+    let n = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, buf.len()) };
+    #[cfg(not(target_os = "linux"))]
+    let n = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, buf.len(), 0, 0) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    buf.truncate(n as usize);
+    Ok(String::from_utf8(buf).unwrap())
+}
+
+fn set(path: &Path, name: &str, value: &str) -> std::io::Result<()> {
+    let (path, name) = (c_path(path), CString::new(name).unwrap());
+    let ptr = value.as_ptr() as *const libc::c_void;
+    #[cfg(target_os = "linux")]
+    let ret = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0) };
+    #[cfg(not(target_os = "linux"))]
+    let ret = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0, 0) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn remove(path: &Path, name: &str) -> std::io::Result<()> {
+    let (path, name) = (c_path(path), CString::new(name).unwrap());
+    #[cfg(target_os = "linux")]
+    let ret = unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) };
+    #[cfg(not(target_os = "linux"))]
+    let ret = unsafe { libc::removexattr(path.as_ptr(), name.as_ptr(), 0) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn list(path: &Path) -> Vec<String> {
+    let path = c_path(path);
+    let mut buf = vec![0u8; 1024];
+    let ptr = buf.as_mut_ptr() as *mut libc::c_char;
+    #[cfg(target_os = "linux")]
+    let n = unsafe { libc::listxattr(path.as_ptr(), ptr, buf.len()) };
+    #[cfg(not(target_os = "linux"))]
+    let n = unsafe { libc::listxattr(path.as_ptr(), ptr, buf.len(), 0) };
+    assert!(n >= 0, "{}", std::io::Error::last_os_error());
+    buf[..n as usize]
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+#[test]
+fn test_xattr_passthrough() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    fs::write(mount.backing_path().join("notes.md"), "notes").unwrap();
+    fs::create_dir(mount.backing_path().join("docs")).unwrap();
+    let alpha = mount.agent_path("alpha");
+
+    for name in ["notes.md", "docs"] {
+        set(&alpha.join(name), "user.origin", "alpha").unwrap();
+        assert_eq!(get(&mount.backing_path().join(name), "user.origin").unwrap(), "alpha");
+        assert_eq!(get(&alpha.join(name), "user.origin").unwrap(), "alpha");
+        assert!(list(&alpha.join(name)).contains(&"user.origin".to_string()));
+
+        remove(&alpha.join(name), "user.origin").unwrap();
+        let err = get(&alpha.join(name), "user.origin").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(NO_XATTR));
+    }
+}
+
+/// The attributes of a file that can't be read can still be changed
+#[test]
+fn test_xattr_unreadable_file() {
+    let mount = TestMount::with_agents(&["alpha"], &[]);
+    let backing = mount.backing_path().join("secret");
+    fs::write(&backing, "secret").unwrap();
+    fs::set_permissions(&backing, fs::Permissions::from_mode(0o200)).unwrap();
+    let alpha = mount.agent_path("alpha").join("secret");
+
+    set(&alpha, "user.draft", "yes").unwrap();
+    remove(&alpha, "user.draft").unwrap();
+    set(&alpha, "user.origin", "alpha").unwrap();
+
+    fs::set_permissions(&backing, fs::Permissions::from_mode(0o600)).unwrap();
+    assert_eq!(get(&backing, "user.origin").unwrap(), "alpha");
+    assert_eq!(get(&backing, "user.draft").unwrap_err().raw_os_error(), Some(NO_XATTR));
+}
+
+#[test]
+fn test_dibs_query_xattrs() {
+    let mount = TestMount::with_agents(&["alpha", "beta"], &[]);
+    fs::write(mount.backing_path().join("main.rs"), "fn main() {}").unwrap();
+    let alpha = mount.agent_path("alpha").join("main.rs");
+    let beta = mount.agent_path("beta").join("main.rs");
+
+    // Nothing to be fresh or stale about before reading
+    let err = get(&alpha, "user.dibs.fresh").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(NO_XATTR));
+    fs::read(&alpha).unwrap();
+    fs::read(&beta).unwrap();
+    assert_eq!(get(&alpha, "user.dibs.fresh").unwrap(), "true");
+    let hash = get(&alpha, "user.dibs.hash").unwrap();
+    assert_eq!(hash, get(&beta, "user.dibs.hash").unwrap());
+
+    fs::write(&beta, "fn main() { run() }").unwrap();
+    assert_eq!(get(&alpha, "user.dibs.fresh").unwrap(), "false");
+    assert_eq!(get(&beta, "user.dibs.fresh").unwrap(), "true");
+    assert_ne!(get(&alpha, "user.dibs.hash").unwrap(), hash);
+    assert_eq!(get(&alpha, "user.dibs.last_writer").unwrap(), "agent beta");
+
+    let err = get(&alpha, "user.dibs.writer").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(NO_XATTR));
+    let mut file = fs::OpenOptions::new().write(true).open(&beta).unwrap();
+    assert_eq!(get(&alpha, "user.dibs.writer").unwrap(), "agent beta");
+    file.write_all(b"fn main() {}").unwrap();
+    drop(file);
+
+    // The queries are read-only and left out of listings
+    let err = set(&alpha, "user.dibs.fresh", "true").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    assert!(list(&alpha).iter().all(|name| !name.starts_with("user.dibs.")));
+}
+
+/// Once its receipt is evicted, an agent is still told the file moved on,
+/// and its write is still rejected
+#[test]
+fn test_fresh_after_eviction() {
+    // A three-second eviction window, checked every second
+    let mount = TestMount::with_agents(&["alpha", "beta"], &["--eviction-minutes", "0.05"]);
+    fs::write(mount.backing_path().join("main.rs"), "fn main() {}").unwrap();
+    let alpha = mount.agent_path("alpha").join("main.rs");
+    let beta = mount.agent_path("beta").join("main.rs");
+
+    fs::read(&alpha).unwrap();
+    assert_eq!(get(&alpha, "user.dibs.fresh").unwrap(), "true");
+    let start = Instant::now();
+    while get(&alpha, "user.dibs.fresh").is_ok() {
+        assert!(start.elapsed() < Duration::from_secs(15), "The receipt should be evicted");
+        std::thread::sleep(Duration::from_millis(200));
+    }
+
+    fs::write(&beta, "fn main() { run() }").unwrap();
+    assert_eq!(get(&alpha, "user.dibs.fresh").unwrap(), "false");
+    let err = fs::write(&alpha, "fn main() { walk() }").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(fs::read_to_string(mount.backing_path().join("main.rs")).unwrap(), "fn main() { run() }");
+}